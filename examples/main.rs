@@ -2,9 +2,9 @@ extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate sidekiq;
-extern crate env_logger;
 
-use sidekiq::{error_handler, panic_handler, printer_handler, retry_middleware, SidekiqServer};
+use sidekiq::{error_handler, panic_handler, printer_handler, retry_middleware, init_logger_with,
+              SidekiqServer, SidekiqServerBuilder};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug, Clone)]
@@ -20,39 +20,36 @@ struct Params {
     queues: Vec<String>,
     #[structopt(short = "t", long = "timeout", help = "the timeout when force terminated", default_value = "10")]
     timeout: usize,
+    #[structopt(long = "log-format", help = "plain, json or logfmt", default_value = "plain")]
+    log_format: String,
 }
 
 fn main() {
-    env_logger::init().unwrap();
     let params = Params::from_args();
+    init_logger_with(params.log_format.parse().unwrap()).unwrap();
+
+    let mut builder = SidekiqServerBuilder::new()
+        .redis(&params.redis)
+        .namespace(&params.namespace)
+        .concurrency(params.concurrency)
+        .timeout(params.timeout);
+    for v in params.queues {
+        let mut sp = v.split(':');
+        let name = sp.next().unwrap();
+        let weight = sp.next().unwrap().parse().unwrap();
+        builder = builder.queue(name, weight);
+    }
 
-    let queues: Vec<_> = params.queues
-        .into_iter()
-        .map(|v| {
-            let mut sp = v.split(':');
-            let name = sp.next().unwrap();
-            let weight = sp.next().unwrap().parse().unwrap();
-            (name.to_string(), weight)
-        })
-        .collect();
-
-
-    let mut server = SidekiqServer::new(&params.redis, params.concurrency).unwrap();
+    let mut server = builder.build().unwrap();
 
     server.attach_handler("Printer", printer_handler);
     server.attach_handler("Error", error_handler);
     server.attach_handler("Panic", panic_handler);
 
     server.attach_middleware(retry_middleware);
-    for (name, weight) in queues {
-        server.new_queue(&name, weight);
-    }
-
-    server.namespace = params.namespace;
-    server.force_quite_timeout = params.timeout;
     start(server)
 }
 
 fn start(mut server: SidekiqServer) {
-    server.start();
+    ::std::process::exit(server.start());
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{FnArg, ImplItem, ItemImpl, LitStr, Type};
+use syn::spanned::Spanned;
+
+// on the `impl` block of a `Clone + Send` struct with a `perform(&self, args: MyArgs)`
+// method, `MyArgs` being `Deserialize`, implements `sidekiq::Worker` and
+// `sidekiq::WorkerClass` so the struct can be given to `SidekiqServer::register`
+//
+//     #[sidekiq_worker(class = "HardJob", queue = "critical")]
+//     impl HardJob {
+//         fn perform(&self, args: HardArgs) -> JobHandlerResult { .. }
+//     }
+//
+// `class` defaults to the name of the struct and `queue` to `default`
+#[proc_macro_attribute]
+pub fn sidekiq_worker(attr: TokenStream, item: TokenStream) -> TokenStream {
+    match expand(attr, item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(attr: TokenStream, item: TokenStream) -> syn::Result<proc_macro2::TokenStream> {
+    let item: ItemImpl = syn::parse(item)?;
+    let mut class: Option<LitStr> = None;
+    let mut queue: Option<LitStr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("class") {
+            class = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("queue") {
+            queue = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `class` or `queue`"))
+        }
+    });
+    syn::parse::Parser::parse(parser, attr)?;
+
+    if let Some((_, ref path, _)) = item.trait_ {
+        return Err(syn::Error::new(path.span(), "expected an inherent impl block"));
+    }
+    let self_ty = &item.self_ty;
+    let class = match class {
+        Some(class) => class,
+        None => LitStr::new(&type_name(self_ty)?, Span::call_site()),
+    };
+    let queue = queue.unwrap_or_else(|| LitStr::new("default", Span::call_site()));
+    let args = perform_args(&item)?;
+
+    Ok(quote! {
+        #item
+
+        impl ::sidekiq::Worker for #self_ty {
+            type Args = #args;
+            fn perform(&self, args: Self::Args) -> ::sidekiq::JobHandlerResult {
+                <#self_ty>::perform(self, args)
+            }
+        }
+
+        impl ::sidekiq::WorkerClass for #self_ty {
+            fn class() -> &'static str {
+                #class
+            }
+            fn queue() -> &'static str {
+                #queue
+            }
+        }
+    })
+}
+
+fn type_name(ty: &Type) -> syn::Result<String> {
+    if let Type::Path(ref path) = *ty {
+        if let Some(segment) = path.path.segments.last() {
+            return Ok(segment.ident.to_string());
+        }
+    }
+    Err(syn::Error::new(ty.span(), "can't name the class, set it with `class = \"..\"`"))
+}
+
+// the type of the argument after `&self`
+fn perform_args(item: &ItemImpl) -> syn::Result<Type> {
+    for impl_item in &item.items {
+        if let ImplItem::Fn(ref method) = *impl_item {
+            if method.sig.ident != "perform" {
+                continue;
+            }
+            let inputs: Vec<_> = method.sig.inputs.iter().collect();
+            return match inputs.as_slice() {
+                [FnArg::Receiver(_), FnArg::Typed(args)] => Ok((*args.ty).clone()),
+                _ => {
+                    Err(syn::Error::new(method.sig.span(),
+                                        "expected `fn perform(&self, args: Args)`"))
+                }
+            };
+        }
+    }
+    Err(syn::Error::new(item.self_ty.span(), "expected a `perform` method"))
+}
@@ -0,0 +1,72 @@
+// the connection to redis of the async jobs, given to the async middlewares and handed out by
+// `SidekiqServer::async_redis`. it's a multiplexed `redis::aio` one instead of a pool, its
+// clones share it, and it reconnects on its own
+//
+//     fn count_runs(job: Job, mut redis: AsyncRedis, next: AsyncNextFunc) -> JobFuture {
+//         Box::pin(async move {
+//             let _: usize = redis::cmd("INCR").arg("runs").query_async(&mut redis).await?;
+//             next(job, redis).await
+//         })
+//     }
+use futures_util::future::{ready, FutureExt};
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::cluster_async::ClusterConnection;
+use redis::{Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+
+#[derive(Clone)]
+pub enum AsyncRedis {
+    Single(ConnectionManager),
+    Cluster(ClusterConnection),
+    // the server wasn't given a redis url, see `SidekiqServer::use_async_redis`, every command
+    // fails
+    Detached,
+}
+
+impl ConnectionLike for AsyncRedis {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match *self {
+            AsyncRedis::Single(ref mut conn) => conn.req_packed_command(cmd),
+            AsyncRedis::Cluster(ref mut conn) => conn.req_packed_command(cmd),
+            AsyncRedis::Detached => ready(Err(detached())).boxed(),
+        }
+    }
+
+    fn req_packed_commands<'a>(&'a mut self,
+                               pipeline: &'a Pipeline,
+                               offset: usize,
+                               count: usize)
+                               -> RedisFuture<'a, Vec<Value>> {
+        match *self {
+            AsyncRedis::Single(ref mut conn) => conn.req_packed_commands(pipeline, offset, count),
+            AsyncRedis::Cluster(ref mut conn) => conn.req_packed_commands(pipeline, offset, count),
+            AsyncRedis::Detached => ready(Err(detached())).boxed(),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match *self {
+            AsyncRedis::Single(ref conn) => conn.get_db(),
+            AsyncRedis::Cluster(ref conn) => conn.get_db(),
+            AsyncRedis::Detached => 0,
+        }
+    }
+}
+
+fn detached() -> RedisError {
+    (ErrorKind::Client, "no redis behind the server, see `use_async_redis`").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::cmd;
+    use tokio::runtime::Builder;
+
+    #[test]
+    fn fails_the_commands_detached() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let mut redis = AsyncRedis::Detached;
+        let pong = runtime.block_on(cmd("PING").query_async::<String>(&mut redis));
+        assert!(pong.unwrap_err().to_string().contains("use_async_redis"));
+    }
+}
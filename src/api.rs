@@ -0,0 +1,615 @@
+// the queues, the job sets and the processes the way ruby's `Sidekiq::API` shows them, for
+// admin tools and scripts
+use std::collections::VecDeque;
+use std::ops::Deref;
+
+use redis::{Commands, Pipeline};
+use serde_json::{from_str, to_string, Value as JValue};
+
+use chrono::UTC;
+
+use errors::*;
+use utils::with_namespace;
+use job::Job;
+use codec::decode_job;
+use middleware::{send_to_morgue, DEAD_MAX_JOBS, DEAD_TIMEOUT};
+use metrics::sample_queues;
+use {RedisConnection, RedisPool};
+
+pub use metrics::QueueStats;
+
+// how many jobs are read at once while iterating
+const PAGE_SIZE: isize = 50;
+
+// seconds a process has to pick up a command, same as ruby sidekiq
+const SIGNAL_TTL: usize = 60;
+
+fn now() -> f64 {
+    let now = UTC::now();
+    now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64
+}
+
+// push the job to its queue now, like the client does
+fn enqueue(conn: &mut RedisConnection, namespace: &str, job: &mut Job) -> Result<()> {
+    job.namespace = namespace.into();
+    job.at = None;
+    job.enqueued_at = UTC::now();
+    let _: () = Pipeline::new()
+        .sadd(job.with_namespace("queues"), &job.queue)
+        .lpush(job.queue_name(), to_string(job)?)
+        .query(conn)?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct Queue {
+    pool: RedisPool,
+    namespace: String,
+    pub name: String,
+}
+
+impl Queue {
+    pub fn new(pool: RedisPool, namespace: &str, name: &str) -> Queue {
+        Queue {
+            pool,
+            namespace: namespace.into(),
+            name: name.into(),
+        }
+    }
+
+    // every queue in the `queues` set, by name
+    pub fn all(pool: RedisPool, namespace: &str) -> Result<Vec<Queue>> {
+        let mut names: Vec<String> = pool.get()?.smembers(with_namespace(namespace, "queues"))?;
+        names.sort();
+        Ok(names.into_iter().map(|name| Queue::new(pool.clone(), namespace, &name)).collect())
+    }
+
+    fn key(&self) -> String {
+        with_namespace(&self.namespace, &("queue:".to_string() + &self.name))
+    }
+
+    pub fn size(&self) -> Result<usize> {
+        Ok(self.pool.get()?.llen(self.key())?)
+    }
+
+    // seconds the oldest job has been waiting
+    pub fn latency(&self) -> Result<f64> {
+        let oldest: Option<Vec<u8>> = self.pool.get()?.lindex(self.key(), -1)?;
+        Ok(oldest.and_then(|oldest| decode_job(&oldest).ok())
+            .map(|job| {
+                let enqueued_at = job.enqueued_at.timestamp() as f64 +
+                                  job.enqueued_at.timestamp_subsec_micros() as f64 / 1000000f64;
+                (now() - enqueued_at).max(0.0)
+            })
+            .unwrap_or(0.0))
+    }
+
+    // newest first, the jobs that can't be decoded are skipped
+    pub fn jobs(&self) -> QueueJobs<'_> {
+        QueueJobs {
+            queue: self,
+            offset: 0,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // scans the whole queue
+    pub fn find_job(&self, jid: &str) -> Result<Option<Job>> {
+        Ok(self.find(jid)?.map(|(_, job)| job))
+    }
+
+    // returns false if it isn't in the queue, e.g. a worker fetched it meanwhile
+    pub fn delete_job(&self, jid: &str) -> Result<bool> {
+        let payload = match self.find(jid)? {
+            Some((payload, _)) => payload,
+            None => return Ok(false),
+        };
+        let removed: usize = self.pool.get()?.lrem(self.key(), 1, payload)?;
+        Ok(removed > 0)
+    }
+
+    // drop every job and the queue itself, returns how many jobs were dropped
+    pub fn clear(&self) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let (size, _): (usize, ()) = Pipeline::new()
+            .atomic()
+            .llen(self.key())
+            .del(self.key())
+            .query(&mut *conn)?;
+        // on another slot of a cluster
+        let _: () = conn.srem(with_namespace(&self.namespace, "queues"), &self.name)?;
+        Ok(size)
+    }
+
+    fn find(&self, jid: &str) -> Result<Option<(Vec<u8>, Job)>> {
+        if jid.is_empty() {
+            return Ok(None);
+        }
+        let mut offset = 0;
+        loop {
+            let page = self.page(offset)?;
+            for payload in &page {
+                // the jid is kept as is by both formats, which is cheaper to look for than
+                // decoding every job
+                if !payload.windows(jid.len()).any(|w| w == jid.as_bytes()) {
+                    continue;
+                }
+                if let Ok(job) = decode_job(payload) {
+                    if job.jid == jid {
+                        return Ok(Some((payload.clone(), job)));
+                    }
+                }
+            }
+            if (page.len() as isize) < PAGE_SIZE {
+                return Ok(None);
+            }
+            offset += page.len() as isize;
+        }
+    }
+
+    fn page(&self, offset: isize) -> Result<Vec<Vec<u8>>> {
+        Ok(self.pool.get()?.lrange(self.key(), offset, offset + PAGE_SIZE - 1)?)
+    }
+}
+
+pub struct QueueJobs<'q> {
+    queue: &'q Queue,
+    offset: isize,
+    page: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl<'q> Iterator for QueueJobs<'q> {
+    type Item = Result<Job>;
+
+    fn next(&mut self) -> Option<Result<Job>> {
+        loop {
+            if let Some(payload) = self.page.pop_front() {
+                match decode_job(&payload) {
+                    Ok(job) => return Some(Ok(job)),
+                    Err(e) => {
+                        warn!("skipping a job of queue '{}': '{}'", self.queue.name, e);
+                        continue;
+                    }
+                }
+            }
+            if self.done {
+                return None;
+            }
+            match self.queue.page(self.offset) {
+                Ok(page) => {
+                    self.done = (page.len() as isize) < PAGE_SIZE;
+                    self.offset += page.len() as isize;
+                    self.page.extend(page);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// a sorted set of jobs scored by time, see `RetrySet`, `ScheduledSet` and `DeadSet`
+#[derive(Clone)]
+pub struct JobSet {
+    pool: RedisPool,
+    namespace: String,
+    pub name: &'static str,
+    // of the dead set the killed jobs are moved to
+    dead_max_jobs: usize,
+    dead_timeout: u64,
+}
+
+impl JobSet {
+    fn new(pool: RedisPool, namespace: &str, name: &'static str) -> JobSet {
+        JobSet {
+            pool,
+            namespace: namespace.into(),
+            name,
+            dead_max_jobs: DEAD_MAX_JOBS,
+            dead_timeout: DEAD_TIMEOUT,
+        }
+    }
+
+    fn key(&self) -> String {
+        with_namespace(&self.namespace, self.name)
+    }
+
+    pub fn size(&self) -> Result<usize> {
+        Ok(self.pool.get()?.zcard(self.key())?)
+    }
+
+    // earliest first
+    pub fn entries(&self) -> SortedEntries<'_> {
+        SortedEntries {
+            set: self,
+            offset: 0,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    pub fn find_job(&self, jid: &str) -> Result<Option<SortedEntry>> {
+        let mut conn = self.pool.get()?;
+        let found: Vec<String> = conn.zscan_match(self.key(), format!("*{}*", jid))?
+            .collect::<::redis::RedisResult<_>>()?;
+        for pair in found.chunks(2) {
+            if let [payload, score] = pair {
+                let entry = match self.entry(payload.clone(), score.parse().unwrap_or(0.0)) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                if entry.job.jid == jid {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // the operations of `SortedEntry` by jid, false if there is no such job
+    pub fn retry_job(&self, jid: &str) -> Result<bool> {
+        Ok(match self.find_job(jid)? {
+            Some(entry) => entry.retry()?,
+            None => false,
+        })
+    }
+
+    pub fn kill_job(&self, jid: &str) -> Result<bool> {
+        Ok(match self.find_job(jid)? {
+            Some(entry) => entry.kill()?,
+            None => false,
+        })
+    }
+
+    pub fn delete_job(&self, jid: &str) -> Result<bool> {
+        Ok(match self.find_job(jid)? {
+            Some(entry) => entry.delete()?,
+            None => false,
+        })
+    }
+
+    // push every job to its queue now, returns how many were
+    pub fn retry_all(&self) -> Result<usize> {
+        self.take_all(|entry| entry.retry())
+    }
+
+    // move every job to the dead set, returns how many were
+    pub fn kill_all(&self) -> Result<usize> {
+        if self.name == "dead" {
+            return Ok(0);
+        }
+        self.take_all(|entry| entry.kill())
+    }
+
+    // drop every job, returns how many were dropped
+    pub fn clear(&self) -> Result<usize> {
+        let (size, _): (usize, ()) = Pipeline::new()
+            .atomic()
+            .zcard(self.key())
+            .del(self.key())
+            .query(&mut *self.pool.get()?)?;
+        Ok(size)
+    }
+
+    // the set shrinks as the entries are taken out, so it's always read from its start,
+    // past the jobs that can't be decoded and stay there
+    fn take_all<F>(&self, mut take: F) -> Result<usize>
+        where F: FnMut(&SortedEntry) -> Result<bool>
+    {
+        let mut count = 0;
+        let mut skipped = 0;
+        loop {
+            let page = self.page(skipped)?;
+            if page.is_empty() {
+                return Ok(count);
+            }
+            for (payload, score) in page {
+                match self.entry(payload, score) {
+                    Some(entry) => {
+                        if take(&entry)? {
+                            count += 1;
+                        }
+                    }
+                    None => skipped += 1,
+                }
+            }
+        }
+    }
+
+    fn entry(&self, payload: String, score: f64) -> Option<SortedEntry> {
+        match from_str(&payload) {
+            Ok(job) => {
+                Some(SortedEntry {
+                    set: self.clone(),
+                    job,
+                    at: score,
+                    payload,
+                })
+            }
+            Err(e) => {
+                warn!("skipping a job of '{}': '{}'", self.name, e);
+                None
+            }
+        }
+    }
+
+    fn page(&self, offset: isize) -> Result<Vec<(String, f64)>> {
+        Ok(self.pool.get()?.zrange_withscores(self.key(), offset, offset + PAGE_SIZE - 1)?)
+    }
+}
+
+pub struct SortedEntries<'s> {
+    set: &'s JobSet,
+    offset: isize,
+    page: VecDeque<(String, f64)>,
+    done: bool,
+}
+
+impl<'s> Iterator for SortedEntries<'s> {
+    type Item = Result<SortedEntry>;
+
+    fn next(&mut self) -> Option<Result<SortedEntry>> {
+        loop {
+            if let Some((payload, score)) = self.page.pop_front() {
+                match self.set.entry(payload, score) {
+                    Some(entry) => return Some(Ok(entry)),
+                    None => continue,
+                }
+            }
+            if self.done {
+                return None;
+            }
+            match self.set.page(self.offset) {
+                Ok(page) => {
+                    self.done = (page.len() as isize) < PAGE_SIZE;
+                    self.offset += page.len() as isize;
+                    self.page.extend(page);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// a job of a `JobSet`, the operations return false when the job was already taken out of
+// the set, e.g. by the scheduler or another admin
+pub struct SortedEntry {
+    set: JobSet,
+    pub job: Job,
+    // when it's retried, scheduled or when it died
+    pub at: f64,
+    payload: String,
+}
+
+impl SortedEntry {
+    fn remove(&self, conn: &mut RedisConnection) -> Result<bool> {
+        let removed: usize = conn.zrem(self.set.key(), &self.payload)?;
+        Ok(removed > 0)
+    }
+
+    pub fn delete(&self) -> Result<bool> {
+        self.remove(&mut *self.set.pool.get()?)
+    }
+
+    // push it to its queue now, a retry doesn't count as one of its retries
+    pub fn retry(&self) -> Result<bool> {
+        let mut conn = self.set.pool.get()?;
+        if !self.remove(&mut conn)? {
+            return Ok(false);
+        }
+        let mut job = self.job.clone();
+        if let Some(ref mut info) = job.retry_info {
+            info.retry_count = info.retry_count.saturating_sub(1);
+        }
+        enqueue(&mut conn, &self.set.namespace, &mut job)?;
+        Ok(true)
+    }
+
+    // move it to the dead set
+    pub fn kill(&self) -> Result<bool> {
+        if self.set.name == "dead" {
+            return Ok(true);
+        }
+        let mut conn = self.set.pool.get()?;
+        if !self.remove(&mut conn)? {
+            return Ok(false);
+        }
+        let mut job = self.job.clone();
+        job.namespace = self.set.namespace.clone();
+        send_to_morgue(&mut conn, &job, self.set.dead_max_jobs, self.set.dead_timeout)?;
+        Ok(true)
+    }
+}
+
+macro_rules! job_set {
+    ($ty: ident, $name: expr) => {
+        #[derive(Clone)]
+        pub struct $ty(JobSet);
+
+        impl $ty {
+            pub fn new(pool: RedisPool, namespace: &str) -> $ty {
+                $ty(JobSet::new(pool, namespace, $name))
+            }
+
+            // like the server's `dead_max_jobs` and `dead_timeout`, for the jobs killed
+            pub fn dead_limits(mut self, max_jobs: usize, timeout: u64) -> $ty {
+                self.0.dead_max_jobs = max_jobs;
+                self.0.dead_timeout = timeout;
+                self
+            }
+        }
+
+        impl Deref for $ty {
+            type Target = JobSet;
+
+            fn deref(&self) -> &JobSet {
+                &self.0
+            }
+        }
+    }
+}
+
+// the jobs waiting for their next retry
+job_set!(RetrySet, "retry");
+// the jobs pushed with `perform_in` or `perform_at`
+job_set!(ScheduledSet, "schedule");
+// the jobs out of retries
+job_set!(DeadSet, "dead");
+
+// a process whose heartbeat is in redis, ruby sidekiq ones included
+#[derive(Debug, Clone)]
+pub struct Process {
+    pub identity: String,
+    pub hostname: String,
+    pub pid: usize,
+    pub started_at: f64,
+    pub concurrency: usize,
+    pub queues: Vec<String>,
+    pub labels: Vec<String>,
+    // how many jobs it's running
+    pub busy: usize,
+    pub quiet: bool,
+    // unix time of its last heartbeat
+    pub beat: f64,
+    pub rss_kb: usize,
+}
+
+// info, busy, quiet, beat and rss of the heartbeat hash
+type ProcessFields = (Option<String>, Option<usize>, Option<String>, Option<f64>, Option<usize>);
+
+#[derive(Clone)]
+pub struct ProcessSet {
+    pool: RedisPool,
+    namespace: String,
+}
+
+impl ProcessSet {
+    pub fn new(pool: RedisPool, namespace: &str) -> ProcessSet {
+        ProcessSet {
+            pool,
+            namespace: namespace.into(),
+        }
+    }
+
+    // the processes still beating, by identity
+    pub fn processes(&self) -> Result<Vec<Process>> {
+        let mut conn = self.pool.get()?;
+        let mut identities: Vec<String> =
+            conn.smembers(with_namespace(&self.namespace, "processes"))?;
+        identities.sort();
+        let mut pipe = Pipeline::new();
+        for identity in &identities {
+            pipe.cmd("HMGET")
+                .arg(with_namespace(&self.namespace, identity))
+                .arg(&["info", "busy", "quiet", "beat", "rss"]);
+        }
+        let fields: Vec<ProcessFields> = pipe.query(&mut *conn)?;
+        Ok(identities.into_iter()
+            .zip(fields)
+            .filter_map(|(identity, (info, busy, quiet, beat, rss))| {
+                // the heartbeat expired, the process is gone
+                let info: JValue = from_str(&info?).ok()?;
+                let strings = |key: &str| -> Vec<String> {
+                    info.get(key)
+                        .and_then(|v| v.as_array())
+                        .map(|v| v.iter().filter_map(|s| s.as_str()).map(|s| s.into()).collect())
+                        .unwrap_or_default()
+                };
+                Some(Process {
+                    hostname: info.get("hostname")
+                        .and_then(|h| h.as_str())
+                        .unwrap_or("")
+                        .into(),
+                    pid: info.get("pid").and_then(|p| p.as_u64()).unwrap_or(0) as usize,
+                    started_at: info.get("started_at").and_then(|s| s.as_f64()).unwrap_or(0.0),
+                    concurrency: info.get("concurrency").and_then(|c| c.as_u64()).unwrap_or(0) as
+                                 usize,
+                    queues: strings("queues"),
+                    labels: strings("labels"),
+                    busy: busy.unwrap_or(0),
+                    quiet: quiet.is_some_and(|q| q == "true"),
+                    beat: beat.unwrap_or(0.0),
+                    rss_kb: rss.unwrap_or(0),
+                    identity,
+                })
+            })
+            .collect())
+    }
+
+    pub fn size(&self) -> Result<usize> {
+        Ok(self.processes()?.len())
+    }
+
+    // stop the process fetching jobs, on its next heartbeat
+    pub fn quiet(&self, identity: &str) -> Result<()> {
+        self.signal(identity, "TSTP")
+    }
+
+    // stop the process like a TERM signal would, on its next heartbeat
+    pub fn stop(&self, identity: &str) -> Result<()> {
+        self.signal(identity, "TERM")
+    }
+
+    // the way sidekiq web does, a process that is gone never picks it up so it expires
+    fn signal(&self, identity: &str, signal: &str) -> Result<()> {
+        let key = with_namespace(&self.namespace, &(identity.to_string() + "-signals"));
+        let _: () = Pipeline::new()
+            .atomic()
+            .lpush(&key, signal)
+            .expire(&key, SIGNAL_TTL as i64)
+            .query(&mut *self.pool.get()?)?;
+        Ok(())
+    }
+}
+
+// the numbers of the dashboard of sidekiq web, for health checks
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub processed: usize,
+    pub failed: usize,
+    // jobs waiting in all the queues
+    pub enqueued: usize,
+    pub scheduled_size: usize,
+    pub retry_size: usize,
+    pub dead_size: usize,
+    pub processes_size: usize,
+    // by name
+    pub queues: Vec<QueueStats>,
+}
+
+impl Stats {
+    pub fn read(pool: &RedisPool, namespace: &str) -> Result<Stats> {
+        let mut conn = pool.get()?;
+        let key = |snippet: &str| with_namespace(namespace, snippet);
+        let (processed, failed, scheduled_size, retry_size, dead_size, processes_size, mut names):
+            (Option<usize>, Option<usize>, usize, usize, usize, usize, Vec<String>) =
+            Pipeline::new()
+                .get(key("stat:processed"))
+                .get(key("stat:failed"))
+                .zcard(key("schedule"))
+                .zcard(key("retry"))
+                .zcard(key("dead"))
+                .scard(key("processes"))
+                .smembers(key("queues"))
+                .query(&mut *conn)?;
+        names.sort();
+        let queues = sample_queues(&mut conn, namespace, &names)?;
+        Ok(Stats {
+            processed: processed.unwrap_or(0),
+            failed: failed.unwrap_or(0),
+            enqueued: queues.iter().map(|queue| queue.size).sum(),
+            scheduled_size,
+            retry_size,
+            dead_size,
+            processes_size,
+            queues,
+        })
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use metrics::QueueStats;
+use utils::process_cpu_secs;
+
+// how many workers may run a job at once, the others wait before fetching. the server has
+// as many workers as its concurrency and lets all of them run unless it has an autoscaler
+#[derive(Clone)]
+pub struct Slots {
+    target: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>,
+}
+
+pub struct SlotGuard<'a> {
+    slots: &'a Slots,
+}
+
+impl Slots {
+    pub fn new(target: usize) -> Slots {
+        Slots {
+            target: Arc::new(AtomicUsize::new(target)),
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn target(&self) -> usize {
+        self.target.load(Ordering::SeqCst)
+    }
+
+    // the jobs already running over a lower target still finish
+    pub fn set_target(&self, target: usize) {
+        self.target.store(target, Ordering::SeqCst);
+    }
+
+    pub fn try_acquire(&self) -> Option<SlotGuard<'_>> {
+        let mut running = self.running.load(Ordering::SeqCst);
+        loop {
+            if running >= self.target() {
+                return None;
+            }
+            match self.running
+                .compare_exchange(running, running + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(SlotGuard { slots: self }),
+                Err(actual) => running = actual,
+            }
+        }
+    }
+}
+
+impl<'a> Drop for SlotGuard<'a> {
+    fn drop(&mut self) {
+        self.slots.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// grows the jobs running at once up to `max` while the queues wait and the cpu has room,
+// and shrinks them down to `min` once they don't, see `SidekiqServer::attach_autoscaler`
+#[derive(Debug, Clone)]
+pub struct Autoscaler {
+    pub min: usize,
+    pub max: usize,
+    // grow when the oldest job of a queue has waited longer than this many seconds
+    pub scale_up_latency: f64,
+    // shrink when the oldest job of every queue has waited less
+    pub scale_down_latency: f64,
+    // share of all the cores this process may use, it doesn't grow above it and shrinks
+    // over it, only known on linux
+    pub max_cpu: f64,
+    // workers added or removed at once
+    pub step: usize,
+    // seconds between two decisions
+    pub interval: usize,
+    next: Option<Instant>,
+    // the cpu time of the process at the last decision
+    cpu: Option<(Instant, f64)>,
+}
+
+impl Autoscaler {
+    pub fn new(min: usize, max: usize) -> Autoscaler {
+        Autoscaler {
+            min: cmp::max(min, 1),
+            max: cmp::max(max, min),
+            scale_up_latency: 5.0,
+            scale_down_latency: 1.0,
+            max_cpu: 0.8,
+            step: 1,
+            interval: 10,
+            next: None,
+            cpu: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.next.is_none_or(|next| Instant::now() >= next)
+    }
+
+    // set the target of `slots` from the latency of the queues and the cpu used since the
+    // last decision
+    pub fn adjust(&mut self, slots: &Slots, stats: &[QueueStats]) {
+        let now = Instant::now();
+        self.next = Some(now + Duration::from_secs(self.interval as u64));
+        let cpu = process_cpu_secs();
+        let usage = match (self.cpu, cpu) {
+            (Some((at, before)), Some(after)) => {
+                let wall = now.duration_since(at);
+                let wall = wall.as_secs() as f64 + wall.subsec_nanos() as f64 / 1000000000f64;
+                let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+                Some((after - before) / (wall * cores as f64))
+            }
+            _ => None,
+        };
+        self.cpu = cpu.map(|cpu| (now, cpu));
+
+        let latency = stats.iter().map(|stat| stat.latency).fold(0.0, f64::max);
+        let busy_cpu = usage.is_some_and(|usage| usage > self.max_cpu);
+        let current = slots.target();
+        let target = if busy_cpu || latency < self.scale_down_latency {
+            current.saturating_sub(self.step)
+        } else if latency > self.scale_up_latency {
+            current + self.step
+        } else {
+            current
+        };
+        let target = cmp::min(cmp::max(target, self.min), self.max);
+        if target != current {
+            info!("autoscaling from {} to {} workers, queue latency {:.3}s, cpu {}",
+                  current,
+                  target,
+                  latency,
+                  usage.map_or("unknown".into(), |usage| format!("{:.0}%", usage * 100.0)));
+            slots.set_target(target);
+        }
+    }
+}
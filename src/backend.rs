@@ -0,0 +1,536 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Instant;
+
+use redis::{cmd, Commands, Pipeline, Script};
+use serde_json::Value as JValue;
+use chrono::{NaiveDate, UTC};
+
+use errors::*;
+use utils::with_namespace;
+use job::Job;
+use fetcher::{Fetcher, FetchContext, UnitOfWork};
+use scheduled::ScheduledPoller;
+use middleware::{send_to_morgue, DEAD_MAX_JOBS, DEAD_TIMEOUT};
+use results::{get_result, store_result};
+use worker::WORKERS_TTL;
+use api::Stats;
+use batch::BATCH_EXPIRY;
+use cluster::{scan_keys, slot_mate};
+use unique::{LOCK_SCRIPT, UNLOCK_SCRIPT};
+use RedisPool;
+
+// a job as a client pushes it
+#[derive(Debug, Clone)]
+pub enum Push {
+    // onto its queue, JSON or MessagePack
+    Enqueue(String, Vec<u8>),
+    // into the schedule set, enqueued by a server once `at`, in seconds since the epoch, is past
+    Schedule(f64, String),
+    // into the retry set, a failed job to run again once `at` is past
+    Retry(f64, String),
+}
+
+// which queues a worker fetches from, the fetcher decides in which order
+pub struct FetchRequest<'a> {
+    pub identity: &'a str,
+    pub queues: &'a [String],
+    pub weights: &'a [f64],
+    // seconds to block at most when there is no job
+    pub timeout: usize,
+}
+
+// what a server reports of itself on each heartbeat
+pub struct Heartbeat {
+    pub identity: String,
+    // JSON of the hostname, pid, queues... like ruby sidekiq's
+    pub info: String,
+    pub busy: usize,
+    pub quiet: bool,
+    pub rss: usize,
+    // the job of each busy worker
+    pub workers: Vec<(String, String)>,
+    // seconds before the process is seen dead without another heartbeat
+    pub ttl: usize,
+}
+
+// what a batch is at, to fire its callbacks
+pub struct BatchState {
+    // the jobs not done yet, the failed ones included
+    pub pending: isize,
+    pub failures: isize,
+    // JSON of the jobs to push on `success` and `complete`
+    pub callbacks: String,
+}
+
+// where the jobs, stats and processes are kept, redis by default, `MemoryBackend` for tests.
+// a server gets one with `SidekiqServer::with_backend` and a client with
+// `SidekiqClient::with_backend`
+//
+// the retries, dead jobs, unique locks and batches of the middlewares are here too. periodic
+// jobs, cancellation, queue patterns, job metrics and reaping need redis and are left out
+// without it
+pub trait Backend: Send + Sync {
+    fn push(&self, jobs: &[Push]) -> Result<()>;
+
+    // move the due retried and scheduled jobs onto their queues, returns how many
+    fn enqueue_scheduled(&self) -> Result<usize>;
+
+    fn fetch(&self,
+             fetcher: &mut dyn Fetcher,
+             request: &FetchRequest)
+             -> Result<Option<UnitOfWork>>;
+
+    // the next job if there's one, without waiting for it, to drain the queues
+    fn try_fetch(&self,
+                 fetcher: &mut dyn Fetcher,
+                 request: &FetchRequest)
+                 -> Result<Option<UnitOfWork>>;
+
+    // the job is done with, whatever its result
+    fn acknowledge(&self,
+                   fetcher: &mut dyn Fetcher,
+                   request: &FetchRequest,
+                   work: &UnitOfWork)
+                   -> Result<()>;
+
+    // when the server starts, before its workers
+    fn startup(&self, fetcher: &mut dyn Fetcher, request: &FetchRequest) -> Result<()>;
+
+    // push the jobs not done on shutdown back to their queues
+    fn requeue(&self,
+               fetcher: &mut dyn Fetcher,
+               request: &FetchRequest,
+               works: &[UnitOfWork])
+               -> Result<()>;
+
+    fn pause_queue(&self, name: &str) -> Result<()>;
+
+    fn unpause_queue(&self, name: &str) -> Result<()>;
+
+    fn paused_queues(&self) -> Result<Vec<String>>;
+
+    // into the dead set
+    fn bury(&self, job: &Job) -> Result<()>;
+
+    // the value a job returned, kept `ttl` seconds
+    fn store_result(&self, jid: &str, value: &JValue, ttl: usize) -> Result<()>;
+
+    fn result(&self, jid: &str) -> Result<Option<JValue>>;
+
+    // add to the processed and failed counts, the dated ones are kept `ttl` seconds
+    fn record_stats(&self, processed: usize, failed: usize, ttl: usize) -> Result<()>;
+
+    // the counts, set sizes and queues of the dashboard
+    fn read_stats(&self) -> Result<Stats>;
+
+    // drop the dated counts older than `ttl` seconds and expire the others, returns how many
+    // were dropped
+    fn prune_stats(&self, ttl: usize) -> Result<usize>;
+
+    // the lock of a unique job by its digest, false when another job holds it, for `ttl`
+    // seconds at most if given. the job holding it can take it again
+    fn lock(&self, digest: &str, jid: &str, ttl: Option<u64>) -> Result<bool>;
+
+    // only if the job still holds it
+    fn unlock(&self, digest: &str, jid: &str) -> Result<()>;
+
+    // count `jobs` more jobs in the batch, before they're pushed
+    fn open_batch(&self,
+                  bid: &str,
+                  jobs: isize,
+                  callbacks: &str,
+                  description: &str)
+                  -> Result<()>;
+
+    // the jobs counted in but not pushed after all
+    fn drop_from_batch(&self, bid: &str, jobs: isize) -> Result<()>;
+
+    // a job of the batch ran, it's done with unless it failed
+    fn batch_ran(&self, bid: &str, jid: &str, failed: bool) -> Result<()>;
+
+    // `None` once it expired
+    fn batch_state(&self, bid: &str) -> Result<Option<BatchState>>;
+
+    // true the first time only, so the callbacks of `event` are pushed once
+    fn fire_batch(&self, bid: &str, event: &str) -> Result<bool>;
+
+    fn heartbeat(&self, beat: &Heartbeat) -> Result<()>;
+
+    // the process is gone
+    fn deregister(&self, identity: &str) -> Result<()>;
+
+    // the next command sent to the process, like sidekiq web's `TSTP` or `TERM`
+    fn remote_signal(&self, identity: &str) -> Result<Option<String>>;
+
+    // the redis behind it, which the features needing it use
+    fn redis(&self) -> Option<&RedisPool> {
+        None
+    }
+}
+
+thread_local!(static CURRENT: RefCell<Option<Arc<dyn Backend>>> = RefCell::new(None));
+
+// the backend of the server running a job on this thread, for the middlewares, which are only
+// given its redis
+pub fn current() -> Option<Arc<dyn Backend>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// `f` run with `backend` as the current one, the previous one is back after it even if it panics
+pub fn using<T, F: FnOnce() -> T>(backend: Arc<dyn Backend>, f: F) -> T {
+    struct Restore(Option<Arc<dyn Backend>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(CURRENT.with(|current| current.replace(Some(backend))));
+    f()
+}
+
+// the current backend, or `redis` when the middlewares run without a server or client
+pub fn current_or_redis(redis: &RedisPool, namespace: &str) -> Arc<dyn Backend> {
+    match current() {
+        Some(backend) => backend,
+        None => Arc::new(RedisBackend::new(redis.clone(), redis.clone(), namespace)),
+    }
+}
+
+// the keys of ruby sidekiq, sidekiq pro's batches and sidekiq-unique-jobs' locks
+pub struct RedisBackend {
+    pool: RedisPool,
+    // only for the fetchers, so blocking on the queues doesn't hold up the short commands
+    fetch_pool: RedisPool,
+    namespace: String,
+    dead_max_jobs: usize,
+    dead_timeout: u64,
+}
+
+impl RedisBackend {
+    pub fn new(pool: RedisPool, fetch_pool: RedisPool, namespace: &str) -> RedisBackend {
+        RedisBackend {
+            pool,
+            fetch_pool,
+            namespace: namespace.into(),
+            dead_max_jobs: DEAD_MAX_JOBS,
+            dead_timeout: DEAD_TIMEOUT,
+        }
+    }
+
+    // how many jobs the dead set keeps at most, and for how many seconds
+    pub fn dead_limits(mut self, max_jobs: usize, timeout: u64) -> RedisBackend {
+        self.dead_max_jobs = max_jobs;
+        self.dead_timeout = timeout;
+        self
+    }
+
+    fn with_fetcher<T, F>(&self, request: &FetchRequest, f: F) -> Result<T>
+        where F: FnOnce(&mut FetchContext) -> Result<T>
+    {
+        let mut conn = self.fetch_pool.get()?;
+        f(&mut FetchContext {
+            conn: &mut conn,
+            namespace: &self.namespace,
+            identity: request.identity,
+            queues: request.queues,
+            weights: request.weights,
+            timeout: request.timeout,
+        })
+    }
+
+    fn lock_key(&self, digest: &str) -> String {
+        with_namespace(&self.namespace, &(digest.to_string() + ":EXISTS"))
+    }
+
+    fn batch_key(&self, bid: &str) -> String {
+        with_namespace(&self.namespace, &("b-".to_string() + bid))
+    }
+}
+
+impl Backend for RedisBackend {
+    fn push(&self, jobs: &[Push]) -> Result<()> {
+        let mut pipe = Pipeline::new();
+        let mut queues = vec![];
+        for job in jobs {
+            match *job {
+                Push::Enqueue(ref queue, ref payload) => {
+                    if !queues.contains(queue) {
+                        queues.push(queue.clone());
+                        pipe.sadd(with_namespace(&self.namespace, "queues"), queue).ignore();
+                    }
+                    pipe.lpush(with_namespace(&self.namespace, &("queue:".to_string() + queue)), &**payload)
+                        .ignore();
+                }
+                // the score carries the time, just like ruby's client
+                Push::Schedule(at, ref payload) => {
+                    pipe.zadd(with_namespace(&self.namespace, "schedule"), &**payload, at).ignore();
+                }
+                Push::Retry(at, ref payload) => {
+                    pipe.zadd(with_namespace(&self.namespace, "retry"), &**payload, at).ignore();
+                }
+            }
+        }
+        let _: () = pipe.query(&mut *self.pool.get()?)?;
+        Ok(())
+    }
+
+    fn enqueue_scheduled(&self) -> Result<usize> {
+        ScheduledPoller::new(self.pool.clone(), &self.namespace).enqueue_jobs()
+    }
+
+    fn fetch(&self,
+             fetcher: &mut dyn Fetcher,
+             request: &FetchRequest)
+             -> Result<Option<UnitOfWork>> {
+        self.with_fetcher(request, |ctx| fetcher.fetch(ctx))
+    }
+
+    fn try_fetch(&self,
+                 fetcher: &mut dyn Fetcher,
+                 request: &FetchRequest)
+                 -> Result<Option<UnitOfWork>> {
+        self.with_fetcher(request, |ctx| fetcher.try_fetch(ctx))
+    }
+
+    fn acknowledge(&self,
+                   fetcher: &mut dyn Fetcher,
+                   request: &FetchRequest,
+                   work: &UnitOfWork)
+                   -> Result<()> {
+        self.with_fetcher(request, |ctx| fetcher.acknowledge(ctx, work))
+    }
+
+    fn startup(&self, fetcher: &mut dyn Fetcher, request: &FetchRequest) -> Result<()> {
+        self.with_fetcher(request, |ctx| fetcher.startup(ctx))
+    }
+
+    fn requeue(&self,
+               fetcher: &mut dyn Fetcher,
+               request: &FetchRequest,
+               works: &[UnitOfWork])
+               -> Result<()> {
+        self.with_fetcher(request, |ctx| fetcher.bulk_requeue(ctx, works))
+    }
+
+    // shared by every process through the sidekiq pro `paused` set
+    fn pause_queue(&self, name: &str) -> Result<()> {
+        let _: () = self.pool.get()?.sadd(with_namespace(&self.namespace, "paused"), name)?;
+        Ok(())
+    }
+
+    fn unpause_queue(&self, name: &str) -> Result<()> {
+        let _: () = self.pool.get()?.srem(with_namespace(&self.namespace, "paused"), name)?;
+        Ok(())
+    }
+
+    fn paused_queues(&self) -> Result<Vec<String>> {
+        Ok(self.pool.get()?.smembers(with_namespace(&self.namespace, "paused"))?)
+    }
+
+    fn bury(&self, job: &Job) -> Result<()> {
+        send_to_morgue(&mut *self.pool.get()?, job, self.dead_max_jobs, self.dead_timeout)
+    }
+
+    fn store_result(&self, jid: &str, value: &JValue, ttl: usize) -> Result<()> {
+        store_result(&mut *self.pool.get()?, &self.namespace, jid, value, ttl)
+    }
+
+    fn result(&self, jid: &str) -> Result<Option<JValue>> {
+        get_result(&mut *self.pool.get()?, &self.namespace, jid)
+    }
+
+    // in one pipeline, like ruby sidekiq
+    fn record_stats(&self, processed: usize, failed: usize, ttl: usize) -> Result<()> {
+        let today = UTC::now().format("%Y-%m-%d").to_string();
+        let mut pipe = Pipeline::new();
+        for &(stat, n) in &[("processed", processed), ("failed", failed)] {
+            if n == 0 {
+                continue;
+            }
+            let dated = with_namespace(&self.namespace, &format!("stat:{}:{}", stat, today));
+            pipe.incr(&dated, n)
+                .ignore()
+                .expire(&dated, ttl as i64)
+                .ignore()
+                .incr(with_namespace(&self.namespace, &format!("stat:{}", stat)), n)
+                .ignore();
+        }
+        let _: () = pipe.query(&mut *self.pool.get()?)?;
+        Ok(())
+    }
+
+    fn read_stats(&self) -> Result<Stats> {
+        Stats::read(&self.pool, &self.namespace)
+    }
+
+    // the keys written by processes without a ttl on them
+    fn prune_stats(&self, ttl: usize) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let now = UTC::now().timestamp();
+        let mut count = 0;
+        for stat in &["processed", "failed"] {
+            let prefix = with_namespace(&self.namespace, &format!("stat:{}:", stat));
+            for key in scan_keys(&mut conn, &(prefix.clone() + "*"))? {
+                let day = match NaiveDate::parse_from_str(&key[prefix.len()..], "%Y-%m-%d") {
+                    Ok(day) => day.and_hms(0, 0, 0).timestamp(),
+                    Err(_) => continue,
+                };
+                let left = day + ttl as i64 - now;
+                if left <= 0 {
+                    let _: () = conn.del(&key)?;
+                    count += 1;
+                } else if cmd("TTL").arg(&key).query::<i64>(&mut *conn)? == -1 {
+                    let _: () = conn.expire(&key, left)?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn lock(&self, digest: &str, jid: &str, ttl: Option<u64>) -> Result<bool> {
+        let locked: usize = Script::new(LOCK_SCRIPT)
+            .key(self.lock_key(digest))
+            .arg(jid)
+            .arg(ttl.unwrap_or(0))
+            .invoke(&mut *self.pool.get()?)?;
+        Ok(locked == 1)
+    }
+
+    fn unlock(&self, digest: &str, jid: &str) -> Result<()> {
+        let _: usize = Script::new(UNLOCK_SCRIPT)
+            .key(self.lock_key(digest))
+            .arg(jid)
+            .invoke(&mut *self.pool.get()?)?;
+        Ok(())
+    }
+
+    fn open_batch(&self,
+                  bid: &str,
+                  jobs: isize,
+                  callbacks: &str,
+                  description: &str)
+                  -> Result<()> {
+        let key = self.batch_key(bid);
+        let _: () = Pipeline::new()
+            .atomic()
+            .hincr(&key, "pending", jobs)
+            .hincr(&key, "total", jobs)
+            .hset(&key, "callbacks", callbacks)
+            .hset(&key, "description", description)
+            .expire(&key, BATCH_EXPIRY as i64)
+            .query(&mut *self.pool.get()?)?;
+        Ok(())
+    }
+
+    fn drop_from_batch(&self, bid: &str, jobs: isize) -> Result<()> {
+        let _: isize = self.pool.get()?.hincr(self.batch_key(bid), "pending", -jobs)?;
+        Ok(())
+    }
+
+    fn batch_ran(&self, bid: &str, jid: &str, failed: bool) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let key = self.batch_key(bid);
+        let failures = slot_mate(&conn, &key, "-failed");
+        let mut pipe = Pipeline::new();
+        pipe.atomic();
+        if failed {
+            pipe.sadd(&failures, jid).ignore().expire(&failures, BATCH_EXPIRY as i64).ignore();
+        } else {
+            pipe.hincr(&key, "pending", -1).ignore().srem(&failures, jid).ignore();
+        }
+        let _: () = pipe.query(&mut *conn)?;
+        Ok(())
+    }
+
+    fn batch_state(&self, bid: &str) -> Result<Option<BatchState>> {
+        let mut conn = self.pool.get()?;
+        let key = self.batch_key(bid);
+        let (pending, failures, callbacks): (Option<isize>, isize, Option<String>) =
+            Pipeline::new()
+                .atomic()
+                .hget(&key, "pending")
+                .scard(slot_mate(&conn, &key, "-failed"))
+                .hget(&key, "callbacks")
+                .query(&mut *conn)?;
+        Ok(match (pending, callbacks) {
+            (Some(pending), Some(callbacks)) => {
+                Some(BatchState {
+                    pending,
+                    failures,
+                    callbacks,
+                })
+            }
+            _ => None,
+        })
+    }
+
+    fn fire_batch(&self, bid: &str, event: &str) -> Result<bool> {
+        let field = event.to_string() + "_fired";
+        Ok(self.pool.get()?.hset_nx(self.batch_key(bid), field, 1)?)
+    }
+
+    fn heartbeat(&self, beat: &Heartbeat) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        // lets the dashboard flag a slow link to redis
+        let ping = Instant::now();
+        let _: String = cmd("PING").query(&mut *conn)?;
+        let rtt = ping.elapsed();
+        let rtt_us = rtt.as_secs() * 1000000 + rtt.subsec_micros() as u64;
+
+        let now = UTC::now();
+        let content = vec![("info", beat.info.clone()),
+                           ("busy", beat.busy.to_string()),
+                           ("quiet", beat.quiet.to_string()),
+                           ("rss", beat.rss.to_string()),
+                           ("rtt_us", rtt_us.to_string()),
+                           ("beat",
+                            (now.timestamp() as f64 +
+                             now.timestamp_subsec_micros() as f64 / 1000000f64)
+                                .to_string())];
+        let key = with_namespace(&self.namespace, &beat.identity);
+        let workers_key = slot_mate(&conn, &key, ":workers");
+        // registered once it has its hash, the set is on another slot of a cluster
+        let mut pipe = Pipeline::new();
+        pipe.atomic()
+            .hset_multiple(&key, &content)
+            .ignore()
+            .expire(&key, beat.ttl as i64)
+            .ignore()
+            .del(&workers_key)
+            .ignore();
+        if !beat.workers.is_empty() {
+            // like ruby sidekiq, kept alive on each heartbeat while jobs run longer
+            pipe.hset_multiple(&workers_key, &beat.workers)
+                .ignore()
+                .expire(&workers_key, WORKERS_TTL as i64)
+                .ignore();
+        }
+        pipe.query::<()>(&mut *conn)?;
+        let _: () = conn.sadd(with_namespace(&self.namespace, "processes"), &beat.identity)?;
+        Ok(())
+    }
+
+    // so the dashboard doesn't show the process until its heartbeat expires
+    fn deregister(&self, identity: &str) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let key = with_namespace(&self.namespace, identity);
+        let _: () = Pipeline::new()
+            .srem(with_namespace(&self.namespace, "processes"), identity)
+            .del(slot_mate(&conn, &key, ":workers"))
+            .del(&key)
+            .query(&mut *conn)?;
+        Ok(())
+    }
+
+    // sidekiq web pushes its commands to `<identity>-signals`
+    fn remote_signal(&self, identity: &str) -> Result<Option<String>> {
+        let key = with_namespace(&self.namespace, &(identity.to_string() + "-signals"));
+        Ok(self.pool.get()?.rpop(key, None)?)
+    }
+
+    fn redis(&self) -> Option<&RedisPool> {
+        Some(&self.pool)
+    }
+}
@@ -0,0 +1,88 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+// seconds before the first retry, doubled on each failed one
+const BASE_DELAY: u64 = 1;
+const MAX_DELAY: u64 = 30;
+
+// whether redis is reachable, shared by the server and its workers so they stop hammering it
+// together while it's down.
+//
+// closed while commands succeed, open for a delay after a connection error, during which
+// nothing is fetched or reported, then the first command tried either closes it again or
+// opens it for twice as long
+#[derive(Clone)]
+pub struct Backoff {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            state: Arc::new(Mutex::new(State {
+                failures: 0,
+                retry_at: None,
+            })),
+        }
+    }
+
+    // how long to wait before trying redis again, none when it can be tried now
+    pub fn wait(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.retry_at {
+            Some(at) if at > now => Some(at - now),
+            _ => None,
+        }
+    }
+
+    // true if it's a connection error, other errors leave the state as it is
+    pub fn failed(&self, e: &Error) -> bool {
+        if !is_connection_error(e) {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        // the others failing while it's open don't make the delay longer
+        if state.retry_at.is_some_and(|at| at > now) {
+            return true;
+        }
+        let delay = cmp::min(BASE_DELAY << cmp::min(state.failures, 8), MAX_DELAY);
+        state.failures += 1;
+        state.retry_at = Some(now + Duration::from_secs(delay));
+        if state.failures == 1 {
+            error!("redis is unavailable: '{}', pausing for {}s", e, delay);
+        } else {
+            warn!("redis is still unavailable after {} tries, pausing for {}s",
+                  state.failures,
+                  delay);
+        }
+        true
+    }
+
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            info!("redis is back after {} failed tries, resuming", state.failures);
+            state.failures = 0;
+            state.retry_at = None;
+        }
+    }
+}
+
+fn is_connection_error(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::RedisError(ref e) => e.is_io_error(),
+        // the pool can't open a connection
+        ErrorKind::R2D2Error(_) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use serde_json::{from_str, from_value, to_string, Value as JValue};
+
+use errors::*;
+use job::{Job, new_jid};
+use client::SidekiqClient;
+use backend::{current_or_redis, Backend};
+use middleware::{MiddleWareResult, NextFunc};
+use JobSuccessType;
+use RedisPool;
+
+// batch keys are kept for 30 days, like sidekiq pro
+pub const BATCH_EXPIRY: usize = 30 * 24 * 60 * 60;
+
+pub struct Batch {
+    pub bid: String,
+    pub description: Option<String>,
+    on_success: Vec<Job>,
+    on_complete: Vec<Job>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch {
+            bid: new_jid(),
+            description: None,
+            on_success: vec![],
+            on_complete: vec![],
+        }
+    }
+
+    // pushed once every job of the batch succeeded
+    pub fn on_success(&mut self, callback: Job) {
+        self.on_success.push(callback);
+    }
+
+    // pushed once every job of the batch has run, whether it succeeded or not
+    pub fn on_complete(&mut self, callback: Job) {
+        self.on_complete.push(callback);
+    }
+
+    // tag the jobs with the bid and push them, can be called several times for the same batch
+    pub fn push_bulk<I: IntoIterator<Item = Job>>(&self,
+                                                  client: &mut SidekiqClient,
+                                                  jobs: I)
+                                                  -> Result<Vec<String>> {
+        let jobs: Vec<_> = jobs.into_iter()
+            .map(|mut job| {
+                job.extra.insert("bid".into(), JValue::String(self.bid.clone()));
+                job
+            })
+            .collect();
+        let total = jobs.len() as isize;
+        let callbacks = json!({
+            "success": self.on_success,
+            "complete": self.on_complete,
+        });
+
+        let backend = client.backend();
+        // count the jobs before pushing them, so the batch can't drain before they are all pushed
+        backend.open_batch(&self.bid,
+                        total,
+                        &to_string(&callbacks)?,
+                        &self.description.clone().unwrap_or_default())?;
+
+        let jids = client.push_bulk(jobs)?;
+        let vetoed = total - jids.len() as isize;
+        if vetoed != 0 {
+            backend.drop_from_batch(&self.bid, vetoed)?;
+            check_batch(&backend, &self.bid, client.redis_pool())?;
+        }
+        Ok(jids)
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Batch {
+        Batch::new()
+    }
+}
+
+// records the outcome of batch jobs and fires the callbacks, should be attached before
+// retry_middleware so that a retried job is seen as a failure
+pub fn batch_middleware(job: &mut Job, redis: RedisPool, next: NextFunc) -> MiddleWareResult {
+    let bid = match job.extra.get("bid").and_then(|b| b.as_str()) {
+        Some(bid) => bid.to_string(),
+        None => return next(job, redis),
+    };
+    let backend = current_or_redis(&redis, &job.namespace);
+    // retry_middleware after this one fails the job by scheduling a retry, or reschedules it
+    // when it's limited, returning `Ignore` for both
+    let failure = |job: &Job| job.retry_info.as_ref().map(|i| (i.retry_count, i.failed_at));
+    let overrated = |job: &Job| job.extra.get("overrated").cloned();
+    let (failed_before, overrated_before) = (failure(job), overrated(job));
+    let r = next(job, redis.clone());
+    let retried = failure(job) != failed_before;
+    let limited = overrated(job) != overrated_before;
+
+    let (failed, pending) = match r {
+        Err(_) => (true, false),
+        Ok(JobSuccessType::Ignore) if retried => (true, false),
+        Ok(JobSuccessType::Ignore) if limited => (false, true),
+        Ok(JobSuccessType::Reschedule(_)) => (false, true),
+        // done with, also when a unique or cancelled job is ignored
+        Ok(_) => (false, false),
+    };
+    if !pending {
+        backend.batch_ran(&bid, &job.jid, failed)?;
+    }
+    check_batch(&backend, &bid, &redis)?;
+    r
+}
+
+fn check_batch(backend: &Arc<dyn Backend>, bid: &str, redis: &RedisPool) -> Result<()> {
+    let state = match backend.batch_state(bid)? {
+        Some(state) => state,
+        None => return Ok(()), // expired or unknown batch
+    };
+    let callbacks: JValue = from_str(&state.callbacks)?;
+
+    let mut fire = vec![];
+    // every job ran at least once
+    if state.pending == state.failures && backend.fire_batch(bid, "complete")? {
+        fire.push("complete");
+    }
+    if state.pending == 0 && backend.fire_batch(bid, "success")? {
+        fire.push("success");
+    }
+
+    let mut client = SidekiqClient::with_shared_backend(redis.clone(), backend.clone());
+    for event in fire {
+        info!("batch '{}' {}, firing callbacks", bid, event);
+        let jobs: Vec<Job> = from_value(callbacks[event].clone())?;
+        client.push_bulk(jobs.into_iter().map(|mut job| {
+                job.jid = new_jid();
+                job.extra.insert("callback_bid".into(), JValue::String(bid.to_string()));
+                job.extra.insert("callback_event".into(), JValue::String(event.to_string()));
+                job
+            }))?;
+    }
+    Ok(())
+}
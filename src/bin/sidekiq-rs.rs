@@ -0,0 +1,14 @@
+// the server with the example handlers only, to try a setup out, services call
+// `sidekiq::cli::run` from their own main with their handlers
+extern crate sidekiq;
+
+use sidekiq::{error_handler, panic_handler, printer_handler, retry_middleware};
+
+fn main() {
+    ::std::process::exit(sidekiq::cli::run(|server| {
+        server.attach_handler("Printer", printer_handler);
+        server.attach_handler("Error", error_handler);
+        server.attach_handler("Panic", panic_handler);
+        server.attach_middleware(retry_middleware);
+    }));
+}
@@ -0,0 +1,549 @@
+use std::env;
+#[cfg(feature = "yaml")]
+use std::fs;
+use std::time::Duration;
+
+use r2d2::Pool;
+
+#[cfg(feature = "yaml")]
+use yaml_rust::{Yaml, YamlLoader};
+
+use RedisPool;
+use client::SidekiqClient;
+use utils::{cluster_connection_manager, connection_manager, RedisConnectionManager, TlsConfig};
+use errors::*;
+use server::SidekiqServer;
+use fetcher::StrictFetcher;
+
+// the workers of a capsule and the queues they fetch from, see `SidekiqServer::new_capsule`
+#[derive(Debug, Clone)]
+pub struct CapsuleConfig {
+    pub name: String,
+    pub concurrency: usize,
+    pub queues: Vec<(String, usize)>,
+}
+
+// what a server is started with, gathered before it's built, e.g. from flags by the `cli`
+// module, the rest is set on the server afterwards
+#[derive(Debug, Clone)]
+pub struct SidekiqServerBuilder {
+    pub redis: String,
+    // the certificates of a `rediss://` url, the system and webpki roots are trusted without it
+    pub tls: Option<TlsConfig>,
+    // nodes of a redis cluster to connect to instead of `redis`, any of them is enough to
+    // find the others
+    pub cluster: Vec<String>,
+    // used instead of connecting to `redis` when set, the workers also fetch from it unless
+    // `fetch_pool` is set
+    pub redis_pool: Option<RedisPool>,
+    pub fetch_pool: Option<RedisPool>,
+    // connections of the pool, `concurrency` + 3 by default, as the handlers may each use one
+    pub pool_size: Option<u32>,
+    // connections of the pool the workers fetch with, `concurrency` + 1 by default, as each
+    // of them holds one. the workers of the capsules count in `concurrency` for both
+    pub fetch_pool_size: Option<u32>,
+    // connections kept open while idle, all of them by default
+    pub min_idle: Option<u32>,
+    // seconds to wait for a connection before failing, 30 by default
+    pub connection_timeout: Option<usize>,
+    // seconds an idle connection above `min_idle` is kept, 10 minutes by default
+    pub idle_timeout: Option<usize>,
+    // prefix of every key, of the server and of the clients, schedulers and reporters it
+    // makes, none by default. the keys are spread over the slots of a cluster whatever it is,
+    // the ones used together are named after each other's hash tag, see `cluster.rs`
+    pub namespace: String,
+    pub concurrency: usize,
+    // by name, with their weights
+    pub queues: Vec<(String, usize)>,
+    // fetch the queues in their order instead of by weight, with `StrictFetcher`, set for a
+    // sidekiq.yml listing them without weights as ruby does
+    pub strict: bool,
+    // besides the `default` one of `concurrency` and `queues`
+    pub capsules: Vec<CapsuleConfig>,
+    // seconds the running jobs get to finish on shutdown
+    pub timeout: usize,
+}
+
+impl Default for SidekiqServerBuilder {
+    fn default() -> SidekiqServerBuilder {
+        SidekiqServerBuilder {
+            redis: "redis://127.0.0.1/".into(),
+            tls: None,
+            cluster: vec![],
+            redis_pool: None,
+            fetch_pool: None,
+            pool_size: None,
+            fetch_pool_size: None,
+            min_idle: None,
+            connection_timeout: None,
+            idle_timeout: None,
+            namespace: String::new(),
+            concurrency: 10,
+            queues: vec![],
+            strict: false,
+            capsules: vec![],
+            timeout: 10,
+        }
+    }
+}
+
+impl SidekiqServerBuilder {
+    pub fn new() -> SidekiqServerBuilder {
+        SidekiqServerBuilder::default()
+    }
+
+    pub fn redis(mut self, url: &str) -> SidekiqServerBuilder {
+        self.redis = url.into();
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> SidekiqServerBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn cluster_node(mut self, url: &str) -> SidekiqServerBuilder {
+        self.cluster.push(url.into());
+        self
+    }
+
+    // an application managed pool, e.g. with its own timeouts or connection customizer, see
+    // `SidekiqServer::with_pool`
+    pub fn redis_pool(mut self, pool: RedisPool) -> SidekiqServerBuilder {
+        self.redis_pool = Some(pool);
+        self
+    }
+
+    // fetch with an application managed pool, see `SidekiqServer::with_pools`
+    pub fn fetch_pool(mut self, pool: RedisPool) -> SidekiqServerBuilder {
+        self.fetch_pool = Some(pool);
+        self
+    }
+
+    pub fn pool_size(mut self, size: u32) -> SidekiqServerBuilder {
+        self.pool_size = Some(size);
+        self
+    }
+
+    pub fn fetch_pool_size(mut self, size: u32) -> SidekiqServerBuilder {
+        self.fetch_pool_size = Some(size);
+        self
+    }
+
+    pub fn min_idle(mut self, min_idle: u32) -> SidekiqServerBuilder {
+        self.min_idle = Some(min_idle);
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: usize) -> SidekiqServerBuilder {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: usize) -> SidekiqServerBuilder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> SidekiqServerBuilder {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> SidekiqServerBuilder {
+        self.concurrency = concurrency;
+        self
+    }
+
+    // add a queue, or change its weight if it is already there
+    pub fn queue(mut self, name: &str, weight: usize) -> SidekiqServerBuilder {
+        match self.queues.iter_mut().find(|queue| queue.0 == name) {
+            Some(queue) => queue.1 = weight,
+            None => self.queues.push((name.into(), weight)),
+        }
+        self
+    }
+
+    // a capsule of `concurrency` more workers fetching from `queues`, by name with their
+    // weights
+    pub fn capsule(mut self,
+                   name: &str,
+                   concurrency: usize,
+                   queues: &[(&str, usize)])
+                   -> SidekiqServerBuilder {
+        self.capsules.push(CapsuleConfig {
+            name: name.into(),
+            concurrency,
+            queues: queues.iter().map(|&(name, weight)| (name.into(), weight)).collect(),
+        });
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> SidekiqServerBuilder {
+        self.strict = strict;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: usize) -> SidekiqServerBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    // the defaults overridden by the environment, see `with_env`
+    pub fn from_env() -> Result<SidekiqServerBuilder> {
+        SidekiqServerBuilder::new().with_env()
+    }
+
+    // override with the variables that are set
+    //
+    //     REDIS_URL, or the variable named by REDIS_PROVIDER
+    //     SIDEKIQ_NAMESPACE
+    //     SIDEKIQ_CONCURRENCY, or RAILS_MAX_THREADS
+    //     SIDEKIQ_QUEUES     comma separated `name[:weight]`, e.g. `critical:3,default`
+    //     SIDEKIQ_TIMEOUT
+    pub fn with_env(mut self) -> Result<SidekiqServerBuilder> {
+        let redis = env_var("REDIS_PROVIDER").and_then(|name| env_var(&name));
+        if let Some(url) = redis.or_else(|| env_var("REDIS_URL")) {
+            self.redis = url;
+        }
+        if let Some(namespace) = env_var("SIDEKIQ_NAMESPACE") {
+            self.namespace = namespace;
+        }
+        if let Some(concurrency) = env_number("SIDEKIQ_CONCURRENCY")? {
+            self.concurrency = concurrency;
+        } else if let Some(concurrency) = env_number("RAILS_MAX_THREADS")? {
+            self.concurrency = concurrency;
+        }
+        if let Some(queues) = env_var("SIDEKIQ_QUEUES") {
+            self.queues.clear();
+            for queue in queues.split(',').map(|queue| queue.trim()).filter(|q| !q.is_empty()) {
+                let mut sp = queue.rsplitn(2, ':');
+                let (name, weight) = match (sp.next(), sp.next()) {
+                    (Some(weight), Some(name)) => {
+                        match weight.parse() {
+                            Ok(weight) => (name, weight),
+                            Err(_) => {
+                                return Err(format!("invalid queue '{}' in SIDEKIQ_QUEUES", queue)
+                                    .into())
+                            }
+                        }
+                    }
+                    _ => (queue, 1),
+                };
+                self = self.queue(name, weight);
+            }
+        }
+        if let Some(timeout) = env_number("SIDEKIQ_TIMEOUT")? {
+            self.timeout = timeout;
+        }
+        Ok(self)
+    }
+
+    // read a `sidekiq.yml` of the ruby sidekiq, the section of the environment named by
+    // APP_ENV, RAILS_ENV or RACK_ENV, `development` if none, overrides the top level
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(path: &str) -> Result<SidekiqServerBuilder> {
+        let environment = ["APP_ENV", "RAILS_ENV", "RACK_ENV"]
+            .iter()
+            .filter_map(|name| env_var(name))
+            .next()
+            .unwrap_or_else(|| "development".into());
+        SidekiqServerBuilder::from_yaml_env(path, &environment)
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_env(path: &str, environment: &str) -> Result<SidekiqServerBuilder> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::from(format!("reading '{}' failed: '{}'", path, e)))?;
+        let docs = YamlLoader::load_from_str(&text)
+            .map_err(|e| Error::from(format!("parsing '{}' failed: '{}'", path, e)))?;
+        let mut builder = SidekiqServerBuilder::new();
+        if let Some(doc) = docs.first() {
+            builder = builder.apply_yaml(doc)?;
+            let section = yaml_key(doc, environment);
+            if !section.is_badvalue() {
+                builder = builder.apply_yaml(section)?;
+            }
+        }
+        Ok(builder)
+    }
+
+    // unknown keys like `:pidfile:` or `:require:` are ignored
+    #[cfg(feature = "yaml")]
+    fn apply_yaml(mut self, section: &Yaml) -> Result<SidekiqServerBuilder> {
+        if let Some(concurrency) = yaml_number(section, "concurrency")? {
+            self.concurrency = concurrency;
+        }
+        if let Some(timeout) = yaml_number(section, "timeout")? {
+            self.timeout = timeout;
+        }
+        match *yaml_key(section, "queues") {
+            Yaml::Array(ref queues) => {
+                // a section's queues replace the ones above it
+                self.queues.clear();
+                // like ruby, strictly ordered unless one of them has a weight
+                self.strict = true;
+                for queue in queues {
+                    // `- default` or `- [default, 2]`
+                    let (name, weight) = match *queue {
+                        Yaml::String(ref name) => (name.as_str(), Some(1)),
+                        Yaml::Array(ref pair) => {
+                            if pair.len() > 1 {
+                                self.strict = false;
+                            }
+                            (pair.first().and_then(|name| name.as_str()).unwrap_or(""),
+                             pair.get(1).map_or(Some(1), |weight| weight.as_i64()))
+                        }
+                        _ => ("", None),
+                    };
+                    match weight {
+                        Some(weight) if !name.is_empty() && weight >= 0 => {
+                            self = self.queue(name, weight as usize);
+                        }
+                        _ => return Err(format!("invalid queue '{:?}'", queue).into()),
+                    }
+                }
+            }
+            Yaml::BadValue => {}
+            _ => return Err("`queues` isn't a list".into()),
+        }
+        Ok(self)
+    }
+
+    // a client pushing to the redis and namespace the server fetches from, for the processes
+    // that only push jobs
+    pub fn client(&self) -> Result<SidekiqClient> {
+        match self.redis_pool {
+            Some(ref pool) => Ok(SidekiqClient::new(pool.clone(), &self.namespace)),
+            None if self.cluster.is_empty() => {
+                SidekiqClient::connect_with_tls(&self.redis, &self.namespace, self.tls.as_ref())
+            }
+            None => {
+                SidekiqClient::connect_cluster(&self.cluster, &self.namespace, self.tls.as_ref())
+            }
+        }
+    }
+
+    fn connect(&self, size: u32) -> Result<RedisPool> {
+        let mut config = Pool::builder()
+            .max_size(size)
+            .min_idle(self.min_idle);
+        if let Some(timeout) = self.connection_timeout {
+            config = config.connection_timeout(Duration::from_secs(timeout as u64));
+        }
+        if let Some(timeout) = self.idle_timeout {
+            config = config.idle_timeout(Some(Duration::from_secs(timeout as u64)));
+        }
+        Ok(config.build(self.manager()?)?)
+    }
+
+    fn manager(&self) -> Result<RedisConnectionManager> {
+        if self.cluster.is_empty() {
+            connection_manager(&self.redis, self.tls.as_ref())
+        } else {
+            cluster_connection_manager(&self.cluster, self.tls.as_ref())
+        }
+    }
+
+    pub fn build<'a>(mut self) -> Result<SidekiqServer<'a>> {
+        let workers = self.concurrency +
+                      self.capsules.iter().map(|capsule| capsule.concurrency).sum::<usize>();
+        let fetch_pool = match (self.fetch_pool.take(), self.redis_pool.as_ref()) {
+            (Some(pool), _) => pool,
+            (None, Some(pool)) => pool.clone(),
+            (None, None) => self.connect(self.fetch_pool_size.unwrap_or(workers as u32 + 1))?,
+        };
+        let own_pool = self.redis_pool.is_some();
+        let pool = match self.redis_pool.take() {
+            Some(pool) => pool,
+            None => self.connect(self.pool_size.unwrap_or(workers as u32 + 3))?,
+        };
+        let mut server = SidekiqServer::with_pools(pool, fetch_pool, self.concurrency)?;
+        if !own_pool {
+            server.use_async_redis(self.manager()?);
+        }
+        for (name, weight) in &self.queues {
+            server.new_queue(name, *weight);
+        }
+        for capsule in &self.capsules {
+            let queues = server.new_capsule(&capsule.name, capsule.concurrency)?;
+            for (name, weight) in &capsule.queues {
+                queues.add(name, *weight);
+            }
+        }
+        if self.strict {
+            server.attach_fetcher(StrictFetcher);
+        }
+        server.namespace = self.namespace;
+        server.force_quite_timeout = self.timeout;
+        Ok(server)
+    }
+}
+
+// unset and empty are the same
+// the variables read by `with_env`, `from_yaml` and the cli, which the tests set. they are
+// shared by the threads running the tests, so those holding the lock run one by one
+#[cfg(test)]
+pub fn with_env_vars<T, F: FnOnce() -> T>(vars: &[(&str, &str)], f: F) -> T {
+    use std::sync::Mutex;
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+    const NAMES: &[&str] = &["REDIS_PROVIDER", "REDIS_URL", "SIDEKIQ_NAMESPACE",
+                             "SIDEKIQ_CONCURRENCY", "RAILS_MAX_THREADS", "SIDEKIQ_QUEUES",
+                             "SIDEKIQ_TIMEOUT", "SIDEKIQ_COUNT", "APP_ENV", "RAILS_ENV",
+                             "RACK_ENV"];
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    for name in NAMES {
+        env::remove_var(name);
+    }
+    for &(name, value) in vars {
+        env::set_var(name, value);
+    }
+    let r = f();
+    for &(name, _) in vars {
+        env::remove_var(name);
+    }
+    r
+}
+
+// a file in the temporary directory, unique to the process and `name`
+#[cfg(all(test, feature = "yaml"))]
+pub fn temp_yaml(name: &str, text: &str) -> String {
+    let path = env::temp_dir().join(format!("sidekiq-{}-{}.yml", ::std::process::id(), name));
+    fs::write(&path, text).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_number(name: &str) -> Result<Option<usize>> {
+    match env_var(name) {
+        Some(value) => {
+            value.trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("{} isn't a number: '{}'", name, value).into())
+        }
+        None => Ok(None),
+    }
+}
+
+// ruby writes the keys as symbols, `:concurrency:`, newer sidekiqs also take plain ones
+#[cfg(feature = "yaml")]
+fn yaml_key<'a>(section: &'a Yaml, key: &str) -> &'a Yaml {
+    let symbol = &section[&*format!(":{}", key)];
+    if symbol.is_badvalue() { &section[key] } else { symbol }
+}
+
+#[cfg(feature = "yaml")]
+fn yaml_number(section: &Yaml, key: &str) -> Result<Option<usize>> {
+    match *yaml_key(section, key) {
+        Yaml::Integer(n) if n >= 0 => Ok(Some(n as usize)),
+        Yaml::BadValue => Ok(None),
+        ref other => Err(format!("`{}` isn't a number: '{:?}'", key, other).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queues(builder: &SidekiqServerBuilder) -> Vec<(&str, usize)> {
+        builder.queues.iter().map(|&(ref name, weight)| (&**name, weight)).collect()
+    }
+
+    #[test]
+    fn overrides_with_the_environment() {
+        let vars = [("REDIS_PROVIDER", "MY_REDIS"),
+                    ("MY_REDIS", "redis://provided/"),
+                    ("REDIS_URL", "redis://url/"),
+                    ("SIDEKIQ_NAMESPACE", "app"),
+                    ("RAILS_MAX_THREADS", "5"),
+                    ("SIDEKIQ_TIMEOUT", "25")];
+        let builder = with_env_vars(&vars, SidekiqServerBuilder::from_env).unwrap();
+        assert_eq!(builder.redis, "redis://provided/");
+        assert_eq!(builder.namespace, "app");
+        assert_eq!(builder.concurrency, 5);
+        assert_eq!(builder.timeout, 25);
+        let vars = [("SIDEKIQ_CONCURRENCY", "7"), ("RAILS_MAX_THREADS", "5")];
+        let builder = with_env_vars(&vars, SidekiqServerBuilder::from_env).unwrap();
+        assert_eq!(builder.concurrency, 7);
+        let vars = [("SIDEKIQ_TIMEOUT", "soon")];
+        assert!(with_env_vars(&vars, SidekiqServerBuilder::from_env).is_err());
+    }
+
+    #[test]
+    fn takes_the_queues_of_the_environment() {
+        let vars = [("SIDEKIQ_QUEUES", "critical:3, default,,low:0")];
+        let builder = with_env_vars(&vars, || {
+                SidekiqServerBuilder::new().queue("other", 2).with_env()
+            })
+            .unwrap();
+        // replacing the ones set before
+        assert_eq!(queues(&builder), [("critical", 3), ("default", 1), ("low", 0)]);
+        // the weight is after the last colon
+        let vars = [("SIDEKIQ_QUEUES", "app:mailers:2")];
+        let builder = with_env_vars(&vars, SidekiqServerBuilder::from_env).unwrap();
+        assert_eq!(queues(&builder), [("app:mailers", 2)]);
+        let vars = [("SIDEKIQ_QUEUES", "critical:high")];
+        assert!(with_env_vars(&vars, SidekiqServerBuilder::from_env).is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    const SIDEKIQ_YML: &str = "
+:concurrency: 5
+timeout: 8
+:queues:
+  - critical
+  - default
+production:
+  :concurrency: 20
+  :queues:
+    - [critical, 3]
+    - default
+";
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn reads_a_sidekiq_yml() {
+        let path = temp_yaml("reads", SIDEKIQ_YML);
+        let builder = SidekiqServerBuilder::from_yaml_env(&path, "development").unwrap();
+        // symbol and plain keys
+        assert_eq!((builder.concurrency, builder.timeout), (5, 8));
+        // in their order without weights
+        assert_eq!(queues(&builder), [("critical", 1), ("default", 1)]);
+        assert!(builder.strict);
+
+        let builder = SidekiqServerBuilder::from_yaml_env(&path, "production").unwrap();
+        assert_eq!((builder.concurrency, builder.timeout), (20, 8));
+        // replaced by the section's, by weight once one has one
+        assert_eq!(queues(&builder), [("critical", 3), ("default", 1)]);
+        assert!(!builder.strict);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn takes_the_section_of_the_environment() {
+        let path = temp_yaml("section", SIDEKIQ_YML);
+        let builder = with_env_vars(&[("RAILS_ENV", "production")],
+                                    || SidekiqServerBuilder::from_yaml(&path))
+            .unwrap();
+        assert_eq!(builder.concurrency, 20);
+        let builder = with_env_vars(&[], || SidekiqServerBuilder::from_yaml(&path)).unwrap();
+        assert_eq!(builder.concurrency, 5);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn rejects_invalid_yaml_settings() {
+        for (name, text) in [("list", ":queues: default"),
+                             ("queue", ":queues:\n  - [default, -1]"),
+                             ("number", ":concurrency: many")] {
+            let path = temp_yaml(name, text);
+            assert!(SidekiqServerBuilder::from_yaml_env(&path, "development").is_err(),
+                    "{}",
+                    text);
+        }
+    }
+}
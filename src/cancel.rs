@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use redis::Pipeline;
+
+use errors::*;
+use utils::with_namespace;
+use RedisConnection;
+
+// how long `SidekiqClient::cancel` keeps `cancel:<jid>`, so a job cancelled before it starts is
+// still cancelled once it does
+pub const CANCEL_TTL: usize = 24 * 60 * 60;
+
+// polled by long running handlers, which decide themselves how to stop
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// the tokens of the running jobs by jid
+#[derive(Clone, Default)]
+pub struct Cancellations {
+    inner: Arc<Mutex<BTreeMap<String, CancellationToken>>>,
+}
+
+impl Cancellations {
+    pub fn new() -> Cancellations {
+        Cancellations::default()
+    }
+
+    pub fn register(&self, jid: &str) -> CancellationToken {
+        self.inner.lock().unwrap().entry(jid.into()).or_default().clone()
+    }
+
+    pub fn remove(&self, jid: &str) {
+        self.inner.lock().unwrap().remove(jid);
+    }
+
+    pub fn get(&self, jid: &str) -> Option<CancellationToken> {
+        self.inner.lock().unwrap().get(jid).cloned()
+    }
+
+    // returns false if no such job is running
+    pub fn cancel(&self, jid: &str) -> bool {
+        match self.get(jid) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // cancel the running jobs with a `cancel:<jid>` key, returns how many were cancelled
+    pub fn poll(&self, conn: &mut RedisConnection, namespace: &str) -> Result<usize> {
+        let running: Vec<_> = self.inner
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, token)| !token.is_cancelled())
+            .map(|(jid, token)| (jid.clone(), token.clone()))
+            .collect();
+        if running.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = Pipeline::new();
+        for (jid, _) in &running {
+            pipe.exists(cancel_key(namespace, jid));
+        }
+        let found: Vec<bool> = pipe.query(conn)?;
+        let mut count = 0;
+        for ((jid, token), found) in running.into_iter().zip(found) {
+            if found {
+                info!("job '{}' is cancelled", jid);
+                token.cancel();
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+pub fn cancel_key(namespace: &str, jid: &str) -> String {
+    with_namespace(namespace, &("cancel:".to_string() + jid))
+}
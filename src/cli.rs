@@ -0,0 +1,245 @@
+// a server configured from the command line, so a service only says which handlers it has
+//
+//     fn main() {
+//         ::std::process::exit(sidekiq::cli::run(|server| {
+//             server.attach_handler("HardJob", hard_job);
+//             server.attach_middleware(retry_middleware);
+//         }));
+//     }
+use std::env;
+
+use builder::SidekiqServerBuilder;
+use logging::{LogFormatter, init_logger_with};
+use server::{SidekiqServer, USAGE_EXIT_CODE};
+use swarm::swarm;
+
+const USAGE: &str = "usage: sidekiq-rs [options]
+
+    -C, --config PATH          a sidekiq.yml, the other options override it
+    -e, --environment ENV      its section to use, APP_ENV, RAILS_ENV or RACK_ENV by default
+    -r, --redis URL            redis connection string, redis://127.0.0.1/ by default
+        --cluster-node URL     a node of a redis cluster to use instead, repeated for several
+    -n, --namespace NAME       prefix of every redis key
+        --tls-ca PATH          CA certificate of a rediss:// server, PEM
+        --tls-cert CERT,KEY    client certificate and key of a rediss:// server, PEM
+    -c, --concurrency N        how many jobs run at once, 10 by default
+    -q, --queue NAME[,WEIGHT]  a queue to fetch from, repeated for several, `default` if none
+    -t, --timeout SECS         seconds the running jobs get to finish on shutdown, 10 by default
+        --log-format FORMAT    plain, json or logfmt
+        --swarm N              run N servers as child processes, 0 for one per core
+    -h, --help                 show this
+
+REDIS_URL, SIDEKIQ_NAMESPACE, SIDEKIQ_CONCURRENCY, SIDEKIQ_QUEUES and SIDEKIQ_TIMEOUT override
+the config, the options override them, SIDEKIQ_COUNT is the default of --swarm";
+
+struct Options {
+    builder: SidekiqServerBuilder,
+    log_format: LogFormatter,
+    // children of the swarm
+    swarm: Option<usize>,
+    help: bool,
+}
+
+// returns the exit code of the server, `USAGE_EXIT_CODE` for invalid options
+pub fn run<F>(registry: F) -> i32
+    where F: FnOnce(&mut SidekiqServer)
+{
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return USAGE_EXIT_CODE;
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return 0;
+    }
+    if let Err(e) = init_logger_with(options.log_format) {
+        eprintln!("initializing the logger failed: '{}'", e);
+        return 1;
+    }
+    // the supervisor returns once its children stopped, they go on
+    if let Some(code) = options.swarm.and_then(swarm) {
+        return code;
+    }
+    let mut builder = options.builder;
+    if builder.queues.is_empty() {
+        builder = builder.queue("default", 1);
+    }
+    let mut server = match builder.build() {
+        Ok(server) => server,
+        Err(e) => {
+            error!("starting the server failed: '{}'", e);
+            return 1;
+        }
+    };
+    registry(&mut server);
+    server.start()
+}
+
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut help = false;
+    let mut config = None;
+    let mut environment = None;
+    let mut flags = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        // both `--flag value` and `--flag=value`
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+            _ => (&arg[..], None),
+        };
+        if flag == "-h" || flag == "--help" {
+            help = true;
+            continue;
+        }
+        let value = match inline.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => return Err(format!("missing value of '{}'", flag)),
+        };
+        match flag {
+            "-C" | "--config" => config = Some(value),
+            "-e" | "--environment" => environment = Some(value),
+            _ => flags.push((flag, value)),
+        }
+    }
+    let builder = match (config, environment) {
+        (Some(path), Some(environment)) => {
+            SidekiqServerBuilder::from_yaml_env(&path, &environment)
+                .map_err(|e| format!("{}", e))?
+        }
+        (Some(path), None) => SidekiqServerBuilder::from_yaml(&path).map_err(|e| format!("{}", e))?,
+        (None, _) => SidekiqServerBuilder::new(),
+    };
+    let builder = builder.with_env().map_err(|e| format!("{}", e))?;
+    let mut options = Options {
+        builder,
+        log_format: LogFormatter::Plain,
+        swarm: match env::var("SIDEKIQ_COUNT") {
+            Ok(ref count) if !count.is_empty() => Some(number("SIDEKIQ_COUNT", count)?),
+            _ => None,
+        },
+        help,
+    };
+    // queues given as flags replace the ones of the config, strictly ordered unless one of
+    // them has a weight, like ruby's
+    let queues: Vec<&String> = flags.iter()
+        .filter(|&&(flag, _)| flag == "-q" || flag == "--queue")
+        .map(|(_, value)| value)
+        .collect();
+    if !queues.is_empty() {
+        options.builder.queues.clear();
+        options.builder.strict = !queues.iter().any(|value| value.contains(','));
+    }
+    for (flag, value) in flags {
+        let builder = options.builder;
+        options.builder = match flag {
+            "-r" | "--redis" => builder.redis(&value),
+            "--cluster-node" => builder.cluster_node(&value),
+            "-n" | "--namespace" => builder.namespace(&value),
+            "--tls-ca" => {
+                let tls = builder.tls.clone().unwrap_or_default().ca_file(&value);
+                builder.tls(tls)
+            }
+            "--tls-cert" => {
+                let mut sp = value.splitn(2, ',');
+                let (cert, key) = match (sp.next(), sp.next()) {
+                    (Some(cert), Some(key)) => (cert, key),
+                    _ => return Err(format!("'{}' of '{}' is not CERT,KEY", value, flag)),
+                };
+                let tls = builder.tls.clone().unwrap_or_default().client_cert_files(cert, key);
+                builder.tls(tls)
+            }
+            "-c" | "--concurrency" => builder.concurrency(number(flag, &value)?),
+            "-q" | "--queue" => {
+                let mut sp = value.splitn(2, ',');
+                let name = sp.next().unwrap_or("");
+                let weight = match sp.next() {
+                    Some(weight) => number(flag, weight)?,
+                    None => 1,
+                };
+                builder.queue(name, weight)
+            }
+            "-t" | "--timeout" => builder.timeout(number(flag, &value)?),
+            "--log-format" => {
+                options.log_format = value.parse().map_err(|e| format!("{}", e))?;
+                builder
+            }
+            "--swarm" => {
+                options.swarm = Some(number(flag, &value)?);
+                builder
+            }
+            _ => return Err(format!("unknown option '{}'", flag)),
+        };
+    }
+    Ok(options)
+}
+
+fn number(flag: &str, value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("'{}' of '{}' is not a number", value, flag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::{temp_yaml, with_env_vars};
+
+    fn parse_args(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse(&args)
+    }
+
+    fn queues(options: &Options) -> Vec<(&str, usize)> {
+        options.builder.queues.iter().map(|&(ref name, weight)| (&**name, weight)).collect()
+    }
+
+    #[test]
+    fn overrides_the_config_with_the_environment_and_the_flags() {
+        let path = temp_yaml("cli", ":concurrency: 5\n:timeout: 8\n:queues:\n  - low\n");
+        let vars = [("SIDEKIQ_CONCURRENCY", "7"), ("SIDEKIQ_NAMESPACE", "env")];
+        let options = with_env_vars(&vars, || parse_args(&["-C", &path, "-c", "9"])).unwrap();
+        assert_eq!(options.builder.concurrency, 9);
+        assert_eq!(options.builder.namespace, "env");
+        assert_eq!(options.builder.timeout, 8);
+        assert_eq!(queues(&options), [("low", 1)]);
+
+        let inline = format!("--config={}", path);
+        let options = with_env_vars(&vars, || parse_args(&[&inline])).unwrap();
+        assert_eq!(options.builder.concurrency, 7);
+    }
+
+    #[test]
+    fn takes_the_section_given() {
+        let path = temp_yaml("cli-env", ":concurrency: 5\nstaging:\n  :concurrency: 6\n");
+        let options = with_env_vars(&[("RAILS_ENV", "production")],
+                                    || parse_args(&["-C", &path, "-e", "staging"]))
+            .unwrap();
+        assert_eq!(options.builder.concurrency, 6);
+    }
+
+    #[test]
+    fn replaces_the_queues_with_the_flags() {
+        let vars = [("SIDEKIQ_QUEUES", "low")];
+        let options = with_env_vars(&vars, || parse_args(&["-q", "critical", "--queue=default"]))
+            .unwrap();
+        assert_eq!(queues(&options), [("critical", 1), ("default", 1)]);
+        assert!(options.builder.strict);
+        let options = with_env_vars(&[], || parse_args(&["-q", "critical,3", "-q", "default"]))
+            .unwrap();
+        assert_eq!(queues(&options), [("critical", 3), ("default", 1)]);
+        assert!(!options.builder.strict);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        with_env_vars(&[], || {
+            assert!(parse_args(&["--workers", "3"]).is_err());
+            assert!(parse_args(&["-c"]).is_err());
+            assert!(parse_args(&["-c", "many"]).is_err());
+            assert!(parse_args(&["-q", "default,high"]).is_err());
+            assert!(parse_args(&["-h"]).unwrap().help);
+        });
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use r2d2::Pool;
+
+use redis::Commands;
+
+use serde::Deserialize;
+use serde_json::{from_value, to_string, Value as JValue};
+
+use chrono::{DateTime, Duration as CDuration, UTC};
+
+use errors::*;
+use job::Job;
+use codec::encode_job;
+use utils::{cluster_connection_manager, connection_manager, detached_pool, TlsConfig};
+use memory::MemoryBackend;
+use testing::InlineBackend;
+use backend::{self, Backend, Push, RedisBackend};
+#[cfg(feature = "compression")]
+use compress::compress_args;
+use cancel::{cancel_key, CANCEL_TTL};
+use progress::{get_progress, Progress};
+use middleware::{ClientMiddleWare, ClientMiddleWareResult};
+use RedisPool;
+
+const BULK_CHUNK_SIZE: usize = 1000;
+
+pub struct SidekiqClient {
+    redispool: RedisPool,
+    // used instead of redis when set
+    backend: Option<Arc<dyn Backend>>,
+    pub namespace: String,
+    middlewares: Vec<Box<dyn ClientMiddleWare>>,
+    msgpack_queues: BTreeSet<String>,
+    payload_warn_size: Option<usize>,
+    payload_max_size: Option<usize>,
+    #[cfg(feature = "compression")]
+    compress_threshold: Option<usize>,
+}
+
+impl SidekiqClient {
+    pub fn new(redispool: RedisPool, namespace: &str) -> SidekiqClient {
+        SidekiqClient {
+            redispool,
+            backend: None,
+            namespace: namespace.into(),
+            middlewares: vec![],
+            msgpack_queues: BTreeSet::new(),
+            payload_warn_size: None,
+            payload_max_size: None,
+            #[cfg(feature = "compression")]
+            compress_threshold: None,
+        }
+    }
+
+    pub fn connect(redis: &str, namespace: &str) -> Result<SidekiqClient> {
+        SidekiqClient::connect_with_tls(redis, namespace, None)
+    }
+
+    // with the certificates of a `rediss://` url
+    pub fn connect_with_tls(redis: &str,
+                            namespace: &str,
+                            tls: Option<&TlsConfig>)
+                            -> Result<SidekiqClient> {
+        let pool = Pool::builder().max_size(2).build(connection_manager(redis, tls)?)?;
+        Ok(SidekiqClient::new(pool, namespace))
+    }
+
+    // to a redis cluster, found from any of its `nodes`
+    pub fn connect_cluster(nodes: &[String],
+                           namespace: &str,
+                           tls: Option<&TlsConfig>)
+                           -> Result<SidekiqClient> {
+        let pool = Pool::builder().max_size(2).build(cluster_connection_manager(nodes, tls)?)?;
+        Ok(SidekiqClient::new(pool, namespace))
+    }
+
+    // push into `backend` instead of redis, see `MemoryBackend`
+    pub fn in_memory(backend: MemoryBackend) -> Result<SidekiqClient> {
+        Ok(SidekiqClient::with_backend(detached_pool(2)?, backend))
+    }
+
+    // runs the handler of each job as it's pushed, for tests
+    pub fn inline(backend: InlineBackend) -> Result<SidekiqClient> {
+        Ok(SidekiqClient::with_backend(detached_pool(2)?, backend))
+    }
+
+    // push through `backend`, the middlewares are given `redispool`
+    pub fn with_backend<B: Backend + 'static>(redispool: RedisPool, backend: B) -> SidekiqClient {
+        SidekiqClient::with_shared_backend(redispool, Arc::new(backend))
+    }
+
+    pub fn with_shared_backend(redispool: RedisPool, backend: Arc<dyn Backend>) -> SidekiqClient {
+        let mut client = SidekiqClient::new(redispool, "");
+        client.backend = Some(backend);
+        client
+    }
+
+    pub fn redis_pool(&self) -> &RedisPool {
+        &self.redispool
+    }
+
+    pub fn attach_middleware<T: ClientMiddleWare + 'static>(&mut self, middleware: T) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    // push the jobs of this queue as MessagePack, which ruby sidekiq can't read, so only for
+    // queues both pushed to and worked by rust processes. scheduled jobs stay JSON
+    #[cfg(feature = "msgpack")]
+    pub fn use_msgpack(&mut self, queue: &str) {
+        self.msgpack_queues.insert(queue.into());
+    }
+
+    // compress the arguments of the jobs whose arguments are larger than this many bytes of
+    // JSON, rust servers built with the `compression` feature decompress them
+    #[cfg(feature = "compression")]
+    pub fn compress_over(&mut self, bytes: usize) {
+        self.compress_threshold = Some(bytes);
+    }
+
+    // log a warning for the jobs whose payload is larger than this many bytes, which slow
+    // redis down
+    pub fn warn_payload_over(&mut self, bytes: usize) {
+        self.payload_warn_size = Some(bytes);
+    }
+
+    // fail with `PayloadTooLarge` for the jobs whose payload is larger than this many bytes,
+    // `push_bulk` then pushes none of the jobs of the same chunk
+    pub fn reject_payload_over(&mut self, bytes: usize) {
+        self.payload_max_size = Some(bytes);
+    }
+
+    pub fn perform_async(&mut self,
+                         class: &str,
+                         queue: &str,
+                         args: Vec<JValue>)
+                         -> Result<Option<String>> {
+        self.push(Job::new(class, args, queue))
+    }
+
+    pub fn perform_in(&mut self, interval: Duration, job: Job) -> Result<Option<String>> {
+        let interval = CDuration::from_std(interval).map_err(|_| "interval out of range")?;
+        self.perform_at(UTC::now() + interval, job)
+    }
+
+    pub fn perform_at(&mut self, at: DateTime<UTC>, mut job: Job) -> Result<Option<String>> {
+        job.at = Some(at);
+        self.push(job)
+    }
+
+    // push the job onto its queue, or into `schedule` if `at` is set. returns the jid, or `None`
+    // if a middleware stopped the job from being pushed
+    pub fn push(&mut self, job: Job) -> Result<Option<String>> {
+        Ok(self.push_bulk(Some(job))?.pop())
+    }
+
+    // push jobs in pipelines of `BULK_CHUNK_SIZE`, returns the jids of pushed jobs in order
+    pub fn push_bulk<I: IntoIterator<Item = Job>>(&mut self, jobs: I) -> Result<Vec<String>> {
+        let backend = self.backend();
+        let mut jids = vec![];
+        let mut chunk = vec![];
+        for mut job in jobs {
+            job.namespace = self.namespace.clone();
+            if !self.call_middleware(&mut job)? {
+                debug!("job '{}' is not pushed by middleware", job.jid);
+                continue;
+            }
+            #[cfg(feature = "compression")]
+            {
+                if let Some(threshold) = self.compress_threshold {
+                    compress_args(&mut job, threshold)?;
+                }
+            }
+            if let Some(at) = job.at.take() {
+                let score = at.timestamp() as f64 +
+                            at.timestamp_subsec_micros() as f64 / 1000000f64;
+                let payload = to_string(&job)?;
+                self.check_size(&job, payload.len())?;
+                chunk.push(Push::Schedule(score, payload));
+            } else {
+                job.enqueued_at = UTC::now();
+                let msgpack = self.msgpack_queues.contains(&job.queue);
+                let payload = encode_job(&job, msgpack)?;
+                self.check_size(&job, payload.len())?;
+                chunk.push(Push::Enqueue(job.queue.clone(), payload));
+            }
+            jids.push(job.jid);
+
+            if chunk.len() == BULK_CHUNK_SIZE {
+                backend.push(&chunk)?;
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() {
+            backend.push(&chunk)?;
+        }
+        Ok(jids)
+    }
+
+    // ask the server running the job to cancel its token, picked up on its next heartbeat, a
+    // job not started yet is cancelled once it starts
+    pub fn cancel(&self, jid: &str) -> Result<()> {
+        let _: () = self.redispool
+            .get()?
+            .set_ex(cancel_key(&self.namespace, jid), 1, CANCEL_TTL as u64)?;
+        Ok(())
+    }
+
+    // what the job last reported, none once it expired or if it never reported any
+    pub fn progress(&self, jid: &str) -> Result<Option<Progress>> {
+        get_progress(&mut *self.redispool.get()?, &self.namespace, jid)
+    }
+
+    // the value the job returned with `Returned`, none until it did or once it expired
+    pub fn result<T: Deserialize>(&self, jid: &str) -> Result<Option<T>> {
+        match self.backend().result(jid)? {
+            Some(value) => Ok(Some(from_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the jobs are pushed through, and the client middlewares lock and count batches with
+    pub fn backend(&self) -> Arc<dyn Backend> {
+        match self.backend {
+            Some(ref backend) => backend.clone(),
+            None => {
+                Arc::new(RedisBackend::new(self.redispool.clone(),
+                                           self.redispool.clone(),
+                                           &self.namespace))
+            }
+        }
+    }
+
+    fn check_size(&self, job: &Job, size: usize) -> Result<()> {
+        if let Some(limit) = self.payload_max_size {
+            if size > limit {
+                error!("job '{}' of '{}' is {} bytes, not pushing it", job.jid, job.class, size);
+                return Err(ErrorKind::PayloadTooLarge(size, limit).into());
+            }
+        }
+        if let Some(limit) = self.payload_warn_size {
+            if size > limit {
+                warn!("job '{}' of '{}' is {} bytes, over {} bytes",
+                      job.jid,
+                      job.class,
+                      size,
+                      limit);
+            }
+        }
+        Ok(())
+    }
+
+    fn call_middleware(&mut self, job: &mut Job) -> Result<bool> {
+        fn imp(job: &mut Job,
+               redis: RedisPool,
+               chain: &mut [Box<dyn ClientMiddleWare>])
+               -> ClientMiddleWareResult {
+            chain.split_first_mut()
+                .map(|(head, tail)| head.handle(job, redis, &mut |job, redis| imp(job, redis, tail)))
+                .unwrap_or(Ok(true))
+        }
+
+        let backend = self.backend();
+        let redispool = self.redispool.clone();
+        let middlewares = &mut self.middlewares;
+        backend::using(backend, || imp(job, redispool, middlewares))
+    }
+}
@@ -0,0 +1,212 @@
+// the connections of a `RedisPool` to a redis cluster, and the naming of the keys which have
+// to be on one slot
+//
+// the keys keep the names ruby sidekiq gives them, each landing on its own slot. the ones used
+// in one command, script or transaction with another are named after a hash tag of it on a
+// cluster, `{<key>}<suffix>` hashes like `<key>`:
+//
+// - the working queues of `ReliableFetcher`, `{<queue>}|sq|<identity>`
+// - the failures of a batch, `{b-<bid>}-failed`
+// - the running jobs of a process, `{<identity>}:workers`
+//
+// the commands of keys which can't share a slot, like a queue and the set of queues, don't run
+// in one transaction or script there. the other pipelines are split by node, BRPOP waits on
+// one queue only and SCAN goes over every primary
+use std::collections::HashMap;
+
+use redis::cluster::{cluster_pipe, ClusterConnection};
+use redis::cluster_routing::{MultipleNodeRoutingInfo, RoutingInfo, SingleNodeRoutingInfo};
+use redis::{cmd, from_redis_value, Cmd, Commands, Connection, ConnectionLike, ErrorKind, Parser,
+            RedisResult, Value};
+
+const MULTI: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+
+const SCAN_COUNT: usize = 1000;
+
+pub enum RedisConnection {
+    Single(Connection),
+    Cluster(Box<ClusterConnection>),
+}
+
+impl RedisConnection {
+    pub fn is_cluster(&self) -> bool {
+        match *self {
+            RedisConnection::Single(_) => false,
+            RedisConnection::Cluster(_) => true,
+        }
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match *self {
+            RedisConnection::Single(ref mut conn) => conn.req_packed_command(cmd),
+            RedisConnection::Cluster(ref mut conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    // a transaction goes to the node of its first key, the other pipelines to the node of the
+    // key of each command
+    fn req_packed_commands(&mut self,
+                           cmd: &[u8],
+                           offset: usize,
+                           count: usize)
+                           -> RedisResult<Vec<Value>> {
+        match *self {
+            RedisConnection::Single(ref mut conn) => conn.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(ref mut conn) if cmd.starts_with(MULTI) => {
+                conn.req_packed_commands(cmd, offset, count)
+            }
+            RedisConnection::Cluster(ref mut conn) => {
+                let mut pipe = cluster_pipe();
+                for command in unpack_commands(cmd, offset + count)? {
+                    pipe.add_command(command);
+                }
+                let values: Vec<Value> = pipe.query(conn)?;
+                Ok(values.into_iter().skip(offset).take(count).collect())
+            }
+        }
+    }
+
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        match *self {
+            RedisConnection::Single(ref mut conn) => conn.req_command(cmd),
+            RedisConnection::Cluster(ref mut conn) => conn.req_command(cmd),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match *self {
+            RedisConnection::Single(ref conn) => conn.get_db(),
+            RedisConnection::Cluster(ref conn) => conn.get_db(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match *self {
+            RedisConnection::Single(ref mut conn) => conn.check_connection(),
+            RedisConnection::Cluster(ref mut conn) => conn.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match *self {
+            RedisConnection::Single(ref conn) => conn.is_open(),
+            RedisConnection::Cluster(ref conn) => conn.is_open(),
+        }
+    }
+}
+
+// the `count` commands of a packed pipeline
+fn unpack_commands(mut packed: &[u8], count: usize) -> RedisResult<Vec<Cmd>> {
+    let mut parser = Parser::new();
+    let mut commands = Vec::with_capacity(count);
+    for _ in 0..count {
+        let args = match parser.parse_value(&mut packed)? {
+            Value::Array(args) => args,
+            _ => return Err((ErrorKind::Client, "not a packed command").into()),
+        };
+        let mut command = Cmd::new();
+        for arg in args {
+            match arg {
+                Value::BulkString(arg) => command.arg(arg),
+                _ => return Err((ErrorKind::Client, "not a packed command").into()),
+            };
+        }
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+// `key` itself when it already has one
+pub fn hash_tagged(key: &str) -> String {
+    if hash_tag(key).is_some() {
+        key.into()
+    } else {
+        format!("{{{}}}", key)
+    }
+}
+
+// what a cluster hashes the key by, when it isn't the whole key
+fn hash_tag(key: &str) -> Option<&str> {
+    let open = key.find('{')?;
+    let close = key[open + 1..].find('}')?;
+    if close == 0 {
+        None
+    } else {
+        Some(&key[open + 1..open + 1 + close])
+    }
+}
+
+// the key named `key` + `suffix`, on the slot of `key` on a cluster
+pub fn slot_mate(conn: &RedisConnection, key: &str, suffix: &str) -> String {
+    if conn.is_cluster() {
+        hash_tagged(key) + suffix
+    } else {
+        key.to_string() + suffix
+    }
+}
+
+// the keys matching `pattern`, of each primary of a cluster
+pub fn scan_keys(conn: &mut RedisConnection, pattern: &str) -> RedisResult<Vec<String>> {
+    let cluster = match *conn {
+        RedisConnection::Single(ref mut conn) => return conn.scan_match(pattern)?.collect(),
+        RedisConnection::Cluster(ref mut cluster) => cluster,
+    };
+    let scan = |cursor: u64| {
+        let mut scan = cmd("SCAN");
+        scan.arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(SCAN_COUNT);
+        scan
+    };
+    let primaries = RoutingInfo::MultiNode((MultipleNodeRoutingInfo::AllMasters, None));
+    let replies: HashMap<String, (u64, Vec<String>)> =
+        from_redis_value(cluster.route_command(&scan(0), primaries)?)?;
+    let mut keys = vec![];
+    for (address, (mut cursor, found)) in replies {
+        keys.extend(found);
+        let (host, port) = match address.rfind(':') {
+            Some(i) => (address[..i].to_string(), address[i + 1..].parse().unwrap_or(6379)),
+            None => (address.clone(), 6379),
+        };
+        while cursor != 0 {
+            let node = SingleNodeRoutingInfo::ByAddress {
+                host: host.clone(),
+                port,
+            };
+            let (next, found): (u64, Vec<String>) =
+                from_redis_value(cluster.route_command(&scan(cursor),
+                                                       RoutingInfo::SingleNode(node))?)?;
+            keys.extend(found);
+            cursor = next;
+        }
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Pipeline;
+
+    #[test]
+    fn unpacks_the_commands_of_a_pipeline() {
+        let mut pipe = Pipeline::new();
+        pipe.sadd("queues", "default").lpush("queue:default", "{\"jid\":\"1\"}");
+        let commands = unpack_commands(&pipe.get_packed_pipeline(), 2).unwrap();
+        let repacked: Vec<Vec<u8>> =
+            commands.iter().map(|command| command.get_packed_command()).collect();
+        let expected: Vec<Vec<u8>> =
+            pipe.cmd_iter().map(|command| command.get_packed_command()).collect();
+        assert_eq!(repacked, expected);
+    }
+
+    #[test]
+    fn tags_the_keys_after_another() {
+        assert_eq!(hash_tagged("myapp:queue:default"), "{myapp:queue:default}");
+        assert_eq!(hash_tag(&(hash_tagged("myapp:b-123") + "-failed")),
+                   Some("myapp:b-123"));
+        // already on the slot of its tag
+        assert_eq!(hash_tagged("{myapp}:queue:default"), "{myapp}:queue:default");
+        assert_eq!(hash_tag("{}:queue:default"), None);
+    }
+}
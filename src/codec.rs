@@ -0,0 +1,198 @@
+// the payloads of the queues, JSON like ruby sidekiq, or MessagePack for the queues only
+// rust processes use, see `SidekiqClient::use_msgpack`. the format is told by the first byte
+// so a queue can hold both, the retried and scheduled jobs coming back as JSON
+
+use serde_json::{from_slice, to_vec, Value as JValue};
+#[cfg(feature = "msgpack")]
+use serde_json::{from_value, to_value, Map as JMap, Number};
+#[cfg(feature = "msgpack")]
+use rmpv::{self, Value as MValue};
+
+use errors::*;
+use job::Job;
+#[cfg(feature = "compression")]
+use compress::decompress_args;
+
+#[cfg(feature = "msgpack")]
+fn is_msgpack(payload: &[u8]) -> bool {
+    payload.first().is_some_and(|&b| b != b'{')
+}
+
+// with its arguments decompressed
+pub fn decode_job(payload: &[u8]) -> Result<Job> {
+    let job = parse_job(payload)?;
+    #[cfg(feature = "compression")]
+    let job = decompress_args(job)?;
+    Ok(job)
+}
+
+fn parse_job(payload: &[u8]) -> Result<Job> {
+    #[cfg(feature = "msgpack")]
+    {
+        if is_msgpack(payload) {
+            return Ok(from_value(decode_msgpack(payload)?)?);
+        }
+    }
+    Ok(from_slice(payload)?)
+}
+
+pub fn encode_job(job: &Job, msgpack: bool) -> Result<Vec<u8>> {
+    #[cfg(feature = "msgpack")]
+    {
+        if msgpack {
+            return encode_msgpack(&to_value(job)?);
+        }
+    }
+    #[cfg(not(feature = "msgpack"))]
+    let _ = msgpack;
+    Ok(to_vec(job)?)
+}
+
+pub fn decode_value(payload: &[u8]) -> Result<JValue> {
+    #[cfg(feature = "msgpack")]
+    {
+        if is_msgpack(payload) {
+            return decode_msgpack(payload);
+        }
+    }
+    Ok(from_slice(payload)?)
+}
+
+// in the format the payload was in
+pub fn reencode_value(value: &JValue, payload: &[u8]) -> Result<Vec<u8>> {
+    #[cfg(feature = "msgpack")]
+    {
+        if is_msgpack(payload) {
+            return encode_msgpack(value);
+        }
+    }
+    #[cfg(not(feature = "msgpack"))]
+    let _ = payload;
+    Ok(to_vec(value)?)
+}
+
+#[cfg(feature = "msgpack")]
+fn decode_msgpack(payload: &[u8]) -> Result<JValue> {
+    let value = rmpv::decode::read_value(&mut &payload[..])
+        .map_err(|e| Error::from(format!("invalid msgpack payload: '{}'", e)))?;
+    from_msgpack(value)
+}
+
+#[cfg(feature = "msgpack")]
+fn encode_msgpack(value: &JValue) -> Result<Vec<u8>> {
+    let mut payload = vec![];
+    rmpv::encode::write_value(&mut payload, &to_msgpack(value))
+        .map_err(|e| Error::from(format!("encoding msgpack payload failed: '{}'", e)))?;
+    Ok(payload)
+}
+
+#[cfg(feature = "msgpack")]
+fn to_msgpack(value: &JValue) -> MValue {
+    match *value {
+        JValue::Null => MValue::Nil,
+        JValue::Bool(b) => MValue::Boolean(b),
+        JValue::Number(ref n) => {
+            if let Some(n) = n.as_u64() {
+                MValue::from(n)
+            } else if let Some(n) = n.as_i64() {
+                MValue::from(n)
+            } else {
+                MValue::F64(n.as_f64().unwrap_or(0.0))
+            }
+        }
+        JValue::String(ref s) => MValue::from(&**s),
+        JValue::Array(ref values) => MValue::Array(values.iter().map(to_msgpack).collect()),
+        JValue::Object(ref map) => {
+            MValue::Map(map.iter().map(|(k, v)| (MValue::from(&**k), to_msgpack(v))).collect())
+        }
+    }
+}
+
+#[cfg(feature = "msgpack")]
+fn from_msgpack(value: MValue) -> Result<JValue> {
+    Ok(match value {
+        MValue::Nil => JValue::Null,
+        MValue::Boolean(b) => JValue::Bool(b),
+        MValue::Integer(n) => {
+            match (n.as_u64(), n.as_i64()) {
+                (Some(n), _) => JValue::from(n),
+                (_, Some(n)) => JValue::from(n),
+                _ => JValue::Null,
+            }
+        }
+        MValue::F32(n) => Number::from_f64(n as f64).map(JValue::Number).unwrap_or(JValue::Null),
+        MValue::F64(n) => Number::from_f64(n).map(JValue::Number).unwrap_or(JValue::Null),
+        MValue::String(s) => {
+            JValue::String(s.into_str().ok_or("invalid utf-8 string in msgpack payload")?)
+        }
+        MValue::Binary(_) | MValue::Ext(..) => {
+            return Err("binary values can't be job arguments".into())
+        }
+        MValue::Array(values) => {
+            JValue::Array(values.into_iter().map(from_msgpack).collect::<Result<_>>()?)
+        }
+        MValue::Map(entries) => {
+            let mut map = JMap::new();
+            for (k, v) in entries {
+                let k = match k {
+                    MValue::String(k) => {
+                        k.into_str().ok_or("invalid utf-8 key in msgpack payload")?
+                    }
+                    _ => return Err("non string key in msgpack payload".into()),
+                };
+                map.insert(k, from_msgpack(v)?);
+            }
+            JValue::Object(map)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> Job {
+        Job::new("HardJob",
+                 vec![json!("bob"), json!(-1), json!(1.5), json!(null), json!({"a": [true]})],
+                 "default")
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let payload = encode_job(&job(), false).unwrap();
+        assert_eq!(payload[0], b'{');
+        assert_eq!(decode_job(&payload).unwrap().args, job().args);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        let job = job();
+        let payload = encode_job(&job, true).unwrap();
+        assert!(is_msgpack(&payload));
+        let decoded = decode_job(&payload).unwrap();
+        assert_eq!(decoded.jid, job.jid);
+        assert_eq!(decoded.args, job.args);
+        // a float of seconds, like in JSON
+        assert_eq!(decoded.enqueued_at.timestamp(), job.enqueued_at.timestamp());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn reencodes_in_the_same_format() {
+        let value = json!({"class": "HardJob", "interrupted_count": 1});
+        let json = reencode_value(&value, b"{}").unwrap();
+        assert_eq!(decode_value(&json).unwrap(), value);
+        let msgpack = reencode_value(&value, &encode_msgpack(&json!({})).unwrap()).unwrap();
+        assert!(is_msgpack(&msgpack));
+        assert_eq!(decode_value(&msgpack).unwrap(), value);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn rejects_binary_values() {
+        let mut payload = vec![];
+        rmpv::encode::write_value(&mut payload, &MValue::Binary(vec![1, 2])).unwrap();
+        assert!(decode_value(&payload).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+// arguments compressed for the rust servers, the job carrying `"compressed": true` and a
+// single argument, the base64 of the zlib deflated JSON array of the arguments. ruby
+// sidekiq doesn't read it, only push such jobs to queues a rust server works
+
+use std::io::{Read, Write};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use serde_json::{from_slice, to_vec, Value as JValue};
+
+use errors::*;
+use job::Job;
+
+const COMPRESSED: &str = "compressed";
+
+pub fn is_compressed(job: &Job) -> bool {
+    job.extra.get(COMPRESSED).and_then(|c| c.as_bool()).unwrap_or(false)
+}
+
+// the arguments are compressed once their JSON is larger than `threshold` bytes
+pub fn compress_args(job: &mut Job, threshold: usize) -> Result<()> {
+    if is_compressed(job) {
+        return Ok(());
+    }
+    let json = to_vec(&job.args)?;
+    if json.len() <= threshold {
+        return Ok(());
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&json)
+        .map_err(|e| Error::from(format!("compressing arguments failed: '{}'", e)))?;
+    let deflated = encoder.finish()
+        .map_err(|e| Error::from(format!("compressing arguments failed: '{}'", e)))?;
+    job.args = vec![JValue::String(STANDARD.encode(deflated))];
+    job.extra.insert(COMPRESSED.into(), JValue::Bool(true));
+    Ok(())
+}
+
+// the job is then retried uncompressed, which any client can read
+pub fn decompress_args(mut job: Job) -> Result<Job> {
+    if !is_compressed(&job) {
+        return Ok(job);
+    }
+    let encoded = match job.args.first() {
+        Some(JValue::String(encoded)) if job.args.len() == 1 => encoded.clone(),
+        _ => {
+            let message = "compressed arguments not a string".into();
+            return Err(ErrorKind::InvalidArguments(message).into());
+        }
+    };
+    let deflated = STANDARD.decode(encoded)
+        .map_err(|e| ErrorKind::InvalidArguments(format!("compressed arguments: '{}'", e)))?;
+    let mut json = vec![];
+    ZlibDecoder::new(&*deflated)
+        .read_to_end(&mut json)
+        .map_err(|e| ErrorKind::InvalidArguments(format!("compressed arguments: '{}'", e)))?;
+    job.args = from_slice(&json)?;
+    job.extra.remove(COMPRESSED);
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `["hello",1]` deflated by python's `zlib.compress`
+    const FIXTURE: &str = "eJyLVspIzcnJV9IxjAUAFYoDbg==";
+
+    fn compressed(args: Vec<JValue>) -> Job {
+        let mut job = Job::new("HardJob", args, "default");
+        job.extra.insert(COMPRESSED.into(), JValue::Bool(true));
+        job
+    }
+
+    #[test]
+    fn decompresses_the_fixture() {
+        let job = decompress_args(compressed(vec![json!(FIXTURE)])).unwrap();
+        assert_eq!(job.args, vec![json!("hello"), json!(1)]);
+        assert!(!is_compressed(&job));
+    }
+
+    #[test]
+    fn round_trips() {
+        let args = vec![json!("x".repeat(100)), json!({"n": 1})];
+        let mut job = Job::new("HardJob", args.clone(), "default");
+        compress_args(&mut job, 10).unwrap();
+        assert!(is_compressed(&job));
+        assert_eq!(job.args.len(), 1);
+        assert_eq!(decompress_args(job).unwrap().args, args);
+    }
+
+    #[test]
+    fn leaves_small_arguments() {
+        let mut job = Job::new("HardJob", vec![json!(1)], "default");
+        compress_args(&mut job, 10).unwrap();
+        assert!(!is_compressed(&job));
+        assert_eq!(job.args, vec![json!(1)]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let err = decompress_args(compressed(vec![json!("not base64!")])).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::InvalidArguments(_)));
+        assert!(decompress_args(compressed(vec![json!(1), json!(2)])).is_err());
+    }
+}
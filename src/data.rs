@@ -0,0 +1,30 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+// shared resources of the handlers by type, like db pools or http clients, available in
+// `JobContext::data`
+#[derive(Clone, Default)]
+pub struct AppData {
+    inner: Arc<RwLock<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>>,
+}
+
+impl AppData {
+    pub fn new() -> AppData {
+        AppData::default()
+    }
+
+    // replaces the value of the same type if there is one
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) {
+        self.inner.write().unwrap().insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.inner
+            .read()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .and_then(|value| value.downcast::<T>().ok())
+    }
+}
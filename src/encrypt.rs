@@ -0,0 +1,183 @@
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use serde_json::{from_slice, to_vec, Value as JValue};
+
+use RedisPool;
+use errors::*;
+use job::Job;
+use middleware::{MiddleWare, MiddleWareResult, NextFunc, ClientMiddleWare,
+                 ClientMiddleWareResult, ClientNextFunc};
+
+const NONCE_LEN: usize = 12;
+
+// encrypts arguments of the jobs carrying `"encrypt": true` with AES-256-GCM, like the
+// encrypted arguments of sidekiq enterprise it's the last one by default, it's a client
+// middleware encrypting them on push and a server middleware decrypting them for the
+// handler. attach it last on the client, and on the server after `retry_middleware` so the
+// job stays encrypted in the retry and dead sets
+//
+// an encrypted argument is the base64 of the nonce followed by the sealed JSON of the value
+#[derive(Clone)]
+pub struct ArgEncryption {
+    cipher: Aes256Gcm,
+    // none for the last argument
+    positions: Option<Vec<usize>>,
+}
+
+impl ArgEncryption {
+    pub fn new(key: &[u8; 32]) -> ArgEncryption {
+        ArgEncryption {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            positions: None,
+        }
+    }
+
+    // encrypt the arguments at these positions instead of the last one
+    pub fn positions(mut self, positions: &[usize]) -> ArgEncryption {
+        self.positions = Some(positions.to_vec());
+        self
+    }
+
+    fn selected(&self, job: &Job) -> Vec<usize> {
+        match self.positions {
+            Some(ref positions) => {
+                positions.iter().cloned().filter(|&position| position < job.args.len()).collect()
+            }
+            None => job.args.len().checked_sub(1).into_iter().collect(),
+        }
+    }
+
+    pub fn encrypt(&self, job: &mut Job) -> Result<()> {
+        for position in self.selected(job) {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let sealed = self.cipher
+                .encrypt(&nonce, &*to_vec(&job.args[position])?)
+                .map_err(|_| Error::from("encrypting argument failed"))?;
+            let mut bytes = nonce.to_vec();
+            bytes.extend(sealed);
+            job.args[position] = JValue::String(STANDARD.encode(bytes));
+        }
+        Ok(())
+    }
+
+    // fails with `InvalidArguments`, the job isn't retried as another try can't do better
+    pub fn decrypt(&self, job: &mut Job) -> Result<()> {
+        for position in self.selected(job) {
+            let bytes = match job.args[position] {
+                JValue::String(ref encoded) => STANDARD.decode(encoded).ok(),
+                _ => None,
+            };
+            let opened = bytes.filter(|bytes| bytes.len() > NONCE_LEN)
+                .and_then(|bytes| {
+                    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+                    self.cipher.decrypt(Nonce::from_slice(nonce), sealed).ok()
+                });
+            let value = match opened.and_then(|json| from_slice(&json).ok()) {
+                Some(value) => value,
+                None => {
+                    let message = format!("argument {} can't be decrypted", position);
+                    return Err(ErrorKind::InvalidArguments(message).into());
+                }
+            };
+            job.args[position] = value;
+        }
+        Ok(())
+    }
+}
+
+fn is_encrypted(job: &Job) -> bool {
+    job.extra.get("encrypt").and_then(|e| e.as_bool()).unwrap_or(false)
+}
+
+impl MiddleWare for ArgEncryption {
+    // the handler is given a decrypted copy, the middlewares before it keep the encrypted job
+    fn handle(&mut self, job: &mut Job, redis: RedisPool, next: NextFunc) -> MiddleWareResult {
+        if !is_encrypted(job) {
+            return next(job, redis);
+        }
+        let mut plain = job.clone();
+        self.decrypt(&mut plain)?;
+        next(&mut plain, redis)
+    }
+
+    fn cloned(&mut self) -> Box<dyn MiddleWare> {
+        Box::new(self.clone())
+    }
+}
+
+impl ClientMiddleWare for ArgEncryption {
+    fn handle(&mut self,
+              job: &mut Job,
+              redis: RedisPool,
+              next: ClientNextFunc)
+              -> ClientMiddleWareResult {
+        if is_encrypted(job) {
+            self.encrypt(job)?;
+        }
+        next(job, redis)
+    }
+
+    fn cloned(&mut self) -> Box<dyn ClientMiddleWare> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn job() -> Job {
+        Job::new("HardJob", vec![json!("bob"), json!({"card": "4242"})], "default")
+    }
+
+    #[test]
+    fn round_trips_the_last_argument() {
+        let encryption = ArgEncryption::new(&KEY);
+        let mut job = job();
+        encryption.encrypt(&mut job).unwrap();
+        assert_eq!(job.args[0], json!("bob"));
+        assert!(job.args[1].is_string());
+        encryption.decrypt(&mut job).unwrap();
+        assert_eq!(job.args, self::job().args);
+    }
+
+    #[test]
+    fn round_trips_the_positions() {
+        let encryption = ArgEncryption::new(&KEY).positions(&[0, 5]);
+        let mut job = job();
+        encryption.encrypt(&mut job).unwrap();
+        assert_ne!(job.args[0], json!("bob"));
+        assert_eq!(job.args[1], json!({"card": "4242"}));
+        encryption.decrypt(&mut job).unwrap();
+        assert_eq!(job.args, self::job().args);
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let encryption = ArgEncryption::new(&KEY);
+        let mut job = job();
+        encryption.encrypt(&mut job).unwrap();
+        let mut bytes = STANDARD.decode(job.args[1].as_str().unwrap()).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        job.args[1] = JValue::String(STANDARD.encode(bytes));
+        let err = encryption.decrypt(&mut job).unwrap_err();
+        assert!(matches!(*err.kind(), ErrorKind::InvalidArguments(_)));
+    }
+
+    #[test]
+    fn rejects_another_key() {
+        let mut job = job();
+        ArgEncryption::new(&KEY).encrypt(&mut job).unwrap();
+        assert!(ArgEncryption::new(&[8; 32]).decrypt(&mut job).is_err());
+        let mut job = self::job();
+        job.args[1] = json!("too short");
+        assert!(ArgEncryption::new(&KEY).decrypt(&mut job).is_err());
+    }
+}
@@ -3,8 +3,7 @@ error_chain!{
     foreign_links {
          RedisError(::redis::RedisError) ;
          JsonError(::serde_json::Error);
-         R2D2TimeoutError(::r2d2::GetTimeout);
-         R2D2InitializerError(::r2d2::InitializationError);
+         R2D2Error(::r2d2::Error);
     }
     errors {
          WorkerError(t: String) {
@@ -19,5 +18,51 @@ error_chain!{
              description("Middleware error")
              display("Middleware error '{}'", e)
          }
+         Limited(name: String) {
+             description("Rate limited")
+             display("Rate limited by '{}'", name)
+         }
+         Panicked(message: String) {
+             description("Job handler panicked")
+             display("Job handler panicked at '{}'", message)
+         }
+         Timeout(secs: usize) {
+             description("Job timed out")
+             display("Job timed out after {} seconds", secs)
+         }
+         InvalidArguments(message: String) {
+             description("Invalid job arguments")
+             display("Invalid job arguments '{}'", message)
+         }
+         PayloadTooLarge(size: usize, limit: usize) {
+             description("Job payload too large")
+             display("Job payload of {} bytes is larger than {} bytes", size, limit)
+         }
+         JobDead(e: Box<Error>) {
+             description("Job moved to dead set")
+             display("Job moved to dead set after '{}'", e)
+         }
+    }
+}
+
+impl Error {
+    // the `error_class` of a failed job shown by sidekiq web, the kind of the error, or
+    // `RuntimeError` like ruby's for a bare message
+    pub fn class_name(&self) -> &'static str {
+        match *self.kind() {
+            ErrorKind::Msg(_) => "RuntimeError",
+            ErrorKind::RedisError(_) => "RedisError",
+            ErrorKind::JsonError(_) => "JsonError",
+            ErrorKind::R2D2Error(_) => "R2D2Error",
+            ErrorKind::WorkerError(_) => "WorkerError",
+            ErrorKind::JobHandlerError(_) => "JobHandlerError",
+            ErrorKind::MiddleWareError(_) => "MiddleWareError",
+            ErrorKind::Limited(_) => "Limited",
+            ErrorKind::Panicked(_) => "Panicked",
+            ErrorKind::Timeout(_) => "Timeout",
+            ErrorKind::InvalidArguments(_) => "InvalidArguments",
+            ErrorKind::PayloadTooLarge(..) => "PayloadTooLarge",
+            ErrorKind::JobDead(ref cause) => cause.class_name(),
+        }
     }
-}
\ No newline at end of file
+}
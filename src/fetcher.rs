@@ -0,0 +1,397 @@
+use std::cmp::Ordering;
+use std::thread::sleep;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+
+use redis::{Commands, Pipeline};
+
+use serde_json::Value as JValue;
+
+use errors::*;
+use utils::with_namespace;
+use RedisConnection;
+use codec::{decode_value, reencode_value};
+use cluster::{hash_tagged, scan_keys};
+
+// a fetched job, `queue` is the queue name without namespace, `payload` is JSON or
+// MessagePack
+#[derive(Debug, Clone)]
+pub struct UnitOfWork {
+    pub queue: String,
+    pub payload: Vec<u8>,
+}
+
+// everything a fetcher needs to know to fetch a job of this process
+pub struct FetchContext<'a> {
+    pub conn: &'a mut RedisConnection,
+    pub namespace: &'a str,
+    pub identity: &'a str,
+    pub queues: &'a [String],
+    pub weights: &'a [f64],
+    // seconds to block at most when there is no job
+    pub timeout: usize,
+}
+
+impl<'a> FetchContext<'a> {
+    pub fn with_namespace(&self, snippet: &str) -> String {
+        with_namespace(self.namespace, snippet)
+    }
+
+    pub fn queue_name(&self, name: &str) -> String {
+        self.with_namespace(&("queue:".to_string() + name))
+    }
+}
+
+pub trait Fetcher: Send {
+    fn fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>>;
+    // the next job without blocking, in the order of the queues, for draining them
+    fn try_fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>> {
+        for name in ctx.queues {
+            let result: Option<Vec<u8>> = ctx.conn.rpop(ctx.queue_name(name), None)?;
+            if let Some(payload) = result {
+                return Ok(Some(UnitOfWork {
+                    queue: name.clone(),
+                    payload,
+                }));
+            }
+        }
+        Ok(None)
+    }
+    // called once the job is dealt with, whatever the result is
+    fn acknowledge(&mut self, _ctx: &mut FetchContext, _work: &UnitOfWork) -> Result<()> {
+        Ok(())
+    }
+    // called once when the server starts, before any worker fetches
+    fn startup(&mut self, _ctx: &mut FetchContext) -> Result<()> {
+        Ok(())
+    }
+    // push the jobs that didn't finish before shutdown back to their queues, at the
+    // consuming end so they run first
+    fn bulk_requeue(&mut self, ctx: &mut FetchContext, works: &[UnitOfWork]) -> Result<()> {
+        let mut pipe = Pipeline::new();
+        for work in works {
+            pipe.rpush(ctx.queue_name(&work.queue), &*work.payload).ignore();
+        }
+        let _: () = pipe.query(ctx.conn)?;
+        Ok(())
+    }
+    fn cloned(&mut self) -> Box<dyn Fetcher>;
+}
+
+// wait on every queue at once, a queue earlier in the list being served first when several
+// have jobs, so the order is shuffled by weight on each fetch
+#[derive(Clone, Copy, Default)]
+pub struct WeightedFetcher;
+
+impl Fetcher for WeightedFetcher {
+    fn fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>> {
+        brpop(ctx, weighted_order(ctx.queues, ctx.weights))
+    }
+
+    fn cloned(&mut self) -> Box<dyn Fetcher> {
+        Box::new(*self)
+    }
+}
+
+// queues are always checked in the order they are added
+#[derive(Clone, Copy, Default)]
+pub struct StrictFetcher;
+
+impl Fetcher for StrictFetcher {
+    fn fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>> {
+        brpop(ctx, ctx.queues.iter().collect())
+    }
+
+    fn cloned(&mut self) -> Box<dyn Fetcher> {
+        Box::new(*self)
+    }
+}
+
+// a random permutation where each queue is the first one with a chance proportional to
+// its weight, using the `u ^ (1 / weight)` sampling keys
+pub fn weighted_order<'q>(queues: &'q [String], weights: &[f64]) -> Vec<&'q String> {
+    let mut rng = thread_rng();
+    let mut keyed: Vec<_> = queues.iter()
+        .zip(weights)
+        .map(|(name, &weight)| {
+            let key = if weight > 0.0 {
+                rng.gen::<f64>().powf(1.0 / weight)
+            } else {
+                0.0
+            };
+            (key, name)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    keyed.into_iter().map(|(_, name)| name).collect()
+}
+
+fn brpop(ctx: &mut FetchContext, mut names: Vec<&String>) -> Result<Option<UnitOfWork>> {
+    // the queues are on different slots of a cluster, only the first one is waited on once
+    // they are all found empty
+    if ctx.conn.is_cluster() && names.len() > 1 {
+        for name in &names {
+            let result: Option<Vec<u8>> = ctx.conn.rpop(ctx.queue_name(name), None)?;
+            if let Some(payload) = result {
+                return Ok(Some(UnitOfWork {
+                    queue: name.to_string(),
+                    payload,
+                }));
+            }
+        }
+        names.truncate(1);
+    }
+    let queue_names: Vec<_> = names.iter().map(|name| ctx.queue_name(name)).collect();
+    let result: Option<(String, Vec<u8>)> = ctx.conn.brpop(queue_names, ctx.timeout as f64)?;
+    Ok(result.map(|(queue_name, payload)| {
+        let prefix_len = ctx.queue_name("").len();
+        UnitOfWork {
+            queue: queue_name[prefix_len..].to_string(),
+            payload,
+        }
+    }))
+}
+
+// same naming as sidekiq pro's super_fetch private queues, `queue:sq|<identity>|<queue>`, on a
+// cluster `{queue:<queue>}|sq|<identity>` to be on the slot of the queue
+const WORKING_QUEUE_PREFIX: &str = "queue:sq|";
+const CLUSTER_WORKING_QUEUE_INFIX: &str = "|sq|";
+
+// keep fetched jobs in a per process working queue until they are done, so that they are
+// pushed back to their queues by the next process starting if this one dies
+#[derive(Clone, Copy, Default)]
+pub struct ReliableFetcher {
+    strict: bool,
+}
+
+impl ReliableFetcher {
+    pub fn new() -> ReliableFetcher {
+        ReliableFetcher { strict: false }
+    }
+
+    pub fn strict() -> ReliableFetcher {
+        ReliableFetcher { strict: true }
+    }
+
+    fn working_queue_name(ctx: &FetchContext, name: &str) -> String {
+        if ctx.conn.is_cluster() {
+            cluster_working_queue(&ctx.queue_name(name), ctx.identity)
+        } else {
+            ctx.with_namespace(&format!("{}{}|{}", WORKING_QUEUE_PREFIX, ctx.identity, name))
+        }
+    }
+
+    // the first job of the queues in this order
+    fn rpoplpush(ctx: &mut FetchContext, names: &[&String]) -> Result<Option<UnitOfWork>> {
+        for name in names {
+            let (queue, working_queue) =
+                (ctx.queue_name(name), ReliableFetcher::working_queue_name(ctx, name));
+            let result: Option<Vec<u8>> = ctx.conn.rpoplpush(queue, working_queue)?;
+            if let Some(payload) = result {
+                return Ok(Some(UnitOfWork {
+                    queue: name.to_string(),
+                    payload,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    // the working queues of every process, with the identity of the process and the queue
+    fn working_queues(ctx: &mut FetchContext) -> Result<Vec<(String, String, String)>> {
+        if !ctx.conn.is_cluster() {
+            let prefix = ctx.with_namespace(WORKING_QUEUE_PREFIX);
+            let keys = scan_keys(ctx.conn, &(prefix.clone() + "*"))?;
+            return Ok(keys.into_iter()
+                .filter_map(|key| {
+                    let (identity, queue) = {
+                        let mut sp = key[prefix.len()..].splitn(2, '|');
+                        (sp.next()?.to_string(), sp.next()?.to_string())
+                    };
+                    Some((key, identity, queue))
+                })
+                .collect());
+        }
+        let any_queue = ctx.queue_name("*");
+        let keys = scan_keys(ctx.conn, &cluster_working_queue(&any_queue, "*"))?;
+        Ok(keys.into_iter()
+            .filter_map(|key| {
+                let (identity, queue) = parse_cluster_working_queue(&any_queue, &key)?;
+                Some((key, identity, queue))
+            })
+            .collect())
+    }
+}
+
+// `{<queue>}|sq|<identity>`, the queue key is kept as is when it already has a hash tag
+fn cluster_working_queue(queue: &str, identity: &str) -> String {
+    hash_tagged(queue) + CLUSTER_WORKING_QUEUE_INFIX + identity
+}
+
+// the identity and queue name of a working queue, `any_queue` being the key of the queue `*`
+fn parse_cluster_working_queue(any_queue: &str, key: &str) -> Option<(String, String)> {
+    let pattern = cluster_working_queue(any_queue, "*");
+    let star = pattern.find('*')?;
+    let infix = &pattern[star + 1..pattern.len() - 1];
+    let rest = key.get(star..)?;
+    if !key.starts_with(&pattern[..star]) {
+        return None;
+    }
+    let i = rest.rfind(infix)?;
+    Some((rest[i + infix.len()..].to_string(), rest[..i].to_string()))
+}
+
+impl Fetcher for ReliableFetcher {
+    fn fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>> {
+        // there is no blocking pop over several queues moving the job, they are all tried
+        // before blocking on the first one, or sleeping when strict
+        let names = if self.strict {
+            ctx.queues.iter().collect()
+        } else {
+            weighted_order(ctx.queues, ctx.weights)
+        };
+        if let Some(work) = ReliableFetcher::rpoplpush(ctx, &names)? {
+            return Ok(Some(work));
+        }
+        match names.first() {
+            Some(name) if !self.strict => {
+                let (queue, working_queue) =
+                    (ctx.queue_name(name), ReliableFetcher::working_queue_name(ctx, name));
+                let result: Option<Vec<u8>> =
+                    ctx.conn.brpoplpush(queue, working_queue, ctx.timeout as f64)?;
+                Ok(result.map(|payload| {
+                    UnitOfWork {
+                        queue: name.to_string(),
+                        payload,
+                    }
+                }))
+            }
+            _ => {
+                sleep(Duration::from_secs(ctx.timeout as u64));
+                Ok(None)
+            }
+        }
+    }
+
+    fn try_fetch(&mut self, ctx: &mut FetchContext) -> Result<Option<UnitOfWork>> {
+        let names: Vec<_> = ctx.queues.iter().collect();
+        ReliableFetcher::rpoplpush(ctx, &names)
+    }
+
+    fn acknowledge(&mut self, ctx: &mut FetchContext, work: &UnitOfWork) -> Result<()> {
+        let working_queue = ReliableFetcher::working_queue_name(ctx, &work.queue);
+        let _: () = ctx.conn.lrem(working_queue, 1, &*work.payload)?;
+        Ok(())
+    }
+
+    // a transaction by queue, the queues can be on several nodes of a cluster
+    fn bulk_requeue(&mut self, ctx: &mut FetchContext, works: &[UnitOfWork]) -> Result<()> {
+        let mut queues: Vec<&str> = works.iter().map(|work| &*work.queue).collect();
+        queues.sort();
+        queues.dedup();
+        for queue in queues {
+            let (queue_name, working_queue) =
+                (ctx.queue_name(queue), ReliableFetcher::working_queue_name(ctx, queue));
+            let mut pipe = Pipeline::new();
+            pipe.atomic();
+            for work in works.iter().filter(|work| work.queue == queue) {
+                pipe.lrem(&working_queue, 1, &*work.payload)
+                    .ignore()
+                    .rpush(&queue_name, &*work.payload)
+                    .ignore();
+            }
+            let _: () = pipe.query(ctx.conn)?;
+        }
+        Ok(())
+    }
+
+    // push the jobs left in the working queues of dead processes back to their queues
+    fn startup(&mut self, ctx: &mut FetchContext) -> Result<()> {
+        let mut count = 0;
+        for (working_queue, identity, queue) in ReliableFetcher::working_queues(ctx)? {
+            let (registered, alive): (bool, bool) = Pipeline::new()
+                .sismember(ctx.with_namespace("processes"), &identity)
+                .exists(ctx.with_namespace(&identity))
+                .query(ctx.conn)?;
+            if registered && alive {
+                continue;
+            }
+            let queue_name = ctx.queue_name(&queue);
+            // popping one by one so that concurrent recoveries never push a job twice
+            while let Some(payload) = ctx.conn.lpop::<_, Option<Vec<u8>>>(&working_queue, None)? {
+                let payload = match decode_value(&payload) {
+                    Ok(JValue::Object(mut job)) => {
+                        let interrupted = job.get("interrupted_count")
+                            .and_then(|c| c.as_u64())
+                            .unwrap_or(0);
+                        job.insert("interrupted_count".into(), json!(interrupted + 1));
+                        reencode_value(&JValue::Object(job), &payload)?
+                    }
+                    _ => payload,
+                };
+                // at the consuming end of the queue, they have waited long enough
+                let _: () = ctx.conn.rpush(&queue_name, payload)?;
+                count += 1;
+            }
+            info!("recovered working queue '{}' of dead process '{}'", queue, identity);
+        }
+        if count > 0 {
+            warn!("recovered {} orphaned jobs", count);
+        }
+        Ok(())
+    }
+
+    fn cloned(&mut self) -> Box<dyn Fetcher> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn weighted_order_is_a_permutation() {
+        let queues = names(&["a", "b", "c"]);
+        let mut order = weighted_order(&queues, &[1.0, 2.0, 3.0]);
+        order.sort();
+        assert_eq!(order, queues.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn weighted_order_puts_weightless_queues_last() {
+        let queues = names(&["a", "b", "c"]);
+        for _ in 0..100 {
+            assert_eq!(weighted_order(&queues, &[0.0, 1.0, 1.0])[2], "a");
+        }
+    }
+
+    #[test]
+    fn weighted_order_is_first_by_weight() {
+        let queues = names(&["a", "b"]);
+        let runs = 4000;
+        let first = (0..runs).filter(|_| weighted_order(&queues, &[1.0, 3.0])[0] == "b").count();
+        // 3 in 4 times
+        let ratio = first as f64 / runs as f64;
+        assert!(ratio > 0.7 && ratio < 0.8, "'b' first {} of the times", ratio);
+    }
+
+    #[test]
+    fn names_the_working_queues_of_a_cluster_after_their_queue() {
+        let key = cluster_working_queue("myapp:queue:default", "host:1:abc");
+        assert_eq!(key, "{myapp:queue:default}|sq|host:1:abc");
+        assert_eq!(parse_cluster_working_queue("myapp:queue:*", &key),
+                   Some(("host:1:abc".into(), "default".into())));
+        assert_eq!(parse_cluster_working_queue("other:queue:*", &key), None);
+
+        let key = cluster_working_queue("{myapp}:queue:default", "host:1:abc");
+        assert_eq!(key, "{myapp}:queue:default|sq|host:1:abc");
+        assert_eq!(parse_cluster_working_queue("{myapp}:queue:*", &key),
+                   Some(("host:1:abc".into(), "default".into())));
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::fmt;
 use std::iter::FromIterator;
 
 use serde_json::{Value as JValue, Map as JMap};
@@ -8,13 +9,22 @@ use serde::ser::SerializeMap;
 
 use chrono::{DateTime, UTC, NaiveDateTime};
 
+use rand::Rng;
+
+use redact::filtered;
+use utils::with_namespace;
+
+// the classes rails puts its jobs in, the class of the job being in `wrapped`
+pub const ACTIVE_JOB_WRAPPERS: [&str; 2] = ["ActiveJob::QueueAdapters::SidekiqAdapter::JobWrapper",
+                                            "Sidekiq::ActiveJob::Wrapper"];
+
 #[derive(Debug, Clone)]
 pub enum BoolOrUSize {
     Bool(bool),
     USize(usize),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Job {
     pub class: String,
     pub jid: String,
@@ -23,6 +33,7 @@ pub struct Job {
     pub enqueued_at: DateTime<UTC>,
     pub queue: String,
     pub retry: BoolOrUSize,
+    pub retry_queue: Option<String>,
     pub at: Option<DateTime<UTC>>, // when scheduled
     pub namespace: String,
     pub retry_info: Option<RetryInfo>,
@@ -30,17 +41,79 @@ pub struct Job {
 }
 
 impl Job {
-    fn with_namespace(&self, snippet: &str) -> String {
-        if self.namespace == "" {
-            snippet.into()
-        } else {
-            self.namespace.clone() + ":" + snippet
+    pub fn new(class: &str, args: Vec<JValue>, queue: &str) -> Job {
+        let now = UTC::now();
+        Job {
+            class: class.into(),
+            jid: new_jid(),
+            args,
+            created_at: Some(now),
+            enqueued_at: now,
+            queue: queue.into(),
+            retry: BoolOrUSize::Bool(true),
+            retry_queue: None,
+            at: None,
+            namespace: "".into(),
+            retry_info: None,
+            extra: BTreeMap::new(),
         }
     }
 
+    pub fn with_namespace(&self, snippet: &str) -> String {
+        with_namespace(&self.namespace, snippet)
+    }
+
     pub fn queue_name(&self) -> String {
         self.with_namespace(&("queue:".to_string() + &self.queue))
     }
+
+    pub fn is_active_job(&self) -> bool {
+        ACTIVE_JOB_WRAPPERS.contains(&&*self.class) && self.wrapped().is_some()
+    }
+
+    // the class the handler is found by, the wrapped class of an active job
+    pub fn handler_class(&self) -> &str {
+        self.wrapped().unwrap_or(&self.class)
+    }
+
+    // the arguments given to `perform_later` for an active job, its `args` otherwise, the
+    // job keeps its envelope so it's retried or moved to the dead set as rails expects
+    pub fn handler_args(&self) -> &[JValue] {
+        if !self.is_active_job() {
+            return &self.args;
+        }
+        match self.args.first().and_then(|envelope| envelope.get("arguments")) {
+            Some(JValue::Array(arguments)) => arguments,
+            _ => &self.args,
+        }
+    }
+
+    fn wrapped(&self) -> Option<&str> {
+        if !ACTIVE_JOB_WRAPPERS.contains(&&*self.class) {
+            return None;
+        }
+        self.extra.get("wrapped").and_then(|wrapped| wrapped.as_str())
+    }
+}
+
+// with the sensitive arguments filtered, as jobs are logged this way
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Job")
+            .field("class", &self.class)
+            .field("jid", &self.jid)
+            .field("args", &filtered(self).args)
+            .field("created_at", &self.created_at)
+            .field("enqueued_at", &self.enqueued_at)
+            .field("queue", &self.queue)
+            .field("retry", &self.retry)
+            .field("retry_queue", &self.retry_queue)
+            .field("at", &self.at)
+            .field("namespace", &self.namespace)
+            .field("retry_info", &self.retry_info)
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl Deserialize for Job {
@@ -53,15 +126,13 @@ impl Deserialize for Job {
             let args = JMapExt::<D>::remove_vec(&mut obj, "args")?;
             let queue = JMapExt::<D>::remove_string(&mut obj, "queue")?;
             let jid = JMapExt::<D>::remove_string(&mut obj, "jid")?;
-            let retry = obj.remove("retry")
-                .and_then(|r| if r.is_u64() {
-                    Some(BoolOrUSize::USize(r.as_u64().unwrap() as usize))
-                } else if r.is_boolean() {
-                    Some(BoolOrUSize::Bool(r.as_bool().unwrap()))
-                } else {
-                    None
-                })
-                .ok_or(D::Error::custom("no member 'retry'"))?;
+            let retry = match obj.remove("retry") {
+                Some(JValue::Bool(b)) => BoolOrUSize::Bool(b),
+                Some(ref r) if r.is_u64() => BoolOrUSize::USize(r.as_u64().unwrap() as usize),
+                Some(_) => return Err(D::Error::custom("'retry' not a bool or usize")),
+                None => BoolOrUSize::Bool(true), // sidekiq retries by default
+            };
+            let retry_queue = JMapExt::<D>::remove_string(&mut obj, "retry_queue").ok();
             let created_at = JMapExt::<D>::remove_datetime(&mut obj, "created_at").ok();
             let enqueued_at = JMapExt::<D>::remove_datetime(&mut obj, "enqueued_at")?;
             let at = JMapExt::<D>::remove_datetime(&mut obj, "at").ok();
@@ -70,7 +141,8 @@ impl Deserialize for Job {
                     retry_count <- JMapExt::<D>::remove_usize(&mut obj, "retry_count").ok();
                     error_message <- JMapExt::<D>::remove_string(&mut obj, "error_message").ok();
                     error_class <- JMapExt::<D>::remove_string(&mut obj, "error_class").ok();
-                    error_backtrace <- JMapExt::<D>::remove_svec(&mut obj, "error_backtrace").ok();
+                    // ruby sidekiq only records backtraces when the `backtrace` option is set
+                    error_backtrace <- Some(JMapExt::<D>::remove_svec(&mut obj, "error_backtrace").unwrap_or(vec![]));
                     failed_at <- JMapExt::<D>::remove_datetime(&mut obj, "failed_at").ok();
                     retried_at <- Some(JMapExt::<D>::remove_datetime(&mut obj, "retried_at").ok());
 
@@ -90,6 +162,7 @@ impl Deserialize for Job {
                 queue: queue,
                 jid: jid,
                 retry: retry,
+                retry_queue,
                 created_at: created_at,
                 enqueued_at: enqueued_at,
                 at: at,
@@ -142,6 +215,10 @@ impl Serialize for Job {
             }
         };
 
+        if let Some(ref retry_queue) = self.retry_queue {
+            map_serializer.serialize_entry("retry_queue", retry_queue)?;
+        }
+
         if let Some(ref retry_info) = self.retry_info {
             map_serializer.serialize_entry("error_backtrace", &retry_info.error_backtrace)?;
             map_serializer.serialize_entry("error_class", &retry_info.error_class)?;
@@ -165,6 +242,15 @@ impl Serialize for Job {
     }
 }
 
+// same shape as ruby's `SecureRandom.hex(12)`
+pub fn new_jid() -> String {
+    ::rand::thread_rng()
+        .gen_iter::<u8>()
+        .take(12)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 pub struct RetryInfo {
     pub retry_count: usize,
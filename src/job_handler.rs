@@ -1,7 +1,22 @@
+use std::any::Any;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{from_value, Value as JValue};
+
+use chrono::UTC;
+
 use job::Job;
+use data::AppData;
+use cancel::{CancellationToken, Cancellations};
+use progress::set_progress;
+use logging::{LogContext, log_context};
+use RedisPool;
 use JobSuccessType;
 use ::JobSuccessType::*;
-use errors::{ErrorKind, Result};
+use errors::{Error, ErrorKind, Result};
 
 pub type JobHandlerResult = Result<JobSuccessType>;
 
@@ -21,6 +36,215 @@ impl<F> JobHandler for F
     }
 }
 
+// what a `ContextHandler` is given, the logs of the handler carry the `log` context already
+pub struct JobContext<'j> {
+    pub job: &'j Job,
+    pub retry_count: usize,
+    // seconds the job waited in its queue
+    pub latency: f64,
+    pub redis: RedisPool,
+    pub log: Option<LogContext>,
+    pub data: AppData,
+    // set by `SidekiqClient::cancel` or `ServerHandle::cancel`, stopping is up to the handler
+    pub cancellation: CancellationToken,
+}
+
+impl<'j> JobContext<'j> {
+    pub fn new(job: &'j Job,
+               redis: RedisPool,
+               data: AppData,
+               cancellation: CancellationToken)
+               -> JobContext<'j> {
+        let waited = UTC::now().signed_duration_since(job.enqueued_at);
+        JobContext {
+            job,
+            retry_count: job.retry_info.as_ref().map(|info| info.retry_count).unwrap_or(0),
+            latency: (waited.num_milliseconds() as f64 / 1000f64).max(0.0),
+            redis,
+            log: log_context(),
+            data,
+            cancellation,
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    // percent done out of 100 and what the job is doing, read with `SidekiqClient::progress`
+    pub fn progress(&self, percent: u8, message: Option<&str>) -> Result<()> {
+        set_progress(&mut *self.redis.get()?, &self.job.namespace, &self.job.jid, percent, message)
+    }
+
+    pub fn jid(&self) -> &str {
+        &self.job.jid
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.job.queue
+    }
+
+    // the value of this type given to `SidekiqServer::add_data`
+    pub fn data<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.data.get()
+    }
+}
+
+// a handler given a `JobContext` instead of the bare job, attach it with
+// `attach_context_handler`
+pub trait ContextHandler: Send {
+    fn handle(&mut self, ctx: &JobContext) -> JobHandlerResult;
+    fn cloned(&mut self) -> Box<dyn ContextHandler>;
+}
+
+impl<F> ContextHandler for F
+    where F: FnMut(&JobContext) -> JobHandlerResult + Copy + Send + 'static
+{
+    fn handle(&mut self, ctx: &JobContext) -> JobHandlerResult {
+        self(ctx)
+    }
+    fn cloned(&mut self) -> Box<dyn ContextHandler> {
+        Box::new(*self)
+    }
+}
+
+// the `JobHandler` of a `ContextHandler`
+pub struct WithContext {
+    pub handler: Box<dyn ContextHandler>,
+    pub redis: RedisPool,
+    pub data: AppData,
+    pub cancellations: Cancellations,
+}
+
+impl JobHandler for WithContext {
+    fn handle(&mut self, job: &Job) -> JobHandlerResult {
+        let cancellation = self.cancellations.get(&job.jid).unwrap_or_default();
+        self.handler.handle(&JobContext::new(job, self.redis.clone(), self.data.clone(),
+                                             cancellation))
+    }
+    fn cloned(&mut self) -> Box<dyn JobHandler> {
+        Box::new(WithContext {
+            handler: self.handler.cloned(),
+            redis: self.redis.clone(),
+            data: self.data.clone(),
+            cancellations: self.cancellations.clone(),
+        })
+    }
+}
+
+pub type JobFuture = Pin<Box<dyn Future<Output = JobHandlerResult> + Send>>;
+
+// a handler whose job is done when the future resolves, attach it with `attach_async_handler`.
+// the worker fetching the job spawns the future on the tokio runtime of the server and goes
+// on fetching, it acknowledges the job once the future is done
+pub trait AsyncJobHandler: Send {
+    fn handle(&mut self, job: &Job) -> JobFuture;
+    fn cloned(&mut self) -> Box<dyn AsyncJobHandler>;
+}
+
+impl<F> AsyncJobHandler for F
+    where F: FnMut(&Job) -> JobFuture + Copy + Send + 'static
+{
+    fn handle(&mut self, job: &Job) -> JobFuture {
+        self(job)
+    }
+    fn cloned(&mut self) -> Box<dyn AsyncJobHandler> {
+        Box::new(*self)
+    }
+}
+
+// a handler taking its arguments deserialized, attach it with `attach_worker`
+pub trait Worker: Clone + Send {
+    type Args: Deserialize;
+    fn perform(&self, args: Self::Args) -> JobHandlerResult;
+}
+
+// the class a `Worker` is registered as and the queue its jobs are pushed to, usually
+// implemented with `#[sidekiq_worker]`
+pub trait WorkerClass: Worker {
+    fn class() -> &'static str;
+    fn queue() -> &'static str;
+
+    fn job(args: Vec<JValue>) -> Job {
+        Job::new(Self::class(), args, Self::queue())
+    }
+}
+
+// the `JobHandler` of a `Worker`
+#[derive(Clone)]
+pub struct TypedHandler<W>(pub W);
+
+impl<W: Worker + 'static> JobHandler for TypedHandler<W> {
+    fn handle(&mut self, job: &Job) -> JobHandlerResult {
+        self.0.perform(parse_args(job)?)
+    }
+    fn cloned(&mut self) -> Box<dyn JobHandler> {
+        Box::new(self.clone())
+    }
+}
+
+// the arguments of the job as a tuple or struct, or as its only argument, e.g. the hash
+// of `perform(params)`, failing with `InvalidArguments` which isn't retried, the arguments of
+// an active job are taken out of its envelope
+pub fn parse_args<T: Deserialize>(job: &Job) -> Result<T> {
+    let args = job.handler_args();
+    let parsed = match from_value(JValue::Array(args.to_vec())) {
+        Err(_) if args.len() == 1 => from_value(args[0].clone()),
+        parsed => parsed,
+    };
+    parsed.map_err(|e| ErrorKind::InvalidArguments(e.to_string()).into())
+}
+
+// what becomes of the jobs of a class without handler, unless there is a fallback handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownClass {
+    // count it as failed and drop it
+    Fail,
+    // drop it without counting it as failed
+    Ignore,
+    // move it to the dead set, to be retried from sidekiq web, counted as failed and passed
+    // to the death handlers
+    Dead,
+    // push it back to its queue after a while, for another process which knows the class,
+    // and to the dead set after a few times
+    Requeue,
+}
+
+// called with the job and its final error when retry_middleware, or `UnknownClass`, moves a
+// job to the dead set
+pub trait DeathHandler: Send {
+    fn handle(&mut self, job: &Job, error: &Error);
+    fn cloned(&mut self) -> Box<dyn DeathHandler>;
+}
+
+impl<F> DeathHandler for F
+    where F: FnMut(&Job, &Error) + Copy + Send + 'static
+{
+    fn handle(&mut self, job: &Job, error: &Error) {
+        self(job, error)
+    }
+    fn cloned(&mut self) -> Box<dyn DeathHandler> {
+        Box::new(*self)
+    }
+}
+
+// called with the job and the error on every failure of a handler, whether it is retried or not
+pub trait ErrorHandler: Send {
+    fn handle(&mut self, job: &Job, error: &Error);
+    fn cloned(&mut self) -> Box<dyn ErrorHandler>;
+}
+
+impl<F> ErrorHandler for F
+    where F: FnMut(&Job, &Error) + Copy + Send + 'static
+{
+    fn handle(&mut self, job: &Job, error: &Error) {
+        self(job, error)
+    }
+    fn cloned(&mut self) -> Box<dyn ErrorHandler> {
+        Box::new(*self)
+    }
+}
+
 pub fn printer_handler(job: &Job) -> JobHandlerResult {
     info!("handling {:?}", job);
     Ok(Success)
@@ -32,4 +256,58 @@ pub fn error_handler(_: &Job) -> JobHandlerResult {
 
 pub fn panic_handler(_: &Job) -> JobHandlerResult {
     panic!("yeah, I do it deliberately")
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use super::*;
+    use sidekiq_worker;
+    use JobSuccessType;
+
+    #[derive(Clone)]
+    struct Sum;
+
+    #[sidekiq_worker]
+    impl Sum {
+        fn perform(&self, args: Vec<i64>) -> JobHandlerResult {
+            Ok(JobSuccessType::Returned(JValue::from(args.iter().sum::<i64>())))
+        }
+    }
+
+    #[derive(Clone)]
+    struct Hard;
+
+    #[sidekiq_worker(class = "HardJob", queue = "critical")]
+    impl Hard {
+        fn perform(&self, (name, times): (String, u64)) -> JobHandlerResult {
+            Ok(JobSuccessType::Returned(JValue::from(name.repeat(times as usize))))
+        }
+    }
+
+    fn args_of<W: Worker<Args = A>, A>(_: &W) {}
+
+    #[test]
+    fn names_the_class_and_queue_after_the_struct_by_default() {
+        assert_eq!(Sum::class(), "Sum");
+        assert_eq!(Sum::queue(), "default");
+        let job = Sum::job(vec![JValue::from(1)]);
+        assert_eq!((&*job.class, &*job.queue), ("Sum", "default"));
+    }
+
+    #[test]
+    fn takes_the_class_and_queue_given() {
+        assert_eq!(Hard::class(), "HardJob");
+        assert_eq!(Hard::queue(), "critical");
+    }
+
+    #[test]
+    fn takes_the_args_of_perform() {
+        args_of::<_, Vec<i64>>(&Sum);
+        args_of::<_, (String, u64)>(&Hard);
+        let job = Job::new("HardJob", vec![JValue::from("ab"), JValue::from(2)], "critical");
+        match TypedHandler(Hard).handle(&job) {
+            Ok(JobSuccessType::Returned(value)) => assert_eq!(value, JValue::from("abab")),
+            _ => panic!("not performed"),
+        }
+    }
+}
@@ -9,38 +9,193 @@ extern crate error_chain;
 extern crate threadpool;
 extern crate redis;
 extern crate r2d2;
-extern crate r2d2_redis;
 extern crate rand;
-extern crate random_choice;
+#[cfg(unix)]
 extern crate libc;
+extern crate md5;
 extern crate chrono;
 #[macro_use]
 extern crate hado;
 #[macro_use]
-extern crate chan;
-extern crate chan_signal;
+extern crate crossbeam_channel;
+#[cfg(unix)]
+extern crate signal_hook;
+#[cfg(windows)]
+extern crate ctrlc;
+extern crate tokio;
+extern crate futures_util;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "otel")]
+extern crate opentelemetry;
+#[cfg(feature = "otel")]
+extern crate opentelemetry_sdk;
+#[cfg(feature = "otel")]
+extern crate tracing_opentelemetry;
+#[cfg(all(test, feature = "otel"))]
+extern crate tracing_subscriber;
+#[cfg(feature = "sentry")]
+extern crate sentry;
+#[cfg(feature = "webhook")]
+extern crate ureq;
+#[cfg(feature = "derive")]
+extern crate sidekiq_derive;
+// the paths `#[sidekiq_worker]` expands to, for its tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as sidekiq;
+#[cfg(feature = "msgpack")]
+extern crate rmpv;
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(any(feature = "compression", feature = "encryption"))]
+extern crate base64;
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+#[cfg(feature = "yaml")]
+extern crate yaml_rust;
 
 mod server;
+mod builder;
+mod client;
 mod job_handler;
 pub mod errors;
+pub mod api;
 mod job;
+mod codec;
+mod redact;
+#[cfg(feature = "compression")]
+mod compress;
 mod utils;
+mod cluster;
+mod aio;
+mod platform;
 mod worker;
 mod middleware;
+mod scheduled;
+mod periodic;
+mod unique;
+mod batch;
+mod limiter;
+mod fetcher;
+mod queues;
+mod metrics;
+mod sink;
+mod logging;
+mod data;
+mod cancel;
+mod backoff;
+mod autoscale;
+mod memory;
+mod backend;
+mod testing;
+mod swarm;
+mod progress;
+mod results;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "sentry")]
+mod report;
+#[cfg(feature = "webhook")]
+mod notifier;
+#[cfg(feature = "encryption")]
+mod encrypt;
+#[cfg(feature = "cli")]
+pub mod cli;
 
 use r2d2::Pool;
-use r2d2_redis::RedisConnectionManager;
+pub use utils::{RedisConnectionManager, TlsConfig};
+pub use cluster::RedisConnection;
+pub use aio::AsyncRedis;
 
 
-pub use server::SidekiqServer;
-pub use job_handler::{JobHandler, JobHandlerResult, printer_handler, error_handler, panic_handler};
+pub use server::{SidekiqServer, ServerHandle, LifecycleEvent, FORCE_QUIT_EXIT_CODE,
+                 MEMORY_EXIT_CODE, USAGE_EXIT_CODE};
+pub use builder::{SidekiqServerBuilder, CapsuleConfig};
+pub use client::SidekiqClient;
+pub use batch::{Batch, batch_middleware};
+pub use limiter::Limiter;
+pub use queues::QueueHandle;
+pub use sink::{MetricsSink, StatsdSink};
+pub use data::AppData;
+pub use cancel::CancellationToken;
+pub use autoscale::Autoscaler;
+pub use memory::MemoryBackend;
+pub use testing::InlineBackend;
+pub use backend::{Backend, RedisBackend, Push, FetchRequest, Heartbeat, BatchState};
+pub use swarm::{swarm, swarm_index, SWARM_INDEX_VAR};
+pub use progress::Progress;
+pub use redact::{sensitive_args, sensitive_key, filter_args, filtered, FILTERED};
+pub use logging::{LogContext, LogFormatter, log_context, init_logger, init_logger_with};
+pub use fetcher::{Fetcher, FetchContext, UnitOfWork, WeightedFetcher, StrictFetcher,
+                  ReliableFetcher};
+pub use job_handler::{JobHandler, JobHandlerResult, JobContext, ContextHandler, AsyncJobHandler,
+                      JobFuture, DeathHandler, ErrorHandler, Worker, WorkerClass, TypedHandler,
+                      parse_args, UnknownClass, printer_handler, error_handler,
+                      panic_handler};
 pub use middleware::{MiddleWare, MiddleWareResult, peek_middleware, retry_middleware,
-                     time_elapse_middleware, NextFunc};
-pub use job::{Job, RetryInfo};
+                     time_elapse_middleware, NextFunc, ClientMiddleWare, ClientMiddleWareResult,
+                     ClientNextFunc, AsyncMiddleWare, AsyncNextFunc, async_retry_middleware};
+pub use job::{Job, RetryInfo, BoolOrUSize};
+#[cfg(feature = "sentry")]
+pub use report::SentryMiddleware;
+#[cfg(feature = "webhook")]
+pub use notifier::WebhookNotifier;
+#[cfg(feature = "encryption")]
+pub use encrypt::ArgEncryption;
+// only on the inherent impl of a worker, with `class` and `queue` as its only options:
+//
+/// ```compile_fail
+/// # extern crate sidekiq;
+/// # use sidekiq::{sidekiq_worker, JobHandlerResult, Worker};
+/// #[derive(Clone)]
+/// struct Hard;
+///
+/// #[sidekiq_worker]
+/// impl Worker for Hard {
+///     type Args = Vec<i64>;
+///     fn perform(&self, _: Vec<i64>) -> JobHandlerResult { unimplemented!() }
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # extern crate sidekiq;
+/// # use sidekiq::{sidekiq_worker, JobHandlerResult};
+/// #[derive(Clone)]
+/// struct Hard;
+///
+/// #[sidekiq_worker(retry = 3)]
+/// impl Hard {
+///     fn perform(&self, _: Vec<i64>) -> JobHandlerResult { unimplemented!() }
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```
+/// # extern crate sidekiq;
+/// # use sidekiq::{sidekiq_worker, JobHandlerResult, JobSuccessType};
+/// #[derive(Clone)]
+/// struct Hard;
+///
+/// #[sidekiq_worker(queue = "critical")]
+/// impl Hard {
+///     fn perform(&self, _: Vec<i64>) -> JobHandlerResult { Ok(JobSuccessType::Success) }
+/// }
+/// # fn main() {}
+/// ```
+#[cfg(feature = "derive")]
+pub use sidekiq_derive::sidekiq_worker;
+pub use unique::{unique_client_middleware, unique_middleware, lock_digest};
 pub type RedisPool = Pool<RedisConnectionManager>;
 
 #[derive(Debug, Clone)]
 pub enum JobSuccessType {
     Success,
     Ignore,
+    // run the job again after this long, without counting it as failed
+    Reschedule(::std::time::Duration),
+    // succeeded with this value, kept under `result:<jid>` for `SidekiqClient::result`
+    Returned(serde_json::Value),
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use redis::{Commands, Pipeline, Script};
+
+use chrono::UTC;
+
+use errors::*;
+use utils::with_namespace;
+use job::new_jid;
+use RedisPool;
+
+// record a call in the sliding window if there is room left in it
+const WINDOW_SCRIPT: &str = r#"
+local now, interval = tonumber(ARGV[1]), tonumber(ARGV[2])
+redis.call('zremrangebyscore', KEYS[1], '-inf', now - interval)
+if redis.call('zcard', KEYS[1]) < tonumber(ARGV[3]) then
+    redis.call('zadd', KEYS[1], now, ARGV[4])
+    redis.call('expire', KEYS[1], math.ceil(interval))
+    return 1
+end
+return 0
+"#;
+
+// take a lease among the concurrent ones, dropping those whose holder has timed out
+const CONCURRENT_SCRIPT: &str = r#"
+redis.call('zremrangebyscore', KEYS[1], '-inf', ARGV[1])
+if redis.call('zcard', KEYS[1]) < tonumber(ARGV[3]) then
+    redis.call('zadd', KEYS[1], tonumber(ARGV[1]) + tonumber(ARGV[2]), ARGV[4])
+    return 1
+end
+return 0
+"#;
+
+#[derive(Debug, Clone)]
+enum LimiterKind {
+    // at most `count` calls in any sliding `interval`
+    Window { count: usize, interval: f64 },
+    // at most `count` calls in each fixed `interval` bucket
+    Bucket { count: usize, interval: u64 },
+    // at most `count` calls running at the same time
+    Concurrent { count: usize, lock_timeout: f64 },
+}
+
+// a redis backed limiter shared by every process using the same `name`
+#[derive(Clone)]
+pub struct Limiter {
+    pool: RedisPool,
+    namespace: String,
+    name: String,
+    kind: LimiterKind,
+}
+
+impl Limiter {
+    pub fn window(pool: RedisPool,
+                  namespace: &str,
+                  name: &str,
+                  count: usize,
+                  interval: Duration)
+                  -> Limiter {
+        Limiter::new(pool,
+                     namespace,
+                     name,
+                     LimiterKind::Window {
+                         count,
+                         interval: as_secs_f64(interval),
+                     })
+    }
+
+    pub fn bucket(pool: RedisPool,
+                  namespace: &str,
+                  name: &str,
+                  count: usize,
+                  interval: Duration)
+                  -> Limiter {
+        Limiter::new(pool,
+                     namespace,
+                     name,
+                     LimiterKind::Bucket {
+                         count,
+                         interval: ::std::cmp::max(interval.as_secs(), 1),
+                     })
+    }
+
+    // `lock_timeout` bounds how long a crashed holder keeps its lease
+    pub fn concurrent(pool: RedisPool,
+                      namespace: &str,
+                      name: &str,
+                      count: usize,
+                      lock_timeout: Duration)
+                      -> Limiter {
+        Limiter::new(pool,
+                     namespace,
+                     name,
+                     LimiterKind::Concurrent {
+                         count,
+                         lock_timeout: as_secs_f64(lock_timeout),
+                     })
+    }
+
+    fn new(pool: RedisPool, namespace: &str, name: &str, kind: LimiterKind) -> Limiter {
+        Limiter {
+            pool,
+            namespace: namespace.into(),
+            name: name.into(),
+            kind,
+        }
+    }
+
+    // run `f` if the limit allows it, otherwise returns a `Limited` error, which makes
+    // retry_middleware reschedule the job instead of failing it
+    pub fn within_limit<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce() -> Result<T>
+    {
+        let lease = match self.acquire()? {
+            Some(lease) => lease,
+            None => return Err(ErrorKind::Limited(self.name.clone()).into()),
+        };
+        let r = f();
+        if let LimiterKind::Concurrent { .. } = self.kind {
+            let _: () = self.pool.get()?.zrem(self.key(), &lease)?;
+        }
+        r
+    }
+
+    // returns the lease taken, or `None` when over the limit
+    fn acquire(&self) -> Result<Option<String>> {
+        let mut conn = self.pool.get()?;
+        let now = UTC::now();
+        let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+        let lease = new_jid();
+        let allowed = match self.kind {
+            LimiterKind::Window { count, interval } => {
+                let allowed: usize = Script::new(WINDOW_SCRIPT)
+                    .key(self.key())
+                    .arg(now)
+                    .arg(interval)
+                    .arg(count)
+                    .arg(&lease)
+                    .invoke(&mut *conn)?;
+                allowed == 1
+            }
+            LimiterKind::Bucket { count, interval } => {
+                let bucket = format!("{}:{}", self.key(), now as u64 / interval);
+                let (calls, _): (usize, ()) = Pipeline::new()
+                    .incr(&bucket, 1)
+                    .expire(&bucket, interval as i64)
+                    .query(&mut *conn)?;
+                calls <= count
+            }
+            LimiterKind::Concurrent { count, lock_timeout } => {
+                let allowed: usize = Script::new(CONCURRENT_SCRIPT)
+                    .key(self.key())
+                    .arg(now)
+                    .arg(lock_timeout)
+                    .arg(count)
+                    .arg(&lease)
+                    .invoke(&mut *conn)?;
+                allowed == 1
+            }
+        };
+        Ok(if allowed { Some(lease) } else { None })
+    }
+
+    fn key(&self) -> String {
+        with_namespace(&self.namespace, &("limiter:".to_string() + &self.name))
+    }
+}
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
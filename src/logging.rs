@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::time::Instant;
+
+use env_logger::LogBuilder;
+use log::{LogRecord, SetLoggerError};
+
+use chrono::UTC;
+
+use errors::*;
+use job::Job;
+
+// the job the current thread is running, attached to everything logged meanwhile
+#[derive(Debug, Clone)]
+pub struct LogContext {
+    // the worker running the job
+    pub worker: String,
+    pub jid: String,
+    pub class: String,
+    pub queue: String,
+    pub started_at: Instant,
+}
+
+impl LogContext {
+    pub fn new(worker: &str, job: &Job) -> LogContext {
+        LogContext {
+            worker: worker.into(),
+            jid: job.jid.clone(),
+            class: job.class.clone(),
+            queue: job.queue.clone(),
+            started_at: Instant::now(),
+        }
+    }
+
+    // seconds since the job started
+    pub fn elapsed(&self) -> f64 {
+        let elapsed = self.started_at.elapsed();
+        elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1000000000f64
+    }
+}
+
+// the target of the job start, done and fail lines, formatted like ruby sidekiq does
+pub const JOB_LOG_TARGET: &str = "sidekiq::job";
+
+thread_local!(static CONTEXT: RefCell<Option<LogContext>> = const { RefCell::new(None) });
+
+// restores the previous context when dropped
+pub struct ContextGuard {
+    previous: Option<LogContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+pub fn enter_context(context: LogContext) -> ContextGuard {
+    let previous = CONTEXT.with(|current| current.borrow_mut().replace(context));
+    ContextGuard { previous }
+}
+
+// for loggers of your own to attach the job context
+pub fn log_context() -> Option<LogContext> {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+// how `init_logger_with` writes each line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormatter {
+    // human readable, the job lines in the ruby sidekiq shape
+    Plain,
+    // one object per line, with the job context under `ctx`
+    Json,
+    // `key=value` pairs
+    Logfmt,
+}
+
+impl LogFormatter {
+    pub fn format(&self, record: &LogRecord) -> String {
+        match *self {
+            LogFormatter::Plain => format_plain(record),
+            LogFormatter::Json => format_json(record),
+            LogFormatter::Logfmt => format_logfmt(record),
+        }
+    }
+}
+
+impl FromStr for LogFormatter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LogFormatter> {
+        match s {
+            "plain" => Ok(LogFormatter::Plain),
+            "json" => Ok(LogFormatter::Json),
+            "logfmt" => Ok(LogFormatter::Logfmt),
+            _ => Err(format!("unknown log format '{}'", s).into()),
+        }
+    }
+}
+
+// like `env_logger::init`, filtered by `RUST_LOG`, with the job context appended to each line
+pub fn init_logger() -> ::std::result::Result<(), SetLoggerError> {
+    init_logger_with(LogFormatter::Plain)
+}
+
+pub fn init_logger_with(formatter: LogFormatter)
+                        -> ::std::result::Result<(), SetLoggerError> {
+    let mut builder = LogBuilder::new();
+    builder.format(move |record| formatter.format(record));
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.init()
+}
+
+fn timestamp() -> String {
+    UTC::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn format_plain(record: &LogRecord) -> String {
+    let now = timestamp();
+    let context = log_context();
+    if let (JOB_LOG_TARGET, Some(context)) = (record.target(), context.as_ref()) {
+        // `<time> <pid> TID-<tid> <class> JID-<jid> INFO: done: 1.23 sec`
+        return format!("{} {} TID-{} {} JID-{} {}: {}",
+                       now,
+                       process::id(),
+                       context.worker,
+                       context.class,
+                       context.jid,
+                       record.level(),
+                       record.args());
+    }
+    let mut line = format!("{} {} {}: {}",
+                           now,
+                           record.level(),
+                           record.location().module_path(),
+                           record.args());
+    if let Some(context) = context {
+        line += &format!(" jid={} class={} queue={} elapsed={:.3}",
+                         context.jid,
+                         context.class,
+                         context.queue,
+                         context.elapsed());
+    }
+    line
+}
+
+fn format_json(record: &LogRecord) -> String {
+    let mut line = json!({
+        "ts": timestamp(),
+        "pid": process::id(),
+        "lvl": record.level().to_string(),
+        "module": record.location().module_path(),
+        "msg": record.args().to_string(),
+    });
+    if let Some(context) = log_context() {
+        line["tid"] = json!(context.worker);
+        line["ctx"] = json!({
+            "jid": context.jid,
+            "class": context.class,
+            "queue": context.queue,
+            "elapsed": format!("{:.3}", context.elapsed()),
+        });
+    }
+    line.to_string()
+}
+
+fn format_logfmt(record: &LogRecord) -> String {
+    let mut pairs = vec![("time", timestamp()),
+                         ("level", record.level().to_string().to_lowercase()),
+                         ("module", record.location().module_path().to_string()),
+                         ("msg", record.args().to_string())];
+    if let Some(context) = log_context() {
+        pairs.push(("tid", context.worker.clone()));
+        pairs.push(("jid", context.jid.clone()));
+        pairs.push(("class", context.class.clone()));
+        pairs.push(("queue", context.queue.clone()));
+        pairs.push(("elapsed", format!("{:.3}", context.elapsed())));
+    }
+    let pairs: Vec<_> = pairs.into_iter()
+        .map(|(key, value)| format!("{}={}", key, logfmt_value(&value)))
+        .collect();
+    pairs.join(" ")
+}
+
+// quoted when it has spaces, quotes or `=`
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty() && !value.contains([' ', '"', '=']) {
+        value.into()
+    } else {
+        format!("{:?}", value)
+    }
+}
@@ -0,0 +1,516 @@
+// a backend keeping the queues in the process instead of redis, so the tests of handlers and
+// middlewares run without one. a client made with `SidekiqClient::in_memory` pushes into it
+// and a server made with `SidekiqServer::in_memory` works from it, each holding a clone
+//
+//     let backend = MemoryBackend::new();
+//     let mut client = SidekiqClient::in_memory(backend.clone())?;
+//     client.perform_async("HardJob", "default", vec![json!(1)])?;
+//     assert_eq!(backend.size("default"), 1);
+//
+// the retries, dead jobs, unique locks and batches of the middlewares are kept in it too,
+// the middlewares using the redis pool they are given fail, there's no redis behind it
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{to_string, Value as JValue};
+use chrono::UTC;
+
+use errors::*;
+use job::Job;
+use codec::{decode_job, decode_value};
+use fetcher::{Fetcher, UnitOfWork, weighted_order};
+use scheduled::prepare;
+use api::Stats;
+use metrics::QueueStats;
+use backend::{Backend, BatchState, FetchRequest, Heartbeat, Push};
+
+#[derive(Default)]
+struct State {
+    queues: BTreeMap<String, VecDeque<Vec<u8>>>,
+    // by the time they're due, like the `schedule` set
+    scheduled: Vec<(f64, String)>,
+    // the same for the `retry` set
+    retries: Vec<(f64, String)>,
+    dead: Vec<String>,
+    paused: BTreeSet<String>,
+    results: BTreeMap<String, JValue>,
+    processed: usize,
+    failed: usize,
+    // the jid holding each, until when if it has a ttl
+    locks: BTreeMap<String, (String, Option<Instant>)>,
+    batches: BTreeMap<String, Batch>,
+}
+
+#[derive(Default)]
+struct Batch {
+    pending: isize,
+    callbacks: String,
+    failed: BTreeSet<String>,
+    fired: BTreeSet<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    inner: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    // the processed and failed jobs counted by the servers so far
+    pub fn stats(&self) -> (usize, usize) {
+        let state = self.inner.0.lock().unwrap();
+        (state.processed, state.failed)
+    }
+
+    pub fn size(&self, queue: &str) -> usize {
+        self.inner.0.lock().unwrap().queues.get(queue).map_or(0, |queue| queue.len())
+    }
+
+    // the jobs waiting in the queue, the next one first
+    pub fn jobs(&self, queue: &str) -> Result<Vec<Job>> {
+        let state = self.inner.0.lock().unwrap();
+        match state.queues.get(queue) {
+            Some(queue) => queue.iter().map(|payload| decode_job(payload)).collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    // the queued and scheduled jobs of a class, like `HardJob.jobs` of ruby's fake testing
+    pub fn jobs_of(&self, class: &str) -> Result<Vec<Job>> {
+        let state = self.inner.0.lock().unwrap();
+        let mut jobs = vec![];
+        let payloads = state.queues
+            .values()
+            .flat_map(|queue| queue.iter().map(|payload| &payload[..]))
+            .chain(state.scheduled.iter().map(|(_, payload)| payload.as_bytes()));
+        for payload in payloads {
+            let job = decode_job(payload)?;
+            if job.handler_class() == class {
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+
+    pub fn scheduled_jobs(&self) -> Result<Vec<Job>> {
+        let state = self.inner.0.lock().unwrap();
+        state.scheduled.iter().map(|(_, payload)| decode_job(payload.as_bytes())).collect()
+    }
+
+    // the failed jobs waiting for their retry
+    pub fn retried_jobs(&self) -> Result<Vec<Job>> {
+        let state = self.inner.0.lock().unwrap();
+        state.retries.iter().map(|(_, payload)| decode_job(payload.as_bytes())).collect()
+    }
+
+    pub fn dead_jobs(&self) -> Result<Vec<Job>> {
+        let state = self.inner.0.lock().unwrap();
+        state.dead.iter().map(|payload| decode_job(payload.as_bytes())).collect()
+    }
+
+    // drop every job, result and count
+    pub fn clear(&self) {
+        *self.inner.0.lock().unwrap() = State::default();
+    }
+}
+
+fn insert_due(jobs: &mut Vec<(f64, String)>, at: f64, payload: &str) {
+    let i = jobs.iter().take_while(|&&(due, _)| due <= at).count();
+    jobs.insert(i, (at, payload.into()));
+}
+
+fn take_due(jobs: &mut Vec<(f64, String)>, now: f64) -> Vec<String> {
+    let n = jobs.iter().take_while(|&&(at, _)| at <= now).count();
+    jobs.drain(..n).map(|(_, payload)| payload).collect()
+}
+
+impl Backend for MemoryBackend {
+    // jobs are fetched in the order they're pushed, like with LPUSH and BRPOP
+    fn push(&self, jobs: &[Push]) -> Result<()> {
+        let (ref lock, ref cvar) = *self.inner;
+        let mut state = lock.lock().unwrap();
+        for job in jobs {
+            match *job {
+                Push::Enqueue(ref queue, ref payload) => {
+                    state.queues.entry(queue.clone()).or_default().push_back(payload.clone());
+                }
+                Push::Schedule(at, ref payload) => insert_due(&mut state.scheduled, at, payload),
+                Push::Retry(at, ref payload) => insert_due(&mut state.retries, at, payload),
+            }
+        }
+        cvar.notify_all();
+        Ok(())
+    }
+
+    fn enqueue_scheduled(&self) -> Result<usize> {
+        let now = UTC::now();
+        let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+        let due: Vec<String> = {
+            let mut state = self.inner.0.lock().unwrap();
+            let mut due = take_due(&mut state.retries, now);
+            due.extend(take_due(&mut state.scheduled, now));
+            due
+        };
+        let mut jobs = vec![];
+        for payload in &due {
+            let (queue, payload) = prepare(payload)?;
+            jobs.push(Push::Enqueue(queue, payload.into_bytes()));
+        }
+        self.push(&jobs)?;
+        Ok(jobs.len())
+    }
+
+    // the first job of the queues in weighted order, the fetcher isn't used
+    fn fetch(&self, _: &mut dyn Fetcher, request: &FetchRequest) -> Result<Option<UnitOfWork>> {
+        let (ref lock, ref cvar) = *self.inner;
+        let deadline = Instant::now() + Duration::from_secs(request.timeout as u64);
+        let mut state = lock.lock().unwrap();
+        loop {
+            for name in weighted_order(request.queues, request.weights) {
+                if let Some(payload) = state.queues.get_mut(name).and_then(|q| q.pop_front()) {
+                    return Ok(Some(UnitOfWork {
+                        queue: name.clone(),
+                        payload,
+                    }));
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn try_fetch(&self,
+                 fetcher: &mut dyn Fetcher,
+                 request: &FetchRequest)
+                 -> Result<Option<UnitOfWork>> {
+        let request = FetchRequest { timeout: 0, ..*request };
+        self.fetch(fetcher, &request)
+    }
+
+    fn acknowledge(&self, _: &mut dyn Fetcher, _: &FetchRequest, _: &UnitOfWork) -> Result<()> {
+        Ok(())
+    }
+
+    fn startup(&self, _: &mut dyn Fetcher, _: &FetchRequest) -> Result<()> {
+        Ok(())
+    }
+
+    // back at the front of their queues
+    fn requeue(&self, _: &mut dyn Fetcher, _: &FetchRequest, works: &[UnitOfWork]) -> Result<()> {
+        let (ref lock, ref cvar) = *self.inner;
+        let mut state = lock.lock().unwrap();
+        for work in works {
+            state.queues.entry(work.queue.clone()).or_default().push_front(work.payload.clone());
+        }
+        cvar.notify_all();
+        Ok(())
+    }
+
+    fn pause_queue(&self, name: &str) -> Result<()> {
+        self.inner.0.lock().unwrap().paused.insert(name.into());
+        Ok(())
+    }
+
+    fn unpause_queue(&self, name: &str) -> Result<()> {
+        self.inner.0.lock().unwrap().paused.remove(name);
+        Ok(())
+    }
+
+    fn paused_queues(&self) -> Result<Vec<String>> {
+        Ok(self.inner.0.lock().unwrap().paused.iter().cloned().collect())
+    }
+
+    fn bury(&self, job: &Job) -> Result<()> {
+        let payload = to_string(job)?;
+        self.inner.0.lock().unwrap().dead.push(payload);
+        Ok(())
+    }
+
+    // for good, the ttl isn't used
+    fn store_result(&self, jid: &str, value: &JValue, _: usize) -> Result<()> {
+        self.inner.0.lock().unwrap().results.insert(jid.into(), value.clone());
+        Ok(())
+    }
+
+    fn result(&self, jid: &str) -> Result<Option<JValue>> {
+        Ok(self.inner.0.lock().unwrap().results.get(jid).cloned())
+    }
+
+    fn record_stats(&self, processed: usize, failed: usize, _: usize) -> Result<()> {
+        let mut state = self.inner.0.lock().unwrap();
+        state.processed += processed;
+        state.failed += failed;
+        Ok(())
+    }
+
+    // no process is ever seen, the latency is of the oldest job of each queue
+    fn read_stats(&self) -> Result<Stats> {
+        let state = self.inner.0.lock().unwrap();
+        let now = UTC::now();
+        let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+        let mut queues = vec![];
+        for (name, queue) in &state.queues {
+            let enqueued_at = match queue.front() {
+                Some(oldest) => decode_value(oldest)?["enqueued_at"].as_f64(),
+                None => None,
+            };
+            queues.push(QueueStats {
+                queue: name.clone(),
+                size: queue.len(),
+                latency: enqueued_at.map_or(0.0, |enqueued_at| (now - enqueued_at).max(0.0)),
+            });
+        }
+        Ok(Stats {
+            processed: state.processed,
+            failed: state.failed,
+            enqueued: queues.iter().map(|queue| queue.size).sum(),
+            scheduled_size: state.scheduled.len(),
+            retry_size: state.retries.len(),
+            dead_size: state.dead.len(),
+            processes_size: 0,
+            queues,
+        })
+    }
+
+    // the counts aren't dated
+    fn prune_stats(&self, _: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn lock(&self, digest: &str, jid: &str, ttl: Option<u64>) -> Result<bool> {
+        let mut state = self.inner.0.lock().unwrap();
+        let now = Instant::now();
+        let held = match state.locks.get(digest) {
+            Some((holder, _)) if holder == jid => false,
+            Some(&(_, Some(until))) => until > now,
+            Some(&(_, None)) => true,
+            None => false,
+        };
+        if held {
+            return Ok(false);
+        }
+        let until = ttl.map(|ttl| now + Duration::from_secs(ttl));
+        state.locks.insert(digest.into(), (jid.into(), until));
+        Ok(true)
+    }
+
+    fn unlock(&self, digest: &str, jid: &str) -> Result<()> {
+        let mut state = self.inner.0.lock().unwrap();
+        if state.locks.get(digest).is_some_and(|(holder, _)| holder == jid) {
+            state.locks.remove(digest);
+        }
+        Ok(())
+    }
+
+    // kept for good, the description isn't used
+    fn open_batch(&self, bid: &str, jobs: isize, callbacks: &str, _: &str) -> Result<()> {
+        let mut state = self.inner.0.lock().unwrap();
+        let batch = state.batches.entry(bid.into()).or_default();
+        batch.pending += jobs;
+        batch.callbacks = callbacks.into();
+        Ok(())
+    }
+
+    fn drop_from_batch(&self, bid: &str, jobs: isize) -> Result<()> {
+        let mut state = self.inner.0.lock().unwrap();
+        state.batches.entry(bid.into()).or_default().pending -= jobs;
+        Ok(())
+    }
+
+    fn batch_ran(&self, bid: &str, jid: &str, failed: bool) -> Result<()> {
+        let mut state = self.inner.0.lock().unwrap();
+        if let Some(batch) = state.batches.get_mut(bid) {
+            if failed {
+                batch.failed.insert(jid.into());
+            } else {
+                batch.pending -= 1;
+                batch.failed.remove(jid);
+            }
+        }
+        Ok(())
+    }
+
+    fn batch_state(&self, bid: &str) -> Result<Option<BatchState>> {
+        let state = self.inner.0.lock().unwrap();
+        Ok(state.batches.get(bid).map(|batch| {
+            BatchState {
+                pending: batch.pending,
+                failures: batch.failed.len() as isize,
+                callbacks: batch.callbacks.clone(),
+            }
+        }))
+    }
+
+    fn fire_batch(&self, bid: &str, event: &str) -> Result<bool> {
+        let mut state = self.inner.0.lock().unwrap();
+        Ok(state.batches.get_mut(bid).is_some_and(|batch| batch.fired.insert(event.into())))
+    }
+
+    fn heartbeat(&self, _: &Heartbeat) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remote_signal(&self, _: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fetcher::StrictFetcher;
+
+    fn job(arg: i64, queue: &str) -> Vec<u8> {
+        to_string(&Job::new("HardJob", vec![json!(arg)], queue)).unwrap().into_bytes()
+    }
+
+    fn fetch(backend: &MemoryBackend, queues: &[&str]) -> Option<UnitOfWork> {
+        let queues: Vec<String> = queues.iter().map(|queue| queue.to_string()).collect();
+        let weights = vec![1.0; queues.len()];
+        let request = FetchRequest {
+            identity: "test",
+            queues: &queues,
+            weights: &weights,
+            timeout: 0,
+        };
+        backend.try_fetch(&mut StrictFetcher, &request).unwrap()
+    }
+
+    fn arg(work: Option<UnitOfWork>) -> i64 {
+        decode_job(&work.unwrap().payload).unwrap().args[0].as_i64().unwrap()
+    }
+
+    #[test]
+    fn fetches_in_push_order() {
+        let backend = MemoryBackend::new();
+        backend.push(&[Push::Enqueue("default".into(), job(1, "default")),
+                   Push::Enqueue("default".into(), job(2, "default"))])
+            .unwrap();
+        backend.push(&[Push::Enqueue("default".into(), job(3, "default"))]).unwrap();
+        assert_eq!(backend.size("default"), 3);
+        assert_eq!(arg(fetch(&backend, &["default"])), 1);
+        assert_eq!(arg(fetch(&backend, &["default"])), 2);
+        assert_eq!(arg(fetch(&backend, &["default"])), 3);
+        assert!(fetch(&backend, &["default"]).is_none());
+    }
+
+    #[test]
+    fn fetches_from_the_given_queues_only() {
+        let backend = MemoryBackend::new();
+        backend.push(&[Push::Enqueue("other".into(), job(1, "other"))]).unwrap();
+        assert!(fetch(&backend, &["default"]).is_none());
+        assert_eq!(fetch(&backend, &["default", "other"]).unwrap().queue, "other");
+    }
+
+    #[test]
+    fn requeues_at_the_front() {
+        let backend = MemoryBackend::new();
+        backend.push(&[Push::Enqueue("default".into(), job(1, "default")),
+                   Push::Enqueue("default".into(), job(2, "default"))])
+            .unwrap();
+        let work = fetch(&backend, &["default"]).unwrap();
+        let request = FetchRequest {
+            identity: "test",
+            queues: &[],
+            weights: &[],
+            timeout: 0,
+        };
+        backend.requeue(&mut StrictFetcher, &request, &[work]).unwrap();
+        assert_eq!(arg(fetch(&backend, &["default"])), 1);
+        assert_eq!(arg(fetch(&backend, &["default"])), 2);
+    }
+
+    #[test]
+    fn enqueues_the_due_jobs_by_time() {
+        let backend = MemoryBackend::new();
+        let now = UTC::now().timestamp() as f64;
+        let payload = |arg| String::from_utf8(job(arg, "default")).unwrap();
+        backend.push(&[Push::Schedule(now - 10.0, payload(2)),
+                   Push::Schedule(now + 3600.0, payload(3)),
+                   Push::Schedule(now - 20.0, payload(1)),
+                   Push::Retry(now - 5.0, payload(4)),
+                   Push::Retry(now + 3600.0, payload(5))])
+            .unwrap();
+        assert_eq!(backend.scheduled_jobs().unwrap().len(), 3);
+        assert_eq!(backend.retried_jobs().unwrap().len(), 2);
+        assert_eq!(backend.enqueue_scheduled().unwrap(), 3);
+        assert_eq!(arg(fetch(&backend, &["default"])), 4);
+        assert_eq!(arg(fetch(&backend, &["default"])), 1);
+        assert_eq!(arg(fetch(&backend, &["default"])), 2);
+        assert!(fetch(&backend, &["default"]).is_none());
+        assert_eq!(backend.scheduled_jobs().unwrap()[0].args, vec![json!(3)]);
+        assert_eq!(backend.retried_jobs().unwrap()[0].args, vec![json!(5)]);
+    }
+
+    #[test]
+    fn finds_jobs_of_a_class() {
+        let backend = MemoryBackend::new();
+        let now = UTC::now().timestamp() as f64;
+        let other = to_string(&Job::new("OtherJob", vec![], "default")).unwrap();
+        backend.push(&[Push::Enqueue("default".into(), job(1, "default")),
+                   Push::Schedule(now + 60.0, String::from_utf8(job(2, "low")).unwrap()),
+                   Push::Enqueue("default".into(), other.into_bytes())])
+            .unwrap();
+        assert_eq!(backend.jobs_of("HardJob").unwrap().len(), 2);
+        assert_eq!(backend.jobs_of("OtherJob").unwrap().len(), 1);
+        backend.clear();
+        assert!(backend.jobs_of("HardJob").unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_results_stats_and_the_dead() {
+        let backend = MemoryBackend::new();
+        backend.store_result("jid", &json!({"n": 1}), 60).unwrap();
+        assert_eq!(backend.result("jid").unwrap(), Some(json!({"n": 1})));
+        assert_eq!(backend.result("other").unwrap(), None);
+        backend.record_stats(3, 1, 60).unwrap();
+        backend.record_stats(1, 0, 60).unwrap();
+        assert_eq!(backend.stats(), (4, 1));
+        backend.bury(&Job::new("HardJob", vec![], "default")).unwrap();
+        assert_eq!(backend.dead_jobs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn locks_once() {
+        let backend = MemoryBackend::new();
+        assert!(backend.lock("digest", "a", None).unwrap());
+        assert!(!backend.lock("digest", "b", None).unwrap());
+        assert!(backend.lock("digest", "a", None).unwrap());
+        // not held by `b`
+        backend.unlock("digest", "b").unwrap();
+        assert!(!backend.lock("digest", "b", None).unwrap());
+        backend.unlock("digest", "a").unwrap();
+        assert!(backend.lock("digest", "b", None).unwrap());
+        assert!(backend.lock("expiring", "a", Some(0)).unwrap());
+        assert!(backend.lock("expiring", "b", Some(0)).unwrap());
+    }
+
+    #[test]
+    fn counts_batches() {
+        let backend = MemoryBackend::new();
+        assert!(backend.batch_state("bid").unwrap().is_none());
+        backend.open_batch("bid", 3, "{}", "").unwrap();
+        backend.drop_from_batch("bid", 1).unwrap();
+        backend.batch_ran("bid", "a", true).unwrap();
+        let state = backend.batch_state("bid").unwrap().unwrap();
+        assert_eq!((state.pending, state.failures), (2, 1));
+        backend.batch_ran("bid", "a", false).unwrap();
+        backend.batch_ran("bid", "b", false).unwrap();
+        let state = backend.batch_state("bid").unwrap().unwrap();
+        assert_eq!((state.pending, state.failures), (0, 0));
+        assert!(backend.fire_batch("bid", "success").unwrap());
+        assert!(!backend.fire_batch("bid", "success").unwrap());
+        assert!(!backend.fire_batch("other", "success").unwrap());
+    }
+}
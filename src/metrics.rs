@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use redis::Pipeline;
+
+use chrono::UTC;
+
+use errors::*;
+use utils::with_namespace;
+use RedisConnection;
+use codec::decode_value;
+
+// upper bounds in milliseconds of the histogram buckets, same as sidekiq 7
+pub const BUCKET_INTERVALS: [u64; 26] = [20, 30, 45, 65, 100, 150, 225, 335, 500, 750, 1100, 1700,
+                                     2500, 3800, 5750, 8500, 13000, 20000, 30000, 45000, 65000,
+                                     100000, 150000, 225000, 335000, u64::MAX];
+
+// how long the daily, hourly and per minute counters are kept
+const LONG_TERM: usize = 90 * 24 * 60 * 60;
+const MID_TERM: usize = 7 * 24 * 60 * 60;
+const SHORT_TERM: usize = 8 * 60 * 60;
+const HISTOGRAM_TTL: usize = 24 * 60 * 60;
+
+// since the process started, unlike the sidekiq counters reset on each flush
+#[derive(Debug, Clone, Default)]
+pub struct ClassTotals {
+    pub processed: u64,
+    pub failed: u64,
+    // of the successful jobs only
+    pub duration_ms: u64,
+    pub buckets: [u64; 26],
+}
+
+#[derive(Default)]
+struct Tracked {
+    // `<class>|ms`, `<class>|p` and `<class>|f` counters
+    jobs: BTreeMap<String, u64>,
+    histograms: BTreeMap<String, [u64; 26]>,
+    totals: BTreeMap<String, ClassTotals>,
+}
+
+// collects the execution time and outcome of jobs per class, flushed by the server on each
+// heartbeat in the format of the sidekiq 7 metrics tab
+#[derive(Clone, Default)]
+pub struct ExecutionTracker {
+    inner: Arc<Mutex<Tracked>>,
+}
+
+impl ExecutionTracker {
+    pub fn new() -> ExecutionTracker {
+        ExecutionTracker::default()
+    }
+
+    pub fn record(&self, class: &str, elapsed: Duration, success: bool) {
+        let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+        let idx = BUCKET_INTERVALS.iter().position(|&bound| ms < bound).unwrap_or(25);
+        let mut tracked = self.inner.lock().unwrap();
+        *tracked.jobs.entry(class.to_string() + "|p").or_insert(0) += 1;
+        if success {
+            // like sidekiq, the time of failures is too unpredictable to be worth tracking
+            *tracked.jobs.entry(class.to_string() + "|ms").or_insert(0) += ms;
+            tracked.histograms.entry(class.into()).or_insert([0; 26])[idx] += 1;
+        } else {
+            *tracked.jobs.entry(class.to_string() + "|f").or_insert(0) += 1;
+        }
+
+        let totals = tracked.totals.entry(class.into()).or_default();
+        totals.processed += 1;
+        if success {
+            totals.duration_ms += ms;
+            totals.buckets[idx] += 1;
+        } else {
+            totals.failed += 1;
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub fn totals(&self) -> BTreeMap<String, ClassTotals> {
+        self.inner.lock().unwrap().totals.clone()
+    }
+
+    // forget what was recorded since the last flush, when it isn't written to redis
+    pub fn clear(&self) {
+        let mut tracked = self.inner.lock().unwrap();
+        tracked.jobs.clear();
+        tracked.histograms.clear();
+    }
+
+    pub fn flush(&self, conn: &mut RedisConnection, namespace: &str) -> Result<()> {
+        let (jobs, histograms) = {
+            let mut tracked = self.inner.lock().unwrap();
+            (mem::take(&mut tracked.jobs), mem::take(&mut tracked.histograms))
+        };
+        if jobs.is_empty() {
+            return Ok(());
+        }
+        let now = UTC::now();
+        let mut pipe = Pipeline::new();
+
+        let window = now.format("%d-%H:%-M");
+        for (class, buckets) in &histograms {
+            let key = with_namespace(namespace, &format!("{}-{}", class, window));
+            pipe.cmd("BITFIELD").arg(&key).arg("OVERFLOW").arg("SAT");
+            for (idx, &count) in buckets.iter().enumerate().filter(|&(_, &count)| count > 0) {
+                pipe.arg("INCRBY").arg("u16").arg(format!("#{}", idx)).arg(count);
+            }
+            pipe.ignore().expire(&key, HISTOGRAM_TTL as i64).ignore();
+        }
+
+        for (bucket, ttl) in [(now.format("%Y%m%d"), LONG_TERM),
+                                 (now.format("%Y%m%d|%-H"), MID_TERM),
+                                 (now.format("%Y%m%d|%-H:%-M"), SHORT_TERM)] {
+            let key = with_namespace(namespace, &format!("j|{}", bucket));
+            for (field, &value) in &jobs {
+                pipe.hincr(&key, field, value).ignore();
+            }
+            pipe.expire(&key, ttl as i64).ignore();
+        }
+        let _: () = pipe.query(conn)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueStats {
+    pub queue: String,
+    pub size: usize,
+    // seconds the oldest job of the queue has been waiting
+    pub latency: f64,
+}
+
+pub fn sample_queues(conn: &mut RedisConnection,
+                     namespace: &str,
+                     queues: &[String])
+                     -> Result<Vec<QueueStats>> {
+    let mut pipe = Pipeline::new();
+    for queue in queues {
+        let key = with_namespace(namespace, &("queue:".to_string() + queue));
+        // jobs are pushed on the left and fetched from the right
+        pipe.llen(&key).lindex(&key, -1);
+    }
+    let sampled: Vec<(usize, Option<Vec<u8>>)> = pipe.query(conn)?;
+    let now = UTC::now();
+    let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+    Ok(queues.iter()
+        .zip(sampled)
+        .map(|(queue, (size, oldest))| {
+            let enqueued_at = oldest.and_then(|oldest| decode_value(&oldest).ok())
+                .and_then(|oldest| oldest["enqueued_at"].as_f64());
+            QueueStats {
+                queue: queue.clone(),
+                size,
+                latency: enqueued_at.map_or(0.0, |enqueued_at| (now - enqueued_at).max(0.0)),
+            }
+        })
+        .collect())
+}
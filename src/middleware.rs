@@ -1,10 +1,16 @@
 use serde_json::to_string;
 use chrono::UTC;
+use redis::Pipeline;
+use rand::Rng;
+use futures_util::future::{ready, FutureExt};
+use tokio::task::spawn_blocking;
 
-use RedisPool;
+use {AsyncRedis, RedisConnection, RedisPool};
 use JobSuccessType;
-use errors::Result;
+use errors::{Error, ErrorKind, Result};
 use job::{Job, RetryInfo};
+use job_handler::JobFuture;
+use backend::{self, current_or_redis, Backend, Push};
 
 pub type MiddleWareResult = Result<JobSuccessType>;
 pub type NextFunc<'a> = &'a mut (FnMut(&mut Job, RedisPool) -> MiddleWareResult + 'a);
@@ -25,6 +31,53 @@ impl<F> MiddleWare for F
     }
 }
 
+// the middlewares of the async jobs, see `SidekiqServer::attach_async_middleware`. they're
+// shared by the jobs running at once, and return the future of the rest of the chain, which
+// they may wrap. they're given the async redis of the server, a blocking call to the backend
+// goes to `tokio::task::spawn_blocking` like `async_retry_middleware` does
+pub type AsyncNextFunc = Box<dyn FnOnce(Job, AsyncRedis) -> JobFuture + Send>;
+
+pub trait AsyncMiddleWare: Send + Sync {
+    fn handle(&self, job: Job, redis: AsyncRedis, next: AsyncNextFunc) -> JobFuture;
+}
+
+impl<F> AsyncMiddleWare for F
+    where F: Fn(Job, AsyncRedis, AsyncNextFunc) -> JobFuture + Send + Sync + 'static
+{
+    fn handle(&self, job: Job, redis: AsyncRedis, next: AsyncNextFunc) -> JobFuture {
+        self(job, redis, next)
+    }
+}
+
+// client middlewares run before a job is pushed, returning `Ok(false)` without calling `next`
+// prevents the job from being pushed
+pub type ClientMiddleWareResult = Result<bool>;
+pub type ClientNextFunc<'a> = &'a mut (dyn FnMut(&mut Job, RedisPool) -> ClientMiddleWareResult + 'a);
+
+pub trait ClientMiddleWare: Send {
+    fn handle(&mut self,
+              job: &mut Job,
+              redis: RedisPool,
+              next: ClientNextFunc)
+              -> ClientMiddleWareResult;
+    fn cloned(&mut self) -> Box<dyn ClientMiddleWare>;
+}
+
+impl<F> ClientMiddleWare for F
+    where F: FnMut(&mut Job, RedisPool, ClientNextFunc) -> ClientMiddleWareResult + Copy + Send + 'static
+{
+    fn handle(&mut self,
+              job: &mut Job,
+              redis: RedisPool,
+              next: ClientNextFunc)
+              -> ClientMiddleWareResult {
+        self(job, redis, next)
+    }
+    fn cloned(&mut self) -> Box<dyn ClientMiddleWare> {
+        Box::new(*self)
+    }
+}
+
 pub fn peek_middleware(job: &mut Job, redis: RedisPool, mut next: NextFunc) -> MiddleWareResult {
     println!("Before Call {:?}", job);
     let r = next(job, redis);
@@ -32,40 +85,148 @@ pub fn peek_middleware(job: &mut Job, redis: RedisPool, mut next: NextFunc) -> M
     r
 }
 
+// the retries and dead jobs go through the backend of the server, or the redis given when the
+// chain runs without a server
 pub fn retry_middleware(job: &mut Job, redis: RedisPool, mut next: NextFunc) -> MiddleWareResult {
-    use redis::Commands;
+    let r = next(job, redis.clone());
+    if r.is_ok() {
+        return r;
+    }
+    let backend = current_or_redis(&redis, &job.namespace);
+    retry(job, &*backend, r)
+}
+
+// `retry_middleware` of the async jobs, the retries and dead jobs are pushed from a blocking
+// thread of the runtime
+pub fn async_retry_middleware(job: Job, redis: AsyncRedis, next: AsyncNextFunc) -> JobFuture {
+    let backend = match backend::current() {
+        Some(backend) => backend,
+        // only the workers of a server run the async middlewares, with their backend
+        None => return ready(Err("no backend to retry the job with".into())).boxed(),
+    };
+    next(job.clone(), redis)
+        .then(move |r| -> JobFuture {
+            if r.is_ok() {
+                return ready(r).boxed();
+            }
+            let mut job = job;
+            spawn_blocking(move || retry(&mut job, &*backend, r))
+                .map(|r| r.unwrap_or_else(|_| Err("retrying the job panicked".into())))
+                .boxed()
+        })
+        .boxed()
+}
+
+// what becomes of a job failing with `r`
+fn retry(job: &mut Job, backend: &dyn Backend, r: MiddleWareResult) -> MiddleWareResult {
     use job::BoolOrUSize::*;
-    let conn = redis.get().unwrap();
-    let r = next(job, redis);
     match r {
+        Err(Error(ErrorKind::Limited(ref name), _)) if overrated(job) < MAX_OVERRATED => {
+            let overrated = overrated(job) + 1;
+            let delay = 300 * overrated + ::rand::thread_rng().gen_range(1, 301);
+            info!("Job '{}' is limited by '{}', rescheduling in {} seconds",
+                  job.jid,
+                  name,
+                  delay);
+            job.extra.insert("overrated".into(), json!(overrated));
+            backend.push(&[Push::Schedule(due_in(delay), to_string(job)?)])?;
+            Ok(JobSuccessType::Ignore)
+        }
         Err(e) => {
             let retry_count = job.retry_info.as_ref().map(|i| i.retry_count).unwrap_or(0);
-            match (&job.retry, usize::max_value()) {
-                (&Bool(true), u) | (&USize(u), _) if retry_count < u => {
-                    warn!("Job '{:?}' failed with '{}', retrying", job, e);
-                    job.retry_info = Some(RetryInfo {
-                        retry_count: retry_count + 1,
-                        error_message: format!("{}", e),
-                        error_class: "dummy".to_string(),
-                        error_backtrace: e.backtrace()
-                            .map(|bt| {
-                                let s = format!("{:?}", bt);
-                                s.split('\n').map(|s| s.to_string()).collect()
-                            })
-                            .unwrap_or(vec![]),
-                        failed_at: UTC::now(),
-                        retried_at: None,
-                    });
-                    let _: () = conn.lpush(job.queue_name(), to_string(job).unwrap())?;
-                    Ok(JobSuccessType::Ignore)
+            let max_retries = match job.retry {
+                // retrying wouldn't change the arguments
+                _ if matches!(*e.kind(), ErrorKind::InvalidArguments(_)) => 0,
+                Bool(true) => DEFAULT_MAX_RETRIES,
+                Bool(false) => 0,
+                USize(u) => u,
+            };
+            if retry_count < max_retries {
+                let delay = retry_delay(retry_count);
+                warn!("Job '{:?}' failed with '{}', retrying in {} seconds", job, e, delay);
+                job.retry_info = Some(failure_info(&e, retry_count + 1));
+                if let Some(retry_queue) = job.retry_queue.clone() {
+                    job.queue = retry_queue;
                 }
-                _ => Err(e),
+                backend.push(&[Push::Retry(due_in(delay), to_string(job)?)])?;
+                Ok(JobSuccessType::Ignore)
+            } else {
+                warn!("Job '{:?}' failed with '{}' after {} retries, moving to dead set",
+                      job,
+                      e,
+                      retry_count);
+                job.retry_info = Some(failure_info(&e, retry_count));
+                backend.bury(job)?;
+                Err(ErrorKind::JobDead(Box::new(e)).into())
             }
         }
         Ok(o) => Ok(o),
     }
 }
 
+// in seconds since the epoch, as the scores of the sorted sets
+fn due_in(delay: u64) -> f64 {
+    let now = UTC::now();
+    now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64 + delay as f64
+}
+
+// a job limited more often than this is failed as usual, same as sidekiq enterprise
+const MAX_OVERRATED: u64 = 20;
+
+fn overrated(job: &Job) -> u64 {
+    job.extra.get("overrated").and_then(|o| o.as_u64()).unwrap_or(0)
+}
+
+// used for `retry: true`, same as ruby sidekiq
+const DEFAULT_MAX_RETRIES: usize = 25;
+
+// ruby sidekiq's backoff: count^4 + 15 + rand(10) * (count + 1) seconds
+fn retry_delay(count: usize) -> u64 {
+    let count = count as u64;
+    count.saturating_pow(4) + 15 + ::rand::thread_rng().gen_range(0, 10) * (count + 1)
+}
+
+fn failure_info(e: &Error, retry_count: usize) -> RetryInfo {
+    RetryInfo {
+        retry_count,
+        error_message: format!("{}", e),
+        error_class: e.class_name().to_string(),
+        error_backtrace: e.backtrace()
+            .map(|bt| {
+                let s = format!("{:?}", bt);
+                s.split('\n').map(|s| s.to_string()).collect()
+            })
+            .unwrap_or_default(),
+        failed_at: UTC::now(),
+        retried_at: None,
+    }
+}
+
+// same defaults as ruby sidekiq's `dead_max_jobs` and `dead_timeout_in_seconds`
+pub const DEAD_MAX_JOBS: usize = 10000;
+pub const DEAD_TIMEOUT: u64 = 180 * 24 * 60 * 60;
+
+// the oldest jobs over `max_jobs`, and the ones dead for more than `timeout` seconds, are
+// dropped from the dead set
+pub fn send_to_morgue(conn: &mut RedisConnection,
+                      job: &Job,
+                      max_jobs: usize,
+                      timeout: u64)
+                      -> Result<()> {
+    let dead = job.with_namespace("dead");
+    let now = UTC::now();
+    let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+    let _: () = Pipeline::new()
+        .zadd(&dead, to_string(job)?, now)
+        .zrembyscore(&dead, "-inf", now - timeout as f64)
+        .cmd("ZREMRANGEBYRANK")
+        .arg(&dead)
+        .arg(0)
+        .arg(-(max_jobs as isize) - 1)
+        .query(conn)?;
+    Ok(())
+}
+
 pub fn time_elapse_middleware(job: &mut Job,
                               redis: RedisPool,
                               mut next: NextFunc)
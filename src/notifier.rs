@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use serde_json::to_string;
+
+use ureq::{Agent, AgentBuilder};
+
+use errors::Error;
+use job::Job;
+use redact::filtered;
+use job_handler::DeathHandler;
+
+// posts the dead jobs to a webhook, with a `text` slack and mattermost display and the job
+// details alongside for other receivers, attach it with `attach_death_handler`
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    url: String,
+    agent: Agent,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> WebhookNotifier {
+        WebhookNotifier::with_timeout(url, 5)
+    }
+
+    // a notification blocks its worker for at most `timeout` seconds
+    pub fn with_timeout(url: &str, timeout: usize) -> WebhookNotifier {
+        WebhookNotifier {
+            url: url.into(),
+            agent: AgentBuilder::new().timeout(Duration::from_secs(timeout as u64)).build(),
+        }
+    }
+}
+
+impl DeathHandler for WebhookNotifier {
+    fn handle(&mut self, job: &Job, error: &Error) {
+        let retry_count = job.retry_info.as_ref().map(|info| info.retry_count).unwrap_or(0);
+        let payload = json!({
+            "text": format!("Job {} `{}` died on queue '{}' after {} retries: {}",
+                            job.class,
+                            job.jid,
+                            job.queue,
+                            retry_count,
+                            error),
+            "class": job.class,
+            "jid": job.jid,
+            "queue": job.queue,
+            "args": filtered(job).args,
+            "retry_count": retry_count,
+            "error": error.to_string(),
+        });
+        let payload = match to_string(&payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("serializing the webhook payload of '{}' failed: '{}'", job.jid, e);
+                return;
+            }
+        };
+        if let Err(e) = self.agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&payload) {
+            warn!("notifying the death of '{}' failed: '{}'", job.jid, e);
+        }
+    }
+
+    fn cloned(&mut self) -> Box<dyn DeathHandler> {
+        Box::new(self.clone())
+    }
+}
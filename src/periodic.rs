@@ -0,0 +1,236 @@
+use std::cmp;
+
+use redis::Script;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, UTC};
+
+use errors::*;
+use utils::with_namespace;
+use job::{Job, new_jid};
+use client::SidekiqClient;
+use RedisPool;
+
+// seconds a leader keeps the periodic lock without renewing it
+const LEADER_TTL: usize = 60;
+
+// renew the lock if we hold it, otherwise try to take it over
+const ELECT_SCRIPT: &str = r#"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    redis.call('expire', KEYS[1], ARGV[2])
+    return 1
+elseif redis.call('set', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then
+    return 1
+else
+    return 0
+end
+"#;
+
+// a standard 5 fields cron expression, evaluated in UTC
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // when both day fields are restricted, cron matches either of them
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<CronSchedule> {
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("invalid cron expression '{}', expecting 5 fields", expr).into());
+        }
+        let parse = |field: &str, min, max| {
+            parse_field(field, min, max).ok_or_else(|| {
+                Error::from(format!("invalid field '{}' in cron expression '{}'", field, expr))
+            })
+        };
+        let weekdays = parse(fields[4], 0, 7)?;
+        Ok(CronSchedule {
+            minutes: parse(fields[0], 0, 59)?,
+            hours: parse(fields[1], 0, 23)?,
+            days: parse(fields[2], 1, 31)?,
+            months: parse(fields[3], 1, 12)?,
+            // both 0 and 7 are sunday
+            weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+            days_restricted: !fields[2].starts_with('*'),
+            weekdays_restricted: !fields[4].starts_with('*'),
+        })
+    }
+
+    pub fn matches(&self, t: &DateTime<UTC>) -> bool {
+        let day = self.days & (1 << t.day()) != 0;
+        let weekday = self.weekdays & (1 << t.weekday().num_days_from_sunday()) != 0;
+        let day = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        day && self.minutes & (1 << t.minute()) != 0 && self.hours & (1 << t.hour()) != 0 &&
+        self.months & (1 << t.month()) != 0
+    }
+}
+
+// parse `*`, `*/n`, `a`, `a-b`, `a-b/n`, `a/n` and comma separated lists of them into a bitmask
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let mut sp = part.splitn(2, '/');
+        let range = sp.next()?;
+        let step = match sp.next() {
+            Some(step) => step.parse::<u32>().ok()?,
+            None => 1,
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some(idx) = range.find('-') {
+            (range[..idx].parse().ok()?, range[idx + 1..].parse().ok()?)
+        } else {
+            let v = range.parse().ok()?;
+            if part.contains('/') { (v, max) } else { (v, v) }
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return None;
+        }
+        for v in (lo..hi + 1).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Some(mask)
+}
+
+pub struct Periodic {
+    pub schedule: CronSchedule,
+    pub job: Job,
+}
+
+pub struct PeriodicScheduler {
+    client: SidekiqClient,
+    pool: RedisPool,
+    namespace: String,
+    identity: String,
+    jobs: Vec<Periodic>,
+    last_minute: i64,
+}
+
+impl PeriodicScheduler {
+    pub fn new(pool: RedisPool,
+               namespace: &str,
+               identity: &str,
+               jobs: Vec<Periodic>)
+               -> PeriodicScheduler {
+        PeriodicScheduler {
+            client: SidekiqClient::new(pool.clone(), namespace),
+            pool,
+            namespace: namespace.into(),
+            identity: identity.into(),
+            jobs,
+            // never backfill the ticks before we started
+            last_minute: UTC::now().timestamp() / 60,
+        }
+    }
+
+    // enqueue the jobs of every minute passed since last call, only on the elected process
+    pub fn enqueue_jobs(&mut self) -> Result<usize> {
+        if self.jobs.is_empty() || !self.elect()? {
+            self.last_minute = UTC::now().timestamp() / 60;
+            return Ok(0);
+        }
+        let minute = UTC::now().timestamp() / 60;
+        let mut count = 0;
+        // catch up on ticks missed in between, but not more than an hour of them. a minute is
+        // done once all its jobs are pushed, the next call picks up from the one failing
+        for m in cmp::max(self.last_minute + 1, minute - 59)..minute + 1 {
+            let t = DateTime::from_utc(NaiveDateTime::from_timestamp(m * 60, 0), UTC);
+            for periodic in &self.jobs {
+                if periodic.schedule.matches(&t) {
+                    let mut job = periodic.job.clone();
+                    job.jid = new_jid();
+                    job.created_at = Some(UTC::now());
+                    self.client.push(job)?;
+                    count += 1;
+                }
+            }
+            self.last_minute = m;
+        }
+        self.last_minute = minute;
+        Ok(count)
+    }
+
+    fn elect(&self) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let leader: usize = Script::new(ELECT_SCRIPT)
+            .key(with_namespace(&self.namespace, "periodic:leader"))
+            .arg(&self.identity)
+            .arg(LEADER_TTL)
+            .invoke(&mut *conn)?;
+        Ok(leader == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<UTC> {
+        // 2024-01-01 is a monday
+        UTC.ymd(2024, 1, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(parse_field("*", 0, 59), Some((1 << 60) - 1));
+        assert_eq!(parse_field("5", 0, 59), Some(1 << 5));
+        assert_eq!(parse_field("1-3", 0, 59), Some(0b1110));
+        assert_eq!(parse_field("*/20", 0, 59), Some(1 | 1 << 20 | 1 << 40));
+        assert_eq!(parse_field("10/25", 0, 59), Some(1 << 10 | 1 << 35));
+        assert_eq!(parse_field("1,4-5", 0, 59), Some(0b110010));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("* * 0 * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert!(CronSchedule::parse("5-1 * * * *").is_err());
+        assert!(CronSchedule::parse("a * * * *").is_err());
+    }
+
+    #[test]
+    fn matches_times() {
+        let schedule = CronSchedule::parse("30 9 * * 1-5").unwrap();
+        assert!(schedule.matches(&at(1, 9, 30)));
+        assert!(!schedule.matches(&at(1, 9, 31)));
+        assert!(!schedule.matches(&at(1, 10, 30)));
+        // a sunday
+        assert!(!schedule.matches(&at(7, 9, 30)));
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        for expr in &["0 0 * * 0", "0 0 * * 7"] {
+            let schedule = CronSchedule::parse(expr).unwrap();
+            assert!(schedule.matches(&at(7, 0, 0)));
+            assert!(!schedule.matches(&at(8, 0, 0)));
+        }
+    }
+
+    #[test]
+    fn matches_either_day_field_when_both_are_restricted() {
+        let schedule = CronSchedule::parse("0 0 15 * 1").unwrap();
+        // a monday, and the 15th a monday
+        assert!(schedule.matches(&at(8, 0, 0)));
+        assert!(schedule.matches(&at(15, 0, 0)));
+        assert!(!schedule.matches(&at(9, 0, 0)));
+        // only the day restricted
+        let schedule = CronSchedule::parse("0 0 9 * *").unwrap();
+        assert!(schedule.matches(&at(9, 0, 0)));
+        assert!(!schedule.matches(&at(8, 0, 0)));
+    }
+}
@@ -0,0 +1,119 @@
+// what differs between unix and windows: the process identity and the signals
+
+use std::process;
+
+use crossbeam_channel::{unbounded, Receiver};
+
+use errors::*;
+
+pub fn pid() -> usize {
+    process::id() as usize
+}
+
+#[cfg(unix)]
+#[allow(unused_assignments)]
+pub fn hostname() -> Option<String> {
+    use libc::{c_char, size_t, c_int};
+
+    extern "C" {
+        pub fn gethostname(name: *mut c_char, size: size_t) -> c_int;
+    }
+
+    let len = 255;
+    let mut buf = Vec::<u8>::with_capacity(len);
+
+    let ptr = buf.as_mut_slice().as_mut_ptr();
+
+    let err = unsafe { gethostname(ptr as *mut c_char, len as size_t) } as isize;
+
+    match err {
+        0 => {
+
+            let mut real_len = len;
+            let mut i = 0;
+            loop {
+                let byte = unsafe { *(((ptr as u64) + (i as u64)) as *const u8) };
+                if byte == 0 {
+                    real_len = i;
+                    break;
+                }
+
+                i += 1;
+            }
+            unsafe { buf.set_len(real_len) }
+            Some(String::from_utf8_lossy(buf.as_slice()).into_owned())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+pub fn hostname() -> Option<String> {
+    ::std::env::var("COMPUTERNAME").ok()
+}
+
+// the names of the signals received, INT, TERM and USR1 stop the server, TSTP quiets it and
+// TTIN logs the running jobs like ruby sidekiq
+#[cfg(unix)]
+pub fn listen_signals() -> Result<Receiver<&'static str>> {
+    use signal_hook::consts::{SIGINT, SIGTERM, SIGTSTP, SIGTTIN, SIGUSR1};
+    use signal_hook::iterator::Signals;
+    use std::thread;
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGUSR1, SIGTSTP, SIGTTIN])
+        .map_err(|e| Error::from(format!("registering signal handlers failed: '{}'", e)))?;
+    let (tx, rx) = unbounded();
+    thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || for signal in signals.forever() {
+            let name = match signal {
+                SIGINT => "INT",
+                SIGTERM => "TERM",
+                SIGUSR1 => "USR1",
+                SIGTSTP => "TSTP",
+                _ => "TTIN",
+            };
+            if tx.send(name).is_err() {
+                break;
+            }
+        })
+        .map_err(|e| Error::from(format!("spawning signal thread failed: '{}'", e)))?;
+    Ok(rx)
+}
+
+// only ctrl-c, which stops the server like INT, the other commands are still available
+// remotely through sidekiq web
+#[cfg(windows)]
+pub fn listen_signals() -> Result<Receiver<&'static str>> {
+    let (tx, rx) = unbounded();
+    ::ctrlc::set_handler(move || {
+            let _ = tx.send("INT");
+        })
+        .map_err(|e| Error::from(format!("registering ctrl-c handler failed: '{}'", e)))?;
+    Ok(rx)
+}
+
+// send one of the signals `listen_signals` knows by name to another process
+#[cfg(unix)]
+pub fn send_signal(pid: u32, name: &str) -> Result<()> {
+    use libc::{kill, pid_t, SIGINT, SIGTERM, SIGTSTP, SIGTTIN, SIGUSR1};
+
+    let signal = match name {
+        "INT" => SIGINT,
+        "TERM" => SIGTERM,
+        "USR1" => SIGUSR1,
+        "TSTP" => SIGTSTP,
+        "TTIN" => SIGTTIN,
+        _ => return Err(format!("unknown signal '{}'", name).into()),
+    };
+    if unsafe { kill(pid as pid_t, signal) } != 0 {
+        return Err(format!("sending {} to {} failed", name, pid).into());
+    }
+    Ok(())
+}
+
+// ctrl-c already reaches every process of the console
+#[cfg(windows)]
+pub fn send_signal(_pid: u32, _name: &str) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use redis::Commands;
+use serde_json::{from_str, Value as JValue};
+
+use chrono::UTC;
+
+use errors::*;
+use utils::with_namespace;
+use RedisConnection;
+
+// the progress outlives the job for a while, so a UI polling it sees it finish
+pub const PROGRESS_TTL: usize = 30 * 60;
+
+// what a handler last reported with `JobContext::progress`
+#[derive(Debug, Clone)]
+pub struct Progress {
+    // 0 to 100
+    pub percent: u8,
+    pub message: Option<String>,
+    // unix time of the report
+    pub at: f64,
+}
+
+pub fn progress_key(namespace: &str, jid: &str) -> String {
+    with_namespace(namespace, &("progress:".to_string() + jid))
+}
+
+pub fn set_progress(conn: &mut RedisConnection,
+                    namespace: &str,
+                    jid: &str,
+                    percent: u8,
+                    message: Option<&str>)
+                    -> Result<()> {
+    let now = UTC::now();
+    let value = json!({
+        "percent": percent.min(100),
+        "message": message,
+        "at": now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64,
+    });
+    let _: () = conn.set_ex(progress_key(namespace, jid), value.to_string(), PROGRESS_TTL as u64)?;
+    Ok(())
+}
+
+pub fn get_progress(conn: &mut RedisConnection,
+                    namespace: &str,
+                    jid: &str)
+                    -> Result<Option<Progress>> {
+    let value: Option<String> = conn.get(progress_key(namespace, jid))?;
+    let value: JValue = match value {
+        Some(value) => from_str(&value)?,
+        None => return Ok(None),
+    };
+    Ok(Some(Progress {
+        percent: value.get("percent").and_then(|p| p.as_u64()).unwrap_or(0) as u8,
+        message: value.get("message").and_then(|m| m.as_str()).map(|m| m.to_string()),
+        at: value.get("at").and_then(|a| a.as_f64()).unwrap_or(0.0),
+    }))
+}
@@ -0,0 +1,146 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use errors::*;
+use metrics::{ExecutionTracker, BUCKET_INTERVALS, sample_queues};
+use queues::QueueHandle;
+use worker::InFlight;
+use RedisPool;
+
+// the scrapes are served one by one, so a client that is slow or sends nothing can't hold up
+// the next ones longer than this many seconds
+const SCRAPE_TIMEOUT: u64 = 5;
+// bytes of the request line read at most
+const MAX_REQUEST_LINE: u64 = 8192;
+
+// what the `/metrics` endpoint reads from
+pub struct Exporter {
+    pub pool: RedisPool,
+    pub namespace: String,
+    pub queues: QueueHandle,
+    pub metrics: ExecutionTracker,
+    pub in_flight: InFlight,
+}
+
+impl Exporter {
+    // serve the scrapes one by one on a background thread
+    pub fn serve(self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| Error::from(format!("binding metrics endpoint failed: '{}'", e)))?;
+        info!("serving prometheus metrics on '{}'", addr);
+        thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.respond(stream) {
+                            warn!("serving metrics failed: '{}'", e);
+                        }
+                    }
+                    Err(e) => warn!("accepting metrics connection failed: '{}'", e),
+                }
+            })
+            .map_err(|e| Error::from(format!("spawning metrics thread failed: '{}'", e)))?;
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> Result<()> {
+        let timeout = Some(Duration::from_secs(SCRAPE_TIMEOUT));
+        stream.set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(|e| Error::from(e.to_string()))?;
+        let mut request_line = String::new();
+        BufReader::new((&stream).take(MAX_REQUEST_LINE)).read_line(&mut request_line)
+            .map_err(|e| Error::from(e.to_string()))?;
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+        let response = if path == "/metrics" {
+            let body = self.render()?;
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body)
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+        };
+        stream.write_all(response.as_bytes()).map_err(|e| Error::from(e.to_string()))?;
+        Ok(())
+    }
+
+    // the prometheus text exposition format
+    fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        let totals = self.metrics.totals();
+
+        out.push_str("# TYPE sidekiq_jobs_processed_total counter\n");
+        for (class, totals) in &totals {
+            let _ = writeln!(out,
+                             "sidekiq_jobs_processed_total{{class=\"{}\"}} {}",
+                             escape(class),
+                             totals.processed);
+        }
+        out.push_str("# TYPE sidekiq_jobs_failed_total counter\n");
+        for (class, totals) in &totals {
+            let _ = writeln!(out,
+                             "sidekiq_jobs_failed_total{{class=\"{}\"}} {}",
+                             escape(class),
+                             totals.failed);
+        }
+
+        // of the successful jobs, like the sidekiq metrics
+        out.push_str("# TYPE sidekiq_job_duration_seconds histogram\n");
+        for (class, totals) in &totals {
+            let class = escape(class);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKET_INTERVALS.iter().zip(totals.buckets.iter()) {
+                cumulative += count;
+                let le = if *bound == u64::MAX {
+                    "+Inf".to_string()
+                } else {
+                    (*bound as f64 / 1000f64).to_string()
+                };
+                let _ = writeln!(out,
+                                 "sidekiq_job_duration_seconds_bucket{{class=\"{}\",le=\"{}\"}} {}",
+                                 class,
+                                 le,
+                                 cumulative);
+            }
+            let _ = writeln!(out,
+                             "sidekiq_job_duration_seconds_sum{{class=\"{}\"}} {}",
+                             class,
+                             totals.duration_ms as f64 / 1000f64);
+            let _ = writeln!(out,
+                             "sidekiq_job_duration_seconds_count{{class=\"{}\"}} {}",
+                             class,
+                             cumulative);
+        }
+
+        out.push_str("# TYPE sidekiq_jobs_in_flight gauge\n");
+        let _ = writeln!(out,
+                         "sidekiq_jobs_in_flight {}",
+                         self.in_flight.lock().unwrap().len());
+
+        let stats = sample_queues(&mut *self.pool.get()?, &self.namespace, &self.queues.names())?;
+        out.push_str("# TYPE sidekiq_queue_size gauge\n");
+        for stat in &stats {
+            let _ = writeln!(out,
+                             "sidekiq_queue_size{{queue=\"{}\"}} {}",
+                             escape(&stat.queue),
+                             stat.size);
+        }
+        out.push_str("# TYPE sidekiq_queue_latency_seconds gauge\n");
+        for stat in &stats {
+            let _ = writeln!(out,
+                             "sidekiq_queue_latency_seconds{{queue=\"{}\"}} {}",
+                             escape(&stat.queue),
+                             stat.latency);
+        }
+        Ok(out)
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
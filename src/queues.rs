@@ -0,0 +1,121 @@
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use errors::*;
+use utils::with_namespace;
+use cluster::scan_keys;
+use RedisPool;
+
+#[derive(Default)]
+struct QueueList {
+    names: Vec<String>,
+    weights: Vec<f64>,
+}
+
+// the queues a server fetches from, shared with its workers so they can be changed while
+// the server is running, workers pick the changes up within a second
+#[derive(Clone, Default)]
+pub struct QueueHandle {
+    inner: Arc<RwLock<QueueList>>,
+}
+
+impl QueueHandle {
+    pub fn new() -> QueueHandle {
+        QueueHandle::default()
+    }
+
+    // add a queue, or change its weight if it is already there
+    pub fn add(&self, name: &str, weight: usize) {
+        let mut list = self.inner.write().unwrap();
+        if let Some(idx) = list.names.iter().position(|n| n == name) {
+            list.weights[idx] = weight as f64;
+        } else {
+            list.names.push(name.into());
+            list.weights.push(weight as f64);
+        }
+    }
+
+    // returns false if there was no such queue, jobs already fetched from it still run
+    pub fn remove(&self, name: &str) -> bool {
+        let mut list = self.inner.write().unwrap();
+        if let Some(idx) = list.names.iter().position(|n| n == name) {
+            list.names.remove(idx);
+            list.weights.remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.inner.read().unwrap().names.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().names.is_empty()
+    }
+
+    // the names and their weights, in the order they are added
+    pub fn snapshot(&self) -> (Vec<String>, Vec<f64>) {
+        let list = self.inner.read().unwrap();
+        (list.names.clone(), list.weights.clone())
+    }
+}
+
+// rescan at most this often, SCAN walks the whole keyspace
+const DISCOVERY_INTERVAL: u64 = 10;
+
+// adds the queues whose name match a pattern as they appear in redis
+pub struct QueueDiscovery {
+    pool: RedisPool,
+    namespace: String,
+    patterns: Vec<(String, usize)>,
+    queues: QueueHandle,
+    last_scan: Option<Instant>,
+}
+
+impl QueueDiscovery {
+    pub fn new(pool: RedisPool,
+               namespace: &str,
+               patterns: Vec<(String, usize)>,
+               queues: QueueHandle)
+               -> QueueDiscovery {
+        QueueDiscovery {
+            pool,
+            namespace: namespace.into(),
+            patterns,
+            queues,
+            last_scan: None,
+        }
+    }
+
+    // returns the number of queues added, discovered queues are never removed
+    pub fn discover(&mut self) -> Result<usize> {
+        let recent = match self.last_scan {
+            Some(t) => t.elapsed().as_secs() < DISCOVERY_INTERVAL,
+            None => false,
+        };
+        if self.patterns.is_empty() || recent {
+            return Ok(0);
+        }
+        self.last_scan = Some(Instant::now());
+        let mut conn = self.pool.get()?;
+        let prefix = with_namespace(&self.namespace, "queue:");
+        let mut known = self.queues.names();
+        let mut count = 0;
+        for &(ref pattern, weight) in &self.patterns {
+            for key in scan_keys(&mut conn, &(prefix.clone() + pattern))? {
+                let name = key[prefix.len()..].to_string();
+                // skip the working queues of reliable fetch
+                if name.contains('|') || known.contains(&name) {
+                    continue;
+                }
+                info!("discovered queue '{}' matching '{}'", name, pattern);
+                self.queues.add(&name, weight);
+                known.push(name);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
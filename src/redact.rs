@@ -0,0 +1,133 @@
+// arguments kept out of the logs, the `workers` hash and the error reports, the handlers
+// still get them. it's for the whole process, like the logger, so the client logs filter
+// them too
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::RwLock;
+
+use serde_json::Value as JValue;
+
+use job::Job;
+
+pub const FILTERED: &str = "[FILTERED]";
+
+struct Sensitive {
+    // by class, the wrapped class of an active job
+    positions: BTreeMap<String, BTreeSet<usize>>,
+    // at any depth of any argument
+    keys: BTreeSet<String>,
+}
+
+static SENSITIVE: RwLock<Sensitive> = RwLock::new(Sensitive {
+    positions: BTreeMap::new(),
+    keys: BTreeSet::new(),
+});
+
+// the arguments at these positions of the jobs of this class
+pub fn sensitive_args(class: &str, positions: &[usize]) {
+    SENSITIVE.write()
+        .unwrap()
+        .positions
+        .entry(class.into())
+        .or_default()
+        .extend(positions);
+}
+
+// the values of this key in any hash of the arguments
+pub fn sensitive_key(key: &str) {
+    SENSITIVE.write().unwrap().keys.insert(key.into());
+}
+
+pub fn filter_args(class: &str, args: &[JValue]) -> Vec<JValue> {
+    let sensitive = SENSITIVE.read().unwrap();
+    sensitive.filter_args(class, args)
+}
+
+// a copy of the job to show, with its sensitive arguments filtered
+pub fn filtered(job: &Job) -> Job {
+    let mut job = job.clone();
+    let sensitive = SENSITIVE.read().unwrap();
+    if job.is_active_job() {
+        let class = job.handler_class().to_string();
+        if let Some(&mut JValue::Object(ref mut envelope)) = job.args.first_mut() {
+            if let Some(&mut JValue::Array(ref mut arguments)) = envelope.get_mut("arguments") {
+                *arguments = sensitive.filter_args(&class, arguments);
+            }
+        }
+        job.args = sensitive.filter_args("", &job.args);
+    } else {
+        job.args = sensitive.filter_args(&job.class, &job.args);
+    }
+    job
+}
+
+impl Sensitive {
+    fn filter_args(&self, class: &str, args: &[JValue]) -> Vec<JValue> {
+        let positions = self.positions.get(class);
+        args.iter()
+            .enumerate()
+            .map(|(i, arg)| if positions.is_some_and(|positions| positions.contains(&i)) {
+                JValue::String(FILTERED.into())
+            } else {
+                self.filter_keys(arg)
+            })
+            .collect()
+    }
+
+    fn filter_keys(&self, value: &JValue) -> JValue {
+        match *value {
+            JValue::Array(ref values) => {
+                JValue::Array(values.iter().map(|v| self.filter_keys(v)).collect())
+            }
+            JValue::Object(ref map) => {
+                JValue::Object(map.iter()
+                    .map(|(k, v)| if self.keys.contains(k) {
+                        (k.clone(), JValue::String(FILTERED.into()))
+                    } else {
+                        (k.clone(), self.filter_keys(v))
+                    })
+                    .collect())
+            }
+            ref value => value.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use job::ACTIVE_JOB_WRAPPERS;
+
+    // the settings are for the whole process, each test has its own class and key
+    #[test]
+    fn filters_positions_of_the_class() {
+        sensitive_args("RedactPositions", &[1]);
+        let args = [json!("bob"), json!("secret")];
+        assert_eq!(filter_args("RedactPositions", &args), vec![json!("bob"), json!(FILTERED)]);
+        assert_eq!(filter_args("RedactOther", &args), args.to_vec());
+    }
+
+    #[test]
+    fn filters_keys_at_any_depth() {
+        sensitive_key("redact_token");
+        let args = [json!({"user": "bob", "auth": [{"redact_token": "abc"}]})];
+        assert_eq!(filter_args("RedactKeys", &args),
+                   vec![json!({"user": "bob", "auth": [{"redact_token": FILTERED}]})]);
+    }
+
+    #[test]
+    fn filters_copies_of_jobs() {
+        sensitive_args("RedactJob", &[0]);
+        let job = Job::new("RedactJob", vec![json!("secret"), json!(1)], "default");
+        assert_eq!(filtered(&job).args, vec![json!(FILTERED), json!(1)]);
+        assert_eq!(job.args[0], json!("secret"));
+    }
+
+    #[test]
+    fn filters_the_arguments_of_active_jobs() {
+        sensitive_args("RedactMailer", &[0]);
+        let envelope = json!({"job_class": "RedactMailer", "arguments": ["secret", 1]});
+        let mut job = Job::new(ACTIVE_JOB_WRAPPERS[0], vec![envelope], "default");
+        job.extra.insert("wrapped".into(), json!("RedactMailer"));
+        assert_eq!(filtered(&job).args[0]["arguments"], json!([FILTERED, 1]));
+    }
+}
@@ -0,0 +1,103 @@
+use std::borrow::Cow;
+
+use sentry::{self, protocol};
+use sentry::protocol::{Event, Exception, Level};
+
+use serde_json::Value as JValue;
+
+use RedisPool;
+use errors::Error;
+use job::Job;
+use redact::filtered;
+use middleware::{MiddleWare, MiddleWareResult, NextFunc};
+
+// reports the errors and panics of handlers to sentry, initialized with `sentry::init`,
+// attach it after `retry_middleware` to report every failure and not only the dead jobs
+#[derive(Clone, Default)]
+pub struct SentryMiddleware {
+    redacted: Vec<String>,
+}
+
+impl SentryMiddleware {
+    pub fn new() -> SentryMiddleware {
+        SentryMiddleware::default()
+    }
+
+    // replace the values of this key in the job arguments, at any depth
+    pub fn redact(mut self, key: &str) -> SentryMiddleware {
+        self.redacted.push(key.into());
+        self
+    }
+
+    fn capture(&self, job: &Job, error: &Error) {
+        let retry_count = job.retry_info.as_ref().map(|info| info.retry_count).unwrap_or(0);
+        let mut event = Event {
+            level: Level::Error,
+            message: Some(error.to_string()),
+            transaction: Some(job.class.clone()),
+            logger: Some("sidekiq".into()),
+            exception: vec![Exception {
+                                ty: error.class_name().into(),
+                                value: Some(error.to_string()),
+                                module: Some(job.class.clone()),
+                                ..Default::default()
+                            }]
+                .into(),
+            fingerprint: Cow::Owned(vec![Cow::Owned(job.class.clone()),
+                                         Cow::Borrowed(error.class_name())]),
+            ..Default::default()
+        };
+        event.tags.insert("class".into(), job.class.clone());
+        event.tags.insert("queue".into(), job.queue.clone());
+        event.tags.insert("jid".into(), job.jid.clone());
+        event.tags.insert("retry_count".into(), retry_count.to_string());
+        let args = filtered(job).args.iter().map(|arg| self.convert(arg)).collect();
+        event.extra.insert("args".into(), protocol::Value::Array(args));
+        event.extra.insert("enqueued_at".into(), job.enqueued_at.to_rfc3339().into());
+        sentry::capture_event(event);
+    }
+
+    // sentry has its own serde_json
+    fn convert(&self, value: &JValue) -> protocol::Value {
+        match *value {
+            JValue::Null => protocol::Value::Null,
+            JValue::Bool(b) => b.into(),
+            JValue::Number(ref n) => {
+                if let Some(n) = n.as_u64() {
+                    n.into()
+                } else if let Some(n) = n.as_i64() {
+                    n.into()
+                } else {
+                    n.as_f64().unwrap_or(0.0).into()
+                }
+            }
+            JValue::String(ref s) => s.clone().into(),
+            JValue::Array(ref values) => {
+                protocol::Value::Array(values.iter().map(|v| self.convert(v)).collect())
+            }
+            JValue::Object(ref map) => {
+                protocol::Value::Object(map.iter()
+                    .map(|(k, v)| if self.redacted.contains(k) {
+                        (k.clone(), "[REDACTED]".into())
+                    } else {
+                        (k.clone(), self.convert(v))
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl MiddleWare for SentryMiddleware {
+    fn handle(&mut self, job: &mut Job, redis: RedisPool, next: NextFunc) -> MiddleWareResult {
+        let r = next(job, redis);
+        if let Err(ref e) = r {
+            self.capture(job, e);
+        }
+        r
+    }
+
+    fn cloned(&mut self) -> Box<dyn MiddleWare> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,36 @@
+use redis::Commands;
+use serde::Deserialize;
+use serde_json::{from_str, Value as JValue};
+
+use errors::*;
+use utils::with_namespace;
+use RedisConnection;
+
+// how long the value returned with `Returned` is kept by default, see
+// `SidekiqServer::result_ttl`
+pub const RESULT_TTL: usize = 24 * 60 * 60;
+
+pub fn result_key(namespace: &str, jid: &str) -> String {
+    with_namespace(namespace, &("result:".to_string() + jid))
+}
+
+pub fn store_result(conn: &mut RedisConnection,
+                    namespace: &str,
+                    jid: &str,
+                    value: &JValue,
+                    ttl: usize)
+                    -> Result<()> {
+    let _: () = conn.set_ex(result_key(namespace, jid), value.to_string(), ttl as u64)?;
+    Ok(())
+}
+
+pub fn get_result<T: Deserialize>(conn: &mut RedisConnection,
+                                  namespace: &str,
+                                  jid: &str)
+                                  -> Result<Option<T>> {
+    let value: Option<String> = conn.get(result_key(namespace, jid))?;
+    match value {
+        Some(value) => Ok(Some(from_str(&value)?)),
+        None => Ok(None),
+    }
+}
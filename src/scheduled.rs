@@ -0,0 +1,111 @@
+use redis::{Commands, Pipeline, Script};
+use serde_json::{from_str, to_string, Value as JValue};
+use chrono::UTC;
+
+use errors::*;
+use utils::with_namespace;
+use RedisPool;
+
+// the job is only pushed by the process whose ZREM removed it, and it can't be lost between
+// the two, so ruby and rust pollers sharing the sets never enqueue it twice
+const ENQUEUE_SCRIPT: &str = r#"
+if redis.call('zrem', KEYS[1], ARGV[1]) == 1 then
+    redis.call('sadd', KEYS[2], ARGV[3])
+    redis.call('lpush', KEYS[3], ARGV[2])
+    return 1
+else
+    return 0
+end
+"#;
+
+pub struct ScheduledPoller {
+    pool: RedisPool,
+    namespace: String,
+}
+
+impl ScheduledPoller {
+    pub fn new(pool: RedisPool, namespace: &str) -> ScheduledPoller {
+        ScheduledPoller {
+            pool,
+            namespace: namespace.into(),
+        }
+    }
+
+    // move every due job in `retry` and `schedule` onto its queue, returns how many jobs were enqueued
+    pub fn enqueue_jobs(&self) -> Result<usize> {
+        let mut count = 0;
+        for sorted_set in &["retry", "schedule"] {
+            count += self.enqueue_due(sorted_set)?;
+        }
+        Ok(count)
+    }
+
+    fn enqueue_due(&self, sorted_set: &str) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let key = with_namespace(&self.namespace, sorted_set);
+        let now = UTC::now();
+        let now = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64;
+        let mut count = 0;
+        loop {
+            let jobs: Vec<String> = conn.zrangebyscore_limit(&key, "-inf", now, 0, 1)?;
+            let job = match jobs.into_iter().next() {
+                Some(job) => job,
+                None => break,
+            };
+            let (queue, payload) = match prepare(&job) {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    error!("cannot enqueue job '{}' from '{}': '{}'", job, sorted_set, e);
+                    let _: () = conn.zrem(&key, &job)?;
+                    continue;
+                }
+            };
+            let queues = with_namespace(&self.namespace, "queues");
+            let queue_name = with_namespace(&self.namespace, &("queue:".to_string() + &queue));
+            // someone else may have taken the job since ZRANGEBYSCORE
+            let enqueued: usize = if conn.is_cluster() {
+                // the keys are on different slots, the job is lost if the process dies
+                // between the two, like ruby sidekiq's poller before the script
+                let removed: usize = conn.zrem(&key, &job)?;
+                if removed == 1 {
+                    let _: () = Pipeline::new()
+                        .sadd(&queues, &queue)
+                        .lpush(&queue_name, payload)
+                        .query(&mut *conn)?;
+                }
+                removed
+            } else {
+                Script::new(ENQUEUE_SCRIPT)
+                    .key(&key)
+                    .key(&queues)
+                    .key(&queue_name)
+                    .arg(&job)
+                    .arg(payload)
+                    .arg(&queue)
+                    .invoke(&mut *conn)?
+            };
+            if enqueued == 1 {
+                debug!("enqueued scheduled job from '{}' to '{}'", sorted_set, queue);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+// the queue of the job and its payload with the time it's enqueued at
+pub fn prepare(payload: &str) -> Result<(String, String)> {
+    let mut job: JValue = from_str(payload)?;
+    let queue = {
+        let obj = job.as_object_mut().ok_or("job is not an object")?;
+        let queue = obj.get("queue")
+            .and_then(|q| q.as_str())
+            .map(|q| q.to_string())
+            .ok_or("job has no queue")?;
+        let now = UTC::now();
+        obj.insert("enqueued_at".into(),
+                   json!(now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64));
+        queue
+    };
+    Ok((queue, to_string(&job)?))
+}
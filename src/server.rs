@@ -1,35 +1,79 @@
+use std::any::Any;
+use std::cmp;
 use std::collections::BTreeMap;
-use std::time::Duration;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use redis::{Pipeline, PipelineCommands};
-use r2d2::{Pool, Config};
-use r2d2_redis::RedisConnectionManager;
+use redis::{Commands, Pipeline};
+use r2d2::Pool;
 
 use rand::Rng;
 
 use threadpool::ThreadPool;
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
 
-use chan::{sync, after, tick, Receiver, Sender};
-use chan_signal::{Signal as SysSignal, notify};
+use crossbeam_channel::{after, bounded, tick, unbounded, Receiver, Sender};
 
-use libc::getpid;
 
 use chrono::UTC;
 
 use serde_json::to_string;
 
-use worker::SidekiqWorker;
+use worker::{SidekiqWorker, WorkerContext, WorkerHooks, InFlight, WorkState, AsyncLane,
+             AsyncTasks};
+use fetcher::{Fetcher, UnitOfWork, WeightedFetcher};
+use queues::{QueueHandle, QueueDiscovery};
+use metrics::{ExecutionTracker, sample_queues};
+use sink::MetricsSink;
+#[cfg(feature = "prometheus")]
+use prometheus::Exporter;
+use periodic::{CronSchedule, Periodic, PeriodicScheduler};
 use errors::*;
-use utils::rust_gethostname;
-use middleware::MiddleWare;
-use job_handler::JobHandler;
-use RedisPool;
+use utils::{connection_manager, detached_pool, rust_rss_kb, with_namespace, RedisConnectionManager,
+            Semaphore};
+use platform;
+use data::AppData;
+use cancel::Cancellations;
+use backoff::Backoff;
+use autoscale::{Autoscaler, Slots};
+use memory::MemoryBackend;
+use backend::{Backend, FetchRequest, Heartbeat, RedisBackend};
+use codec::decode_job;
+use results::RESULT_TTL;
+use middleware::{MiddleWare, AsyncMiddleWare, DEAD_MAX_JOBS, DEAD_TIMEOUT};
+use job_handler::{JobHandler, DeathHandler, ErrorHandler, Worker, WorkerClass, TypedHandler,
+                  AsyncJobHandler, ContextHandler, WithContext, UnknownClass};
+use job::Job;
+use client::SidekiqClient;
+use {AsyncRedis, RedisPool};
+
+// same as ruby sidekiq, 5 years
+const STAT_TTL: usize = 5 * 365 * 24 * 60 * 60;
+
+// seconds between two reaps of stale processes
+const REAP_INTERVAL: u64 = 60;
+
+// exit code of a process stopped before all of its running jobs were done
+pub const FORCE_QUIT_EXIT_CODE: i32 = 2;
+// exit code of a process stopped by the memory watchdog, so a supervisor knows to restart it
+pub const MEMORY_EXIT_CODE: i32 = 3;
+// exit code of `cli::run` given invalid options, `EX_USAGE` of sysexits.h, the swarm doesn't
+// restart a child exiting with it
+pub const USAGE_EXIT_CODE: i32 = 64;
+
+// the capsule of the queues and concurrency the server is made with
+const DEFAULT_CAPSULE: &str = "default";
 
 #[derive(Debug)]
 pub enum Signal {
     Complete(String, usize),
     Fail(String, usize),
+    // a worker starts or is done running a job
     Acquire(String),
+    Release(String),
     Terminated(String),
 }
 
@@ -37,117 +81,775 @@ pub enum Operation {
     Terminate,
 }
 
+// moments of the server life hooks can be attached to, like ruby sidekiq's `config.on`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    // workers are started
+    Startup,
+    // no more jobs are fetched
+    Quiet,
+    // the server begins to stop, before waiting for the running jobs
+    Shutdown,
+    // after each heartbeat
+    Heartbeat,
+}
+
+// controls a server from other threads, get it with `SidekiqServer::handle` before starting
+#[derive(Clone)]
+pub struct ServerHandle {
+    quiet: Arc<AtomicBool>,
+    stop: Sender<()>,
+    exit: Arc<(Mutex<Option<i32>>, Condvar)>,
+    cancellations: Cancellations,
+}
+
+impl ServerHandle {
+    // stop fetching new jobs, the running ones still finish, there is no way back except
+    // restarting the server
+    pub fn quiet(&self) {
+        if !self.quiet.swap(true, Ordering::SeqCst) {
+            info!("quieting, no more jobs will be fetched");
+        }
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::SeqCst)
+    }
+
+    // stop the server like a TERM signal would, returns without waiting for it
+    pub fn stop(&self) {
+        let _ = self.stop.send(());
+    }
+
+    // cancel the token of a job running on this server, false if it isn't running here,
+    // `SidekiqClient::cancel` reaches the job on any server
+    pub fn cancel(&self, jid: &str) -> bool {
+        self.cancellations.cancel(jid)
+    }
+
+    // block until `start` returns, with the exit code it returned
+    pub fn join(&self) -> i32 {
+        let (ref lock, ref cvar) = *self.exit;
+        let mut exit = lock.lock().unwrap();
+        loop {
+            if let Some(code) = *exit {
+                return code;
+            }
+            exit = cvar.wait(exit).unwrap();
+        }
+    }
+}
+
+// workers of their own fetching only from their own queues, like the capsules of sidekiq 7
+#[derive(Clone)]
+struct Capsule {
+    name: String,
+    queues: QueueHandle,
+    concurrency: usize,
+    slots: Slots,
+}
+
 pub struct SidekiqServer<'a> {
     redispool: RedisPool,
+    // only for the fetchers, so blocking on the queues doesn't hold up the short commands
+    fetch_pool: RedisPool,
+    // used instead of redis when set
+    backend: Option<Arc<dyn Backend>>,
     threadpool: ThreadPool,
+    // prefix of every key, read by `client`, `serve_prometheus` and `start`, so set it
+    // before them, `SidekiqServerBuilder::namespace` does
     pub namespace: String,
     job_handlers: BTreeMap<String, Box<JobHandler + 'a>>,
+    fallback_handler: Option<Box<dyn JobHandler + 'a>>,
+    handler_limits: BTreeMap<String, Semaphore>,
+    handler_timeouts: BTreeMap<String, usize>,
     middlewares: Vec<Box<MiddleWare + 'a>>,
-    queues: Vec<String>,
-    weights: Vec<f64>,
+    death_handlers: Vec<Box<dyn DeathHandler + 'a>>,
+    error_handlers: Vec<Box<dyn ErrorHandler + 'a>>,
+    async_handlers: BTreeMap<String, Box<dyn AsyncJobHandler>>,
+    async_middlewares: Vec<Arc<dyn AsyncMiddleWare>>,
+    // started when an async handler is attached
+    executor: Option<Runtime>,
+    // what `async_redis` connects to
+    redis_manager: Option<RedisConnectionManager>,
+    async_redis: Option<AsyncRedis>,
+    // made with `async_concurrency` permits once the workers are
+    async_budget: Option<Arc<::tokio::sync::Semaphore>>,
+    async_tasks: AsyncTasks,
+    data: AppData,
+    fetcher: Box<dyn Fetcher + 'a>,
+    in_flight: InFlight,
+    work_state: WorkState,
+    cancellations: Cancellations,
+    backoff: Backoff,
+    slots: Slots,
+    autoscaler: Option<Autoscaler>,
+    // workers stop fetching new jobs once set
+    quiet: Arc<AtomicBool>,
+    stop: (Sender<()>, Receiver<()>),
+    exit: Arc<(Mutex<Option<i32>>, Condvar)>,
+    lifecycle_hooks: Vec<(LifecycleEvent, Box<dyn FnMut() + Send + 'a>)>,
+    periodic_jobs: Vec<Periodic>,
+    queue_patterns: Vec<(String, usize)>,
+    queue_limits: BTreeMap<String, Semaphore>,
+    queues: QueueHandle,
+    // besides the default one
+    capsules: Vec<Capsule>,
     started_at: f64,
     rs: String,
     pid: usize,
-    signal_chan: Receiver<SysSignal>,
+    signal_chan: Receiver<&'static str>,
     worker_info: BTreeMap<String, bool>, // busy?
+    worker_capsules: BTreeMap<String, String>,
     concurrency: usize,
     pub force_quite_timeout: usize,
+    // seconds a worker blocks waiting for a job before checking for operations again
+    pub fetch_timeout: usize,
+    // async jobs running at once in the process, on top of the jobs of the workers, 100 by
+    // default. a worker fetching one more waits until one is done
+    pub async_concurrency: usize,
+    // seconds between two heartbeats, which also poll the scheduled and periodic jobs
+    pub heartbeat_interval: usize,
+    // seconds a job may run before failing with a `Timeout` error, unless its class has
+    // its own timeout, see `set_handler_timeout`
+    pub job_timeout: Option<usize>,
+    // what becomes of the jobs of a class without handler, when there is no fallback
+    // handler, `Fail` by default
+    pub unknown_class: UnknownClass,
+    // seconds the value of a job returning `Returned` is kept, a day by default
+    pub result_ttl: usize,
+    // log a warning for the fetched jobs whose payload is larger than this many bytes, none by
+    // default, see `SidekiqClient::reject_payload_over` to keep them out
+    pub payload_warn_size: Option<usize>,
+    // every minute, drop from the `processes` set the processes whose heartbeat expired,
+    // left there by crashed processes of any language
+    pub reap_stale_processes: bool,
+    // seconds the dated `stat:processed:<date>` and `stat:failed:<date>` keys are kept
+    pub stat_ttl: usize,
+    // on start, expire the dated stat keys written before they had a ttl
+    pub prune_stats: bool,
+    // record the execution time of each job class for the sidekiq 7 metrics tab
+    pub job_metrics: bool,
+    metrics: ExecutionTracker,
+    // processed and failed jobs not written yet
+    pending_stats: (usize, usize),
+    sinks: Vec<Box<dyn MetricsSink + 'a>>,
+    // seconds between two samples of the size and latency of every queue, none by default
+    pub queue_stats_interval: Option<usize>,
+    // checked on each heartbeat, once the resident memory of the process is over this many
+    // kilobytes it quiets, waits for the running jobs like on TERM and exits with
+    // `MEMORY_EXIT_CODE`, none by default, only known on linux
+    pub max_rss_kb: Option<usize>,
+    // the dead set keeps this many jobs at most, for this many seconds, 10000 and 180 days
+    // by default like ruby sidekiq. only for the redis backend
+    pub dead_max_jobs: usize,
+    pub dead_timeout: u64,
 }
 
 impl<'a> SidekiqServer<'a> {
     // Interfaces to be exposed
 
     pub fn new(redis: &str, concurrency: usize) -> Result<Self> {
-        let signal = notify(&[SysSignal::INT, SysSignal::USR1]); // should be here to set proper signal mask to all threads
+        let pool = Pool::builder()
+            .max_size(concurrency as u32 + 3) // dunno why, it corrupt for unable to get connection sometimes with concurrency + 1
+            .build(connection_manager(redis, None)?)?;
+        let fetch_pool = Pool::builder()
+            .max_size(concurrency as u32 + 1)
+            .build(connection_manager(redis, None)?)?;
+        let mut server = SidekiqServer::with_pools(pool, fetch_pool, concurrency)?;
+        server.use_async_redis(connection_manager(redis, None)?);
+        Ok(server)
+    }
+
+    // share a pool with the rest of the application, the workers fetch from it too, so it
+    // needs more than `concurrency` connections
+    pub fn with_pool(pool: RedisPool, concurrency: usize) -> Result<Self> {
+        SidekiqServer::with_pools(pool.clone(), pool, concurrency)
+    }
+
+    // every worker holds a connection of `fetch_pool` while blocking on the queues, so it
+    // needs more than `concurrency` connections, the heartbeat, bookkeeping and handlers use
+    // `pool`
+    pub fn with_pools(pool: RedisPool, fetch_pool: RedisPool, concurrency: usize) -> Result<Self> {
+        if (fetch_pool.max_size() as usize) <= concurrency {
+            warn!("the redis pool of {} connections is small for a concurrency of {}",
+                  fetch_pool.max_size(),
+                  concurrency);
+        }
+        let signal = platform::listen_signals()?;
         let now = UTC::now();
-        let config = Config::builder()
-            .pool_size(concurrency as u32 + 3) // dunno why, it corrupt for unable to get connection sometimes with concurrency + 1
-            .build();
-        let manager = try!(RedisConnectionManager::new(redis));
-        let pool = try!(Pool::new(config, manager));
         Ok(SidekiqServer {
             redispool: pool,
+            fetch_pool,
+            backend: None,
             threadpool: ThreadPool::new_with_name("worker".into(), concurrency),
             namespace: String::new(),
             job_handlers: BTreeMap::new(),
-            queues: vec![],
-            weights: vec![],
+            fallback_handler: None,
+            unknown_class: UnknownClass::Fail,
+            result_ttl: RESULT_TTL,
+            payload_warn_size: None,
+            handler_limits: BTreeMap::new(),
+            handler_timeouts: BTreeMap::new(),
+            queues: QueueHandle::new(),
+            capsules: vec![],
             started_at: now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64,
-            pid: unsafe { getpid() } as usize,
+            pid: platform::pid(),
             worker_info: BTreeMap::new(),
+            worker_capsules: BTreeMap::new(),
             concurrency: concurrency,
             signal_chan: signal,
             force_quite_timeout: 10,
+            fetch_timeout: 2,
+            async_concurrency: 100,
+            heartbeat_interval: 2,
+            job_timeout: None,
+            reap_stale_processes: false,
+            stat_ttl: STAT_TTL,
+            prune_stats: false,
+            job_metrics: true,
+            metrics: ExecutionTracker::new(),
+            pending_stats: (0, 0),
+            sinks: vec![],
+            queue_stats_interval: None,
+            max_rss_kb: None,
+            dead_max_jobs: DEAD_MAX_JOBS,
+            dead_timeout: DEAD_TIMEOUT,
             middlewares: vec![],
+            death_handlers: vec![],
+            error_handlers: vec![],
+            async_handlers: BTreeMap::new(),
+            async_middlewares: vec![],
+            executor: None,
+            redis_manager: None,
+            async_redis: None,
+            async_budget: None,
+            async_tasks: Default::default(),
+            data: AppData::new(),
+            fetcher: Box::new(WeightedFetcher),
+            in_flight: Arc::new(Mutex::new(BTreeMap::new())),
+            work_state: Arc::new(Mutex::new(BTreeMap::new())),
+            cancellations: Cancellations::new(),
+            backoff: Backoff::new(),
+            slots: Slots::new(concurrency),
+            autoscaler: None,
+            quiet: Arc::new(AtomicBool::new(false)),
+            stop: unbounded(),
+            exit: Arc::new((Mutex::new(None), Condvar::new())),
+            lifecycle_hooks: vec![],
+            periodic_jobs: vec![],
+            queue_patterns: vec![],
+            queue_limits: BTreeMap::new(),
             // random itentity
             rs: ::rand::thread_rng().gen_ascii_chars().take(12).collect(),
         })
     }
 
+    // work from `backend` instead of redis, for tests, see `MemoryBackend`
+    pub fn in_memory(backend: MemoryBackend, concurrency: usize) -> Result<Self> {
+        SidekiqServer::with_backend(backend, concurrency)
+    }
+
+    // work through `backend`, the middlewares and handlers are given the redis behind it.
+    // without one there is no cancellation from other processes, queue pattern, periodic job,
+    // job metric, queue sample, autoscaling or reaping. the retry, unique and batch middlewares
+    // go through the backend, the others using redis fail
+    pub fn with_backend<B: Backend + 'static>(backend: B, concurrency: usize) -> Result<Self> {
+        let pool = match backend.redis() {
+            Some(pool) => pool.clone(),
+            None => detached_pool(concurrency as u32 + 3)?,
+        };
+        let mut server = SidekiqServer::with_pool(pool, concurrency)?;
+        server.backend = Some(Arc::new(backend));
+        Ok(server)
+    }
+
     pub fn new_queue(&mut self, name: &str, weight: usize) {
-        self.queues.push(name.into());
-        self.weights.push(weight as f64);
+        self.queues.add(name, weight);
+    }
+
+    // at most `limit` jobs of the queue run at the same time in this process, workers don't
+    // fetch from it while they are all taken
+    pub fn new_queue_with_limit(&mut self, name: &str, weight: usize, limit: usize) {
+        self.new_queue(name, weight);
+        self.queue_limits.insert(name.into(), Semaphore::new(limit));
+    }
+
+    // also fetch from the queues matching a redis glob pattern such as `tenant_*`, they are
+    // looked up again every few seconds while running
+    pub fn new_queue_pattern(&mut self, pattern: &str, weight: usize) {
+        self.queue_patterns.push((pattern.into(), weight));
+    }
+
+    // run `concurrency` more workers fetching only from the queues added to the returned
+    // handle, like the capsules of sidekiq 7, e.g. a single one for the jobs that must run one
+    // at a time. the queues and concurrency the server is made with are the `default` capsule,
+    // the autoscaler only scales that one. fails if the fetch pool has no connection for each
+    // worker, `SidekiqServerBuilder::capsule` sizes the pools for the capsules
+    pub fn new_capsule(&mut self, name: &str, concurrency: usize) -> Result<QueueHandle> {
+        if name == DEFAULT_CAPSULE || self.capsules.iter().any(|capsule| capsule.name == name) {
+            return Err(format!("capsule '{}' already exists", name).into());
+        }
+        let total = self.total_concurrency() + concurrency;
+        let size = self.fetch_pool.max_size() as usize;
+        // the other backends don't fetch with it
+        if self.backend.is_none() && size <= total {
+            return Err(format!("the fetch pool of {} connections is too small for the {} \
+                                workers of capsule '{}' and the others",
+                               size,
+                               concurrency,
+                               name)
+                .into());
+        }
+        let capsule = Capsule {
+            name: name.into(),
+            queues: QueueHandle::new(),
+            concurrency,
+            slots: Slots::new(concurrency),
+        };
+        let queues = capsule.queues.clone();
+        self.capsules.push(capsule);
+        self.threadpool.set_num_threads(total);
+        Ok(queues)
+    }
+
+    // add or remove queues of the running server through the returned handle
+    pub fn queue_handle(&self) -> QueueHandle {
+        self.queues.clone()
+    }
+
+    // workers stop fetching from a paused queue until it is unpaused, this is shared by
+    // every process through the sidekiq pro `paused` set
+    pub fn pause_queue(&self, name: &str) -> Result<()> {
+        self.backend().pause_queue(name)
+    }
+
+    pub fn unpause_queue(&self, name: &str) -> Result<()> {
+        self.backend().unpause_queue(name)
+    }
+
+    pub fn paused_queues(&self) -> Result<Vec<String>> {
+        self.backend().paused_queues()
     }
 
     pub fn attach_handler<T: JobHandler + 'a>(&mut self, name: &str, handle: T) {
         self.job_handlers.insert(name.into(), Box::new(handle));
     }
 
+    // like `attach_handler`, with the handler given a `JobContext`
+    pub fn attach_context_handler<T: ContextHandler + 'static>(&mut self, name: &str, handler: T) {
+        let redis = self.redispool.clone();
+        let data = self.data.clone();
+        let cancellations = self.cancellations.clone();
+        self.attach_handler(name,
+                            WithContext {
+                                handler: Box::new(handler),
+                                redis,
+                                data,
+                                cancellations,
+                            });
+    }
+
+    // share a value with the context handlers, found by its type with `JobContext::data`
+    pub fn add_data<T: Any + Send + Sync>(&mut self, value: T) {
+        self.data.insert(value);
+    }
+
+    // the futures of the handler run on a tokio runtime with a thread per cpu, shared by all
+    // the async handlers. the workers spawn them and go on fetching, up to `async_concurrency`
+    // of them run at once, wrapped by the async middlewares instead of the others
+    pub fn attach_async_handler<T: AsyncJobHandler + 'static>(&mut self,
+                                                              name: &str,
+                                                              handler: T)
+                                                              -> Result<()> {
+        self.async_redis()?;
+        self.async_handlers.insert(name.into(), Box::new(handler));
+        Ok(())
+    }
+
+    // the connection of the async jobs to redis, see `AsyncRedis`, for the async handlers to
+    // keep. it's connected on the runtime of the async handlers, started if need be
+    pub fn async_redis(&mut self) -> Result<AsyncRedis> {
+        if self.executor.is_none() {
+            let runtime = RuntimeBuilder::new_multi_thread()
+                .thread_name("async-job")
+                .enable_all()
+                .build()
+                .map_err(|e| Error::from(format!("starting the async runtime failed: '{}'", e)))?;
+            self.executor = Some(runtime);
+        }
+        if self.async_redis.is_none() {
+            let runtime = self.executor.as_ref().unwrap().handle();
+            self.async_redis = Some(match self.redis_manager {
+                Some(ref manager) => manager.async_redis(runtime)?,
+                None => AsyncRedis::Detached,
+            });
+        }
+        Ok(self.async_redis.clone().unwrap())
+    }
+
+    // what `async_redis` connects to, `new` and `SidekiqServerBuilder::build` give the redis of
+    // their url, there is none with a pool of your own
+    pub fn use_async_redis(&mut self, manager: RedisConnectionManager) {
+        self.redis_manager = Some(manager);
+        self.async_redis = None;
+    }
+
+    // wraps the jobs of the async handlers, `async_retry_middleware` retries them
+    pub fn attach_async_middleware<T: AsyncMiddleWare + 'static>(&mut self, middleware: T) {
+        self.async_middlewares.push(Arc::new(middleware));
+    }
+
+    // runs the jobs of the classes without handler, instead of `unknown_class`
+    pub fn attach_fallback_handler<T: JobHandler + 'a>(&mut self, handle: T) {
+        self.fallback_handler = Some(Box::new(handle));
+    }
+
+    // like `attach_handler`, with the arguments deserialized as `Worker::Args`
+    pub fn attach_worker<W: Worker + 'static>(&mut self, name: &str, worker: W) {
+        self.attach_handler(name, TypedHandler(worker));
+    }
+
+    // same as `attach_worker` with the class of the worker
+    pub fn register<W: WorkerClass + 'static>(&mut self, worker: W) {
+        self.attach_worker(W::class(), worker);
+    }
+
+    // at most `limit` jobs of the class run at the same time in this process, workers
+    // fetching more of them wait until one is done
+    pub fn attach_handler_with_limit<T: JobHandler + 'a>(&mut self,
+                                                         name: &str,
+                                                         handle: T,
+                                                         limit: usize) {
+        self.attach_handler(name, handle);
+        self.handler_limits.insert(name.into(), Semaphore::new(limit));
+    }
+
+    // jobs of the class running longer than `timeout` seconds fail with a `Timeout` error and
+    // go through the retries, the thread running them can't be stopped though and keeps
+    // running. their job is cancelled, a handler polling its `CancellationToken` can stop
+    pub fn set_handler_timeout(&mut self, name: &str, timeout: usize) {
+        self.handler_timeouts.insert(name.into(), timeout);
+    }
+
     pub fn attach_middleware<T: MiddleWare + 'a>(&mut self, factory: T) {
         self.middlewares.push(Box::new(factory));
     }
 
-    pub fn start(&mut self) {
+    pub fn attach_death_handler<T: DeathHandler + 'a>(&mut self, handler: T) {
+        self.death_handlers.push(Box::new(handler));
+    }
+
+    // called on every failure of a job, before and regardless of the middlewares
+    pub fn attach_error_handler<T: ErrorHandler + 'a>(&mut self, handler: T) {
+        self.error_handlers.push(Box::new(handler));
+    }
+
+    // run between `min` and `max` jobs at once depending on the queue latency and the cpu
+    // usage, `max` is at most the concurrency the server is made with
+    pub fn attach_autoscaler(&mut self, mut autoscaler: Autoscaler) {
+        if autoscaler.max > self.concurrency {
+            warn!("the autoscaler max of {} is over the concurrency, using {}",
+                  autoscaler.max,
+                  self.concurrency);
+            autoscaler.max = self.concurrency;
+        }
+        autoscaler.min = cmp::min(autoscaler.min, autoscaler.max);
+        self.slots.set_target(autoscaler.min);
+        self.autoscaler = Some(autoscaler);
+    }
+
+    // replace how workers pick a queue and fetch jobs from it, `WeightedFetcher` by default
+    pub fn attach_fetcher<T: Fetcher + 'a>(&mut self, fetcher: T) {
+        self.fetcher = Box::new(fetcher);
+    }
+
+    // run `hook` on the server thread whenever `event` happens
+    pub fn on<F: FnMut() + Send + 'a>(&mut self, event: LifecycleEvent, hook: F) {
+        self.lifecycle_hooks.push((event, Box::new(hook)));
+    }
+
+    // enqueue a copy of `job` on every tick of the cron expression, a single process of
+    // the cluster is elected through redis to do the enqueuing
+    pub fn periodic(&mut self, cron: &str, job: Job) -> Result<()> {
+        self.periodic_jobs.push(Periodic {
+            schedule: CronSchedule::parse(cron)?,
+            job,
+        });
+        Ok(())
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            quiet: self.quiet.clone(),
+            stop: self.stop.0.clone(),
+            exit: self.exit.clone(),
+            cancellations: self.cancellations.clone(),
+        }
+    }
+
+    pub fn quiet(&self) {
+        self.handle().quiet()
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::SeqCst)
+    }
+
+    // report the timing and outcome of every job, and the queue gauges when sampled
+    pub fn attach_metrics_sink<T: MetricsSink + 'a>(&mut self, sink: T) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    // serve the job counters and durations, the running jobs and the queue sizes for
+    // prometheus to scrape at `http://<addr>/metrics`
+    #[cfg(feature = "prometheus")]
+    pub fn serve_prometheus(&self, addr: &str) -> Result<()> {
+        Exporter {
+                pool: self.redispool.clone(),
+                namespace: self.namespace.clone(),
+                queues: self.queues.clone(),
+                metrics: self.metrics.clone(),
+                in_flight: self.in_flight.clone(),
+            }
+            .serve(addr)
+    }
+
+    pub fn client(&self) -> SidekiqClient {
+        if let Some(ref backend) = self.backend {
+            return SidekiqClient::with_shared_backend(self.redispool.clone(), backend.clone());
+        }
+        SidekiqClient::new(self.redispool.clone(), &self.namespace)
+    }
+
+    // fetch and run the jobs of `queues` in this thread until they're all empty, a queue after
+    // the other, with the handlers and middlewares of the server, which needn't be started.
+    // lets an integration test look at what the jobs did, returns how many were run
+    pub fn drain(&mut self, queues: &[&str]) -> Result<usize> {
+        let (tsx, _) = unbounded();
+        let (_, rox) = unbounded();
+        let mut drained = 0;
+        // again while the jobs push more
+        loop {
+            let mut run = 0;
+            for name in queues {
+                let capsule = Capsule {
+                    name: "drain".into(),
+                    queues: QueueHandle::new(),
+                    concurrency: 1,
+                    slots: Slots::new(1),
+                };
+                capsule.queues.add(name, 1);
+                let mut worker = self.new_worker(&capsule, tsx.clone(), rox.clone());
+                let (n, processed, failed) = worker.drain()?;
+                self.pending_stats.0 += processed;
+                self.pending_stats.1 += failed;
+                run += n;
+            }
+            self.flush_stats()?;
+            if run == 0 {
+                return Ok(drained);
+            }
+            drained += run;
+        }
+    }
+
+    // returns the exit code the process should exit with
+    pub fn start(&mut self) -> i32 {
+        let exit_code = self.run();
+        let (ref lock, ref cvar) = *self.exit;
+        *lock.lock().unwrap() = Some(exit_code);
+        cvar.notify_all();
+        exit_code
+    }
+
+    fn run(&mut self) -> i32 {
         info!("sidekiq-rs is running...");
-        if self.queues.len() == 0 {
+        let backend = self.backend();
+        // what only redis provides is left out without it
+        let has_redis = backend.redis().is_some();
+        let mut discovery = QueueDiscovery::new(self.redispool.clone(),
+                                                &self.namespace,
+                                                self.queue_patterns.clone(),
+                                                self.queues.clone());
+        if has_redis {
+            if let Err(e) = discovery.discover() {
+                error!("discover queues failed: '{}'", e);
+            }
+        }
+        if self.queues.is_empty() && self.queue_patterns.is_empty() &&
+           self.capsules.iter().all(|capsule| capsule.queues.is_empty()) {
             error!("queue is empty, exiting");
-            return;
+            return 1;
+        }
+        for capsule in &self.capsules {
+            if capsule.queues.is_empty() {
+                warn!("capsule '{}' has no queue, its workers stay idle", capsule.name);
+            }
+        }
+        let identity = self.identity();
+        let (queues, weights) = self.queues.snapshot();
+        let request = FetchRequest {
+            identity: &identity,
+            queues: &queues,
+            weights: &weights,
+            timeout: self.fetch_timeout(),
+        };
+        if let Err(e) = backend.startup(&mut *self.fetcher, &request) {
+            error!("fetcher startup failed: '{}'", e);
         }
-        let (tsx, rsx) = sync(self.concurrency + 10);
-        let (tox, rox) = sync(self.concurrency + 10);
+        if self.prune_stats {
+            match backend.prune_stats(self.stat_ttl) {
+                Ok(n) if n > 0 => info!("pruned {} outdated stat keys", n),
+                Ok(_) => {}
+                Err(e) => error!("prune stats failed: '{}'", e),
+            }
+        }
+
+        let (tsx, rsx) = bounded(self.total_concurrency() + 10);
+        let (tox, rox) = bounded(self.total_concurrency() + 10);
         let signal = self.signal_chan.clone();
+        let stop = self.stop.1.clone();
+        let mut periodic = PeriodicScheduler::new(self.redispool.clone(),
+                                                  &self.namespace,
+                                                  &self.identity(),
+                                                  self.periodic_jobs.drain(..).collect());
 
         // start worker threads
         self.launch_workers(tsx.clone(), rox.clone());
+        self.fire(LifecycleEvent::Startup);
 
+        let mut exit_code = 0;
         // controller loop
-        let (tox2, rsx2) = (tox.clone(), rsx.clone()); // rename channels cuz `chan_select!` will rename'em below
-        let clock = tick(Duration::from_secs(2)); // report to sidekiq every 5 secs
+        let clock = tick(Duration::from_secs(cmp::max(self.heartbeat_interval, 1) as u64));
+        let mut quiet = false;
+        let mut next_reap = Instant::now();
+        let mut next_queue_stats = Instant::now();
+        self.heartbeat();
         loop {
-            if let Err(e) = self.report_alive() {
-                error!("report alive failed: '{}'", e);
+            if !quiet && self.is_quiet() {
+                // quieted by a signal or from another thread through the handle
+                quiet = true;
+                self.fire(LifecycleEvent::Quiet);
             }
-            chan_select! {
-                signal.recv() -> signal => {
+            select! {
+                recv(signal) -> signal => {
                     match signal {
-                        Some(signal @ SysSignal::USR1) => {
-                            info!("{:?}: Terminating", signal);
-                            self.terminate_gracefully(tox2, rsx2);
+                        Ok(signal @ "USR1") | Ok(signal @ "INT") | Ok(signal @ "TERM") => {
+                            info!("{}: Terminating", signal);
+                            if !self.terminate(tox.clone(), rsx.clone()) {
+                                exit_code = FORCE_QUIT_EXIT_CODE;
+                            }
                             break;
                         }
-                        Some(signal @ SysSignal::INT) => {
-                            info!("{:?}: Force terminating", signal);                            
-                            self.terminate_forcely(tox2, rsx2);
-                            break;
+                        Ok(signal @ "TSTP") => {
+                            info!("{}: Quieting", signal);
+                            self.quiet();
                         }
-                        Some(_) => { unimplemented!() }
-                        None => { unimplemented!() }
+                        Ok(signal @ "TTIN") => {
+                            info!("{}: Dumping running jobs", signal);
+                            self.dump_in_flight();
+                        }
+                        Ok(signal) => warn!("unexpected signal {}", signal),
+                        Err(_) => unreachable!("signal forwarding thread exited"),
                     }
                 },
-                clock.recv() => {
+                recv(stop) -> _ => {
+                    info!("stop requested: Terminating");
+                    if !self.terminate(tox.clone(), rsx.clone()) {
+                        exit_code = FORCE_QUIT_EXIT_CODE;
+                    }
+                    break;
+                },
+                recv(clock) -> _ => {
                     debug!("server clock triggered");
+                    self.fire(LifecycleEvent::Heartbeat);
+                    if let (Some(limit), Some(rss)) = (self.max_rss_kb, rust_rss_kb()) {
+                        if rss > limit {
+                            warn!("using {} KB of memory, over {} KB: Terminating", rss, limit);
+                            self.quiet();
+                            // even with jobs left running, so the supervisor can tell it
+                            // from a forced quit
+                            self.terminate(tox.clone(), rsx.clone());
+                            exit_code = MEMORY_EXIT_CODE;
+                            break;
+                        }
+                    }
+                    if !self.heartbeat() {
+                        // redis is down, the signals sent through it included
+                        continue;
+                    }
+                    if let Err(e) = self.flush_stats() {
+                        error!("flush stats failed: '{}'", e);
+                    }
+                    if let Err(e) = self.flush_metrics() {
+                        error!("flush job metrics failed: '{}'", e);
+                    }
+                    if let Err(e) = backend.enqueue_scheduled() {
+                        error!("enqueue scheduled jobs failed: '{}'", e);
+                    }
+                    if has_redis {
+                        if let Err(e) = periodic.enqueue_jobs() {
+                            error!("enqueue periodic jobs failed: '{}'", e);
+                        }
+                        if let Err(e) = discovery.discover() {
+                            error!("discover queues failed: '{}'", e);
+                        }
+                        if let Err(e) = self.poll_cancellations() {
+                            error!("poll cancelled jobs failed: '{}'", e);
+                        }
+                        if let Some(interval) = self.queue_stats_interval {
+                            if Instant::now() >= next_queue_stats {
+                                next_queue_stats = Instant::now() +
+                                                   Duration::from_secs(interval as u64);
+                                if let Err(e) = self.report_queue_stats() {
+                                    error!("sample queues failed: '{}'", e);
+                                }
+                            }
+                        }
+                        if self.autoscaler.as_ref().is_some_and(|autoscaler| autoscaler.is_due()) {
+                            if let Err(e) = self.autoscale() {
+                                error!("autoscale failed: '{}'", e);
+                            }
+                        }
+                        if self.reap_stale_processes && Instant::now() >= next_reap {
+                            next_reap = Instant::now() + Duration::from_secs(REAP_INTERVAL);
+                            match self.reap() {
+                                Ok(n) if n > 0 => info!("reaped {} stale processes", n),
+                                Ok(_) => {}
+                                Err(e) => error!("reap stale processes failed: '{}'", e),
+                            }
+                        }
+                    }
+                    match backend.remote_signal(&self.identity()) {
+                        // sent by sidekiq web, which used USR1 for quiet before sidekiq 5
+                        Ok(Some(ref signal)) if signal == "TSTP" || signal == "USR1" => {
+                            info!("remote {}: Quieting", signal);
+                            self.quiet();
+                        }
+                        Ok(Some(ref signal)) if signal == "TERM" => {
+                            info!("remote {}: Terminating", signal);
+                            if !self.terminate(tox.clone(), rsx.clone()) {
+                                exit_code = FORCE_QUIT_EXIT_CODE;
+                            }
+                            break;
+                        }
+                        Ok(Some(signal)) => warn!("unknown remote signal '{}'", signal),
+                        Ok(None) => {}
+                        Err(e) => error!("read remote signal failed: '{}'", e),
+                    }
                 },
-                rsx.recv() -> sig => {
+                recv(rsx) -> sig => {
                     debug!("received signal {:?}", sig);
-                    if let Some(Err(e)) = sig.map(|s| self.deal_signal(s)) {
+                    if let Ok(Err(e)) = sig.map(|s| self.deal_signal(s)) {
                         error!("error when dealing signal: '{}'", e);
                     }
                     let worker_count = self.threadpool.active_count();
                     // relaunch workers if they died unexpectly
-                    if worker_count < self.concurrency {
+                    if worker_count < self.total_concurrency() {
                         warn!("worker down, restarting");
                         self.launch_workers(tsx.clone(), rox.clone());
-                    } else if worker_count > self.concurrency {
+                    } else if worker_count > self.total_concurrency() {
                         unreachable!("unreachable! worker_count can never larger than concurrency!")
                     }
                 }
@@ -155,56 +857,136 @@ impl<'a> SidekiqServer<'a> {
         }
 
         // exiting
+        if let Err(e) = self.flush_stats() {
+            error!("flush stats failed: '{}'", e);
+        }
+        if let Err(e) = backend.deregister(&self.identity()) {
+            error!("deregister process failed: '{}'", e);
+        }
         info!("sidekiq exited");
+        exit_code
     }
 
     // Worker start/terminate functions
 
 
     fn launch_workers(&mut self, tsx: Sender<Signal>, rox: Receiver<Operation>) {
-        while self.worker_info.len() < self.concurrency {
-            self.launch_worker(tsx.clone(), rox.clone());
+        for capsule in self.all_capsules() {
+            let running = self.worker_capsules
+                .values()
+                .filter(|name| **name == capsule.name)
+                .count();
+            for _ in running..capsule.concurrency {
+                self.launch_worker(&capsule, tsx.clone(), rox.clone());
+            }
         }
     }
 
 
-    fn launch_worker(&mut self, tsx: Sender<Signal>, rox: Receiver<Operation>) {
-        let worker = SidekiqWorker::new(&self.identity(),
-                                        self.redispool.clone(),
-                                        tsx,
-                                        rox,
-                                        self.queues.clone(),
-                                        self.weights.clone(),
-                                        self.job_handlers
-                                            .iter_mut()
-                                            .map(|(k, v)| (k.clone(), v.cloned()))
-                                            .collect(),
-                                        self.middlewares.iter_mut().map(|v| v.cloned()).collect(),
-                                        self.namespace.clone());
+    fn launch_worker(&mut self,
+                     capsule: &Capsule,
+                     tsx: Sender<Signal>,
+                     rox: Receiver<Operation>) {
+        let worker = self.new_worker(capsule, tsx, rox);
         self.worker_info.insert(worker.id.clone(), false);
+        self.worker_capsules.insert(worker.id.clone(), capsule.name.clone());
         self.threadpool.execute(move || worker.work());
     }
 
+    fn new_worker(&mut self,
+                  capsule: &Capsule,
+                  tsx: Sender<Signal>,
+                  rox: Receiver<Operation>)
+                  -> SidekiqWorker<'static> {
+        let context = WorkerContext {
+            server_id: self.identity(),
+            pool: self.redispool.clone(),
+            backend: self.backend(),
+            namespace: self.namespace.clone(),
+            queue_limits: self.queue_limits.clone(),
+            unknown_class: self.unknown_class,
+            handler_limits: self.handler_limits.clone(),
+            handler_timeouts: self.handler_timeouts.clone(),
+            job_timeout: self.job_timeout,
+            result_ttl: self.result_ttl,
+            payload_warn_size: self.payload_warn_size,
+            in_flight: self.in_flight.clone(),
+            work_state: self.work_state.clone(),
+            cancellations: self.cancellations.clone(),
+            backoff: self.backoff.clone(),
+            quiet: self.quiet.clone(),
+            metrics: self.metrics.clone(),
+            fetch_timeout: self.fetch_timeout(),
+            async_lane: self.async_lane(),
+        };
+        let hooks = WorkerHooks {
+            handlers: self.job_handlers
+                .iter_mut()
+                .map(|(k, v)| (k.clone(), v.cloned()))
+                .collect(),
+            fallback: self.fallback_handler.as_mut().map(|v| v.cloned()),
+            async_handlers: self.async_handlers
+                .iter_mut()
+                .map(|(k, v)| (k.clone(), v.cloned()))
+                .collect(),
+            middlewares: self.middlewares.iter_mut().map(|v| v.cloned()).collect(),
+            death_handlers: self.death_handlers.iter_mut().map(|v| v.cloned()).collect(),
+            error_handlers: self.error_handlers.iter_mut().map(|v| v.cloned()).collect(),
+            fetcher: self.fetcher.cloned(),
+            sinks: self.sinks.iter_mut().map(|v| v.cloned()).collect(),
+        };
+        SidekiqWorker::new(context,
+                           hooks,
+                           capsule.queues.clone(),
+                           capsule.slots.clone(),
+                           tsx,
+                           rox)
+    }
+
+    fn async_lane(&mut self) -> Option<AsyncLane> {
+        let runtime = self.executor.as_ref()?.handle().clone();
+        let redis = self.async_redis().unwrap_or_else(|e| {
+            warn!("connecting the async jobs to redis failed: '{}'", e);
+            AsyncRedis::Detached
+        });
+        let permits = cmp::max(self.async_concurrency, 1);
+        let budget = self.async_budget
+            .get_or_insert_with(|| Arc::new(::tokio::sync::Semaphore::new(permits)))
+            .clone();
+        Some(AsyncLane {
+            runtime,
+            redis,
+            budget,
+            middlewares: Arc::new(self.async_middlewares.clone()),
+            tasks: self.async_tasks.clone(),
+            shutdown_timeout: Duration::from_secs(self.force_quite_timeout as u64),
+        })
+    }
+
     fn inform_termination(&self, tox: Sender<Operation>) {
-        for _ in 0..self.concurrency {
-            tox.send(Operation::Terminate);
+        for _ in 0..self.total_concurrency() {
+            let _ = tox.send(Operation::Terminate);
         }
     }
 
-    fn terminate_forcely(&mut self, tox: Sender<Operation>, rsx: Receiver<Signal>) {
+    // stop fetching and wait for the running jobs up to `force_quite_timeout`, then push
+    // the unfinished ones back to their queues, returns false if there were some
+    fn terminate(&mut self, tox: Sender<Operation>, rsx: Receiver<Signal>) -> bool {
+        self.fire(LifecycleEvent::Shutdown);
         self.inform_termination(tox);
 
+        info!("waiting for other workers exit");
         let timer = after(Duration::from_secs(self.force_quite_timeout as u64));
         // deplete the signal channel
         loop {
-            chan_select! {
-                timer.recv() => {
+            select! {
+                recv(timer) -> _ => {
                     info!("force quitting");
                     break
                 },
-                rsx.recv() -> sig => {
+                recv(rsx) -> sig => {
                     debug!("received signal {:?}", sig);
-                    if let Some(Err(e)) = sig.map(|s| self.deal_signal(s)) {
+                    if let Ok(Err(e)) = sig.map(|s| self.deal_signal(s)) {
                         error!("error when dealing signal: '{}'", e);
                     }
                     if self.worker_info.len() == 0 {
@@ -213,27 +995,33 @@ impl<'a> SidekiqServer<'a> {
                 },
             }
         }
-    }
 
-
-    fn terminate_gracefully(&mut self, tox: Sender<Operation>, rsx: Receiver<Signal>) {
-        self.inform_termination(tox);
-
-        info!("waiting for other workers exit");
-        // deplete the signal channel
-        loop {
-            chan_select! {
-                rsx.recv() -> sig => {
-                    debug!("received signal {:?}", sig);
-                    if let Some(Err(e)) = sig.map(|s| self.deal_signal(s)) {
-                        error!("error when dealing signal: '{}'", e);
-                    }
-                    if self.worker_info.len()== 0 {
-                        break
-                    }
-                },
-            }
+        // the async ones would keep running on the runtime after being requeued
+        for (key, task) in mem::take(&mut *self.async_tasks.lock().unwrap()) {
+            warn!("aborting async job '{}'", key);
+            task.abort();
+        }
+        let works: Vec<_> = self.in_flight.lock().unwrap().values().cloned().collect();
+        if works.is_empty() {
+            return true;
+        }
+        if let Err(e) = self.requeue_in_flight(works) {
+            error!("requeue unfinished jobs failed: '{}'", e);
         }
+        false
+    }
+
+    fn requeue_in_flight(&mut self, works: Vec<UnitOfWork>) -> Result<()> {
+        warn!("pushing {} unfinished jobs back to their queues", works.len());
+        let identity = self.identity();
+        let (queues, weights) = self.queues.snapshot();
+        let request = FetchRequest {
+            identity: &identity,
+            queues: &queues,
+            weights: &weights,
+            timeout: self.fetch_timeout(),
+        };
+        self.backend().requeue(&mut *self.fetcher, &request, &works)
     }
 
 
@@ -241,83 +1029,232 @@ impl<'a> SidekiqServer<'a> {
         debug!("dealing signal {:?}", sig);
         match sig {
             Signal::Complete(id, n) => {
-                let _ = try!(self.report_processed(n));
-                *self.worker_info.get_mut(&id).unwrap() = false;
+                debug!("worker '{}' processed {} jobs", id, n);
+                self.pending_stats.0 += n;
             }
             Signal::Fail(id, n) => {
-                let _ = try!(self.report_failed(n));
-                *self.worker_info.get_mut(&id).unwrap() = false;
+                debug!("worker '{}' failed {} jobs", id, n);
+                self.pending_stats.1 += n;
             }
             Signal::Acquire(id) => {
                 self.worker_info.insert(id, true);
             }
+            Signal::Release(id) => {
+                if let Some(busy) = self.worker_info.get_mut(&id) {
+                    *busy = false;
+                }
+            }
             Signal::Terminated(id) => {
                 self.worker_info.remove(&id);
+                self.worker_capsules.remove(&id);
             }
         }
         debug!("signal dealt");
         Ok(())
     }
 
+    fn fire(&mut self, event: LifecycleEvent) {
+        for &mut (e, ref mut hook) in &mut self.lifecycle_hooks {
+            if e == event {
+                hook();
+            }
+        }
+    }
+
     // Sidekiq dashboard reporting functions
 
 
+    // false while redis is down, the heartbeat is what tries it again once the backoff
+    // delay is over
+    fn heartbeat(&mut self) -> bool {
+        if self.backoff.wait().is_some() {
+            return false;
+        }
+        match self.report_alive() {
+            Ok(()) => {
+                self.backoff.succeeded();
+                true
+            }
+            Err(ref e) if self.backoff.failed(e) => false,
+            Err(e) => {
+                error!("report alive failed: '{}'", e);
+                true
+            }
+        }
+    }
+
     fn report_alive(&mut self) -> Result<()> {
-        let now = UTC::now();
+        let capsules = self.all_capsules();
+        let mut queues = vec![];
+        let mut weights = vec![];
+        for capsule in &capsules {
+            let (names, capsule_weights) = capsule.queues.snapshot();
+            let mut capsule_queues = ::serde_json::Map::new();
+            for (name, weight) in names.into_iter().zip(capsule_weights) {
+                if !queues.contains(&name) {
+                    queues.push(name.clone());
+                }
+                capsule_queues.insert(name, json!(weight as usize));
+            }
+            weights.push(capsule_queues);
+        }
+        let info = to_string(&json!({
+            "hostname": platform::hostname().unwrap_or("unknown".into()),
+            "started_at": self.started_at,
+            "pid": self.pid,
+            "concurrency": self.total_concurrency(),
+            "queues": queues,
+            // the queues of each capsule, like sidekiq 7
+            "weights": weights,
+            "labels": [],
+            "identity": self.identity()
+        }))?;
+        let workers: Vec<(String, String)> = self.work_state
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, work)| (id.clone(), work.clone()))
+            .collect();
+        self.backend().heartbeat(&Heartbeat {
+            identity: self.identity(),
+            info,
+            // the async jobs included
+            busy: workers.len(),
+            quiet: self.is_quiet(),
+            rss: rust_rss_kb().unwrap_or(0),
+            workers,
+            ttl: self.heartbeat_ttl(),
+        })
+    }
 
-        let content = vec![("info",
-                            to_string(&json!({
-                                "hostname": rust_gethostname().unwrap_or("unknown".into()),
-                                "started_at": self.started_at,
-                                "pid": self.pid,
-                                "concurrency": self.concurrency,
-                                "queues": self.queues.clone(),
-                                "labels": [],
-                                "identity": self.identity()
-                            }))
-                                .unwrap()),
-                           ("busy", self.worker_info.values().filter(|v| **v).count().to_string()),
-                           ("beat",
-                            (now.timestamp() as f64 +
-                             now.timestamp_subsec_micros() as f64 / 1000000f64)
-                                .to_string())];
-        let conn = try!(self.redispool.get());
-        try!(Pipeline::new()
-            .hset_multiple(self.with_namespace(&self.identity()), &content)
-            .expire(self.with_namespace(&self.identity()), 5)
-            .sadd(self.with_namespace(&"processes"), self.identity())
-            .query::<()>(&*conn));
 
-        Ok(())
+    fn reap(&self) -> Result<usize> {
+        let mut conn = self.redispool.get()?;
+        let processes: Vec<String> = conn.smembers(self.with_namespace("processes"))?;
+        let mut pipe = Pipeline::new();
+        for identity in &processes {
+            pipe.exists(self.with_namespace(identity));
+        }
+        let alive: Vec<bool> = pipe.query(&mut *conn)?;
+        let stale: Vec<_> = processes.iter()
+            .zip(alive)
+            .filter(|&(_, alive)| !alive)
+            .map(|(identity, _)| identity)
+            .collect();
+        if !stale.is_empty() {
+            let _: () = conn.srem(self.with_namespace("processes"), stale.clone())?;
+        }
+        Ok(stale.len())
+    }
 
+
+    fn dump_in_flight(&self) {
+        let in_flight = self.in_flight.lock().unwrap();
+        info!("{} of {} workers are busy", in_flight.len(), self.total_concurrency());
+        for (worker, work) in in_flight.iter() {
+            match decode_job(&work.payload) {
+                Ok(job) => info!("worker '{}' runs '{:?}' from queue '{}'", worker, job, work.queue),
+                Err(_) => info!("worker '{}' runs a job from queue '{}'", worker, work.queue),
+            }
+        }
     }
 
 
-    fn report_processed(&mut self, n: usize) -> Result<()> {
-        let connection = try!(self.redispool.get());
-        let _: () = Pipeline::new().incr(self.with_namespace(&format!("stat:processed:{}",
-                                               UTC::now().format("%Y-%m-%d"))),
-                  n)
-            .incr(self.with_namespace(&format!("stat:processed")), n)
-            .query(&*connection)?;
+    fn flush_metrics(&self) -> Result<()> {
+        if !self.job_metrics || self.backend().redis().is_none() {
+            self.metrics.clear();
+            return Ok(());
+        }
+        self.metrics.flush(&mut *self.redispool.get()?, &self.namespace)
+    }
+
+    fn poll_cancellations(&self) -> Result<()> {
+        self.cancellations.poll(&mut *self.redispool.get()?, &self.namespace).map(|_| ())
+    }
 
+    fn autoscale(&mut self) -> Result<()> {
+        let mut conn = self.redispool.get()?;
+        let stats = sample_queues(&mut conn, &self.namespace, &self.queues.names())?;
+        if let Some(ref mut autoscaler) = self.autoscaler {
+            autoscaler.adjust(&self.slots, &stats);
+        }
+        Ok(())
+    }
+
+    fn report_queue_stats(&mut self) -> Result<()> {
+        let mut conn = self.redispool.get()?;
+        let stats = sample_queues(&mut conn, &self.namespace, &self.queues.names())?;
+        for stat in stats {
+            info!("queue '{}' has {} jobs, latency {:.3}s",
+                  stat.queue,
+                  stat.size,
+                  stat.latency);
+            let tags = [("queue", &*stat.queue)];
+            for sink in &mut self.sinks {
+                sink.gauge("queue.size", stat.size as f64, &tags);
+                sink.gauge("queue.latency", stat.latency, &tags);
+            }
+        }
         Ok(())
     }
 
 
-    fn report_failed(&mut self, n: usize) -> Result<()> {
-        let connection = try!(self.redispool.get());
-        let _: () = Pipeline::new()
-            .incr(self.with_namespace(&format!("stat:failed:{}", UTC::now().format("%Y-%m-%d"))),
-                  n)
-            .incr(self.with_namespace(&format!("stat:failed")), n)
-            .query(&*connection)?;
+    // write the counts gathered since the last heartbeat, they are kept for the next one if
+    // it fails
+    fn flush_stats(&mut self) -> Result<()> {
+        let (processed, failed) = self.pending_stats;
+        if processed == 0 && failed == 0 {
+            return Ok(());
+        }
+        self.backend().record_stats(processed, failed, self.stat_ttl)?;
+        self.pending_stats = (0, 0);
         Ok(())
     }
 
 
+    // redis unless the server is made with another backend, built on each use as the
+    // namespace may change until the server starts
+    fn backend(&self) -> Arc<dyn Backend> {
+        match self.backend {
+            Some(ref backend) => backend.clone(),
+            None => {
+                Arc::new(RedisBackend::new(self.redispool.clone(),
+                                               self.fetch_pool.clone(),
+                                               &self.namespace)
+                    .dead_limits(self.dead_max_jobs, self.dead_timeout))
+            }
+        }
+    }
+
+    // the default capsule first
+    fn all_capsules(&self) -> Vec<Capsule> {
+        let mut capsules = vec![Capsule {
+                                    name: DEFAULT_CAPSULE.into(),
+                                    queues: self.queues.clone(),
+                                    concurrency: self.concurrency,
+                                    slots: self.slots.clone(),
+                                }];
+        capsules.extend(self.capsules.iter().cloned());
+        capsules
+    }
+
+    // the workers of every capsule
+    fn total_concurrency(&self) -> usize {
+        self.concurrency + self.capsules.iter().map(|capsule| capsule.concurrency).sum::<usize>()
+    }
+
+    // BRPOP blocks forever with a zero timeout
+    fn fetch_timeout(&self) -> usize {
+        cmp::max(self.fetch_timeout, 1)
+    }
+
+    // the process is seen dead after missing a couple of heartbeats
+    fn heartbeat_ttl(&self) -> usize {
+        cmp::max(self.heartbeat_interval * 2 + 1, 5)
+    }
+
     fn identity(&self) -> String {
-        let host = rust_gethostname().unwrap_or("unknown".into());
+        let host = platform::hostname().unwrap_or("unknown".into());
         let pid = self.pid;
 
         host + ":" + &pid.to_string() + ":" + &self.rs
@@ -325,10 +1262,321 @@ impl<'a> SidekiqServer<'a> {
 
 
     fn with_namespace(&self, snippet: &str) -> String {
-        if self.namespace == "" {
-            snippet.into()
-        } else {
-            self.namespace.clone() + ":" + snippet
+        with_namespace(&self.namespace, snippet)
+    }
+}
+
+impl SidekiqServer<'static> {
+    // run the server on a background thread, e.g. next to an http server in the same binary
+    pub fn spawn(mut self) -> Result<(JoinHandle<i32>, ServerHandle)> {
+        let handle = self.handle();
+        let join = thread::Builder::new()
+            .name("sidekiq".into())
+            .spawn(move || self.start())
+            .map_err(|e| Error::from(format!("spawning server thread failed: '{}'", e)))?;
+        Ok((join, handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::{ready, FutureExt};
+
+    use super::*;
+    use job_handler::{JobContext, JobFuture, JobHandlerResult};
+    use middleware::{async_retry_middleware, retry_middleware, AsyncNextFunc};
+    use batch::{Batch, batch_middleware};
+    use unique::{unique_client_middleware, unique_middleware};
+    use JobSuccessType;
+
+    // pushes a `Countdown` job with its argument less one until it's 0
+    #[derive(Clone)]
+    struct Countdown(MemoryBackend);
+
+    impl JobHandler for Countdown {
+        fn handle(&mut self, job: &Job) -> JobHandlerResult {
+            let n = job.args[0].as_i64().unwrap();
+            if n > 0 {
+                let mut client = SidekiqClient::in_memory(self.0.clone())?;
+                client.perform_async("Countdown", "low", vec![json!(n - 1)])?;
+            }
+            Ok(JobSuccessType::Success)
+        }
+
+        fn cloned(&mut self) -> Box<dyn JobHandler> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn fail(_: &Job) -> JobHandlerResult {
+        Err("boom".into())
+    }
+
+    fn server(backend: &MemoryBackend) -> SidekiqServer<'static> {
+        let mut server = SidekiqServer::in_memory(backend.clone(), 1).unwrap();
+        server.attach_handler("Countdown", Countdown(backend.clone()));
+        server.attach_handler("Fail", fail);
+        server.attach_middleware(retry_middleware);
+        server
+    }
+
+    #[test]
+    fn drains_the_jobs_pushed_by_jobs() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Countdown", "default", vec![json!(3)]).unwrap();
+        let mut server = server(&backend);
+        assert_eq!(server.drain(&["default", "low"]).unwrap(), 4);
+        assert_eq!(backend.size("default"), 0);
+        assert_eq!(backend.size("low"), 0);
+        assert_eq!(backend.stats(), (4, 0));
+        assert_eq!(server.drain(&["default", "low"]).unwrap(), 0);
+    }
+
+    #[test]
+    fn drains_the_given_queues_only() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Countdown", "default", vec![json!(1)]).unwrap();
+        let mut server = server(&backend);
+        assert_eq!(server.drain(&["default"]).unwrap(), 1);
+        assert_eq!(backend.size("low"), 1);
+    }
+
+    #[test]
+    fn drains_failed_jobs_into_the_retry_set() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Fail", "default", vec![]).unwrap();
+        let job = Job {
+            retry: ::job::BoolOrUSize::Bool(false),
+            ..Job::new("Fail", vec![], "default")
+        };
+        client.push(job).unwrap();
+        assert_eq!(server(&backend).drain(&["default"]).unwrap(), 2);
+        let retried = backend.retried_jobs().unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].retry_info.as_ref().unwrap().retry_count, 1);
+        assert_eq!(backend.dead_jobs().unwrap().len(), 1);
+        assert_eq!(backend.stats(), (0, 1));
+    }
+
+    #[test]
+    fn drains_batches_and_fires_their_callbacks() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        let mut batch = Batch::new();
+        batch.on_complete(Job::new("Countdown", vec![json!(0)], "callbacks"));
+        batch.on_success(Job::new("Countdown", vec![json!(0)], "callbacks"));
+        let failing = Job {
+            retry: ::job::BoolOrUSize::Bool(false),
+            ..Job::new("Fail", vec![], "default")
+        };
+        let jobs = vec![Job::new("Countdown", vec![json!(0)], "default"), failing];
+        batch.push_bulk(&mut client, jobs).unwrap();
+        let mut server = server(&backend);
+        server.attach_middleware(batch_middleware);
+        assert_eq!(server.drain(&["default"]).unwrap(), 2);
+        // complete, and not a success with the failed job
+        assert_eq!(backend.size("callbacks"), 1);
+        let callback = &backend.jobs("callbacks").unwrap()[0];
+        assert_eq!(callback.extra["callback_event"], json!("complete"));
+    }
+
+    #[test]
+    fn buries_unknown_classes_through_the_death_handlers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DIED: AtomicUsize = AtomicUsize::new(0);
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.push(Job::new("Unknown", vec![], "default")).unwrap();
+        let mut server = server(&backend);
+        server.unknown_class = UnknownClass::Dead;
+        server.attach_death_handler(|_: &Job, _: &Error| {
+            DIED.fetch_add(1, Ordering::SeqCst);
+        });
+        server.drain(&["default"]).unwrap();
+        assert_eq!(backend.dead_jobs().unwrap().len(), 1);
+        assert_eq!(DIED.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn drains_unique_jobs_once() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.attach_middleware(unique_client_middleware);
+        let unique = || {
+            let mut job = Job::new("Countdown", vec![json!(0)], "default");
+            job.extra.insert("lock".into(), json!("until_executed"));
+            job
+        };
+        assert!(client.push(unique()).unwrap().is_some());
+        assert!(client.push(unique()).unwrap().is_none());
+        let mut server = server(&backend);
+        server.attach_middleware(unique_middleware);
+        assert_eq!(server.drain(&["default"]).unwrap(), 1);
+        // unlocked once done
+        assert!(client.push(unique()).unwrap().is_some());
+    }
+
+    fn double_later(job: &Job) -> JobFuture {
+        let n = job.args[0].as_i64().unwrap();
+        Box::pin(::tokio::time::sleep(Duration::from_millis(10))
+            .map(move |_| Ok(JobSuccessType::Returned(json!(n * 2)))))
+    }
+
+    fn fail_now(_: &Job) -> JobFuture {
+        Box::pin(ready(Err("boom".into())))
+    }
+
+    fn hang(_: &Job) -> JobFuture {
+        Box::pin(::tokio::time::sleep(Duration::from_secs(60)).map(|_| Ok(JobSuccessType::Success)))
+    }
+
+    #[test]
+    fn drains_async_jobs() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        let jid = client.perform_async("Double", "default", vec![json!(21)]).unwrap().unwrap();
+        client.perform_async("AsyncFail", "default", vec![]).unwrap();
+        client.push(Job {
+                retry: ::job::BoolOrUSize::Bool(false),
+                ..Job::new("AsyncFail", vec![], "default")
+            })
+            .unwrap();
+        let mut server = server(&backend);
+        server.attach_async_handler("Double", double_later).unwrap();
+        server.attach_async_handler("AsyncFail", fail_now).unwrap();
+        server.attach_async_middleware(async_retry_middleware);
+        assert_eq!(server.drain(&["default"]).unwrap(), 3);
+        assert_eq!(client.result::<i64>(&jid).unwrap(), Some(42));
+        assert_eq!(backend.retried_jobs().unwrap().len(), 1);
+        assert_eq!(backend.dead_jobs().unwrap().len(), 1);
+        assert_eq!(backend.stats(), (1, 1));
+        assert!(server.in_flight.lock().unwrap().is_empty());
+    }
+
+    fn double_args(mut job: Job, redis: AsyncRedis, next: AsyncNextFunc) -> JobFuture {
+        job.args[0] = json!(job.args[0].as_i64().unwrap() * 2);
+        next(job, redis)
+    }
+
+    #[test]
+    fn runs_the_async_middlewares_around_the_handlers() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        let jid = client.perform_async("Double", "default", vec![json!(1)]).unwrap().unwrap();
+        let mut server = server(&backend);
+        server.attach_async_handler("Double", double_later).unwrap();
+        server.attach_async_middleware(double_args);
+        server.attach_async_middleware(double_args);
+        assert_eq!(server.drain(&["default"]).unwrap(), 1);
+        assert_eq!(client.result::<i64>(&jid).unwrap(), Some(8));
+    }
+
+    #[test]
+    fn times_async_jobs_out() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Hang", "default", vec![]).unwrap();
+        let mut server = server(&backend);
+        server.attach_async_handler("Hang", hang).unwrap();
+        server.attach_async_middleware(async_retry_middleware);
+        server.set_handler_timeout("Hang", 1);
+        assert_eq!(server.drain(&["default"]).unwrap(), 1);
+        let retried = backend.retried_jobs().unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].retry_info.as_ref().unwrap().error_class, "Timeout");
+    }
+
+    static STOPPED: AtomicBool = AtomicBool::new(false);
+
+    fn wait_for_cancel(ctx: &JobContext) -> JobHandlerResult {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ctx.is_cancelled() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        STOPPED.store(ctx.is_cancelled(), Ordering::SeqCst);
+        Ok(JobSuccessType::Success)
+    }
+
+    #[test]
+    fn cancels_the_jobs_timed_out() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Wait", "default", vec![]).unwrap();
+        let mut server = server(&backend);
+        server.attach_context_handler("Wait", wait_for_cancel);
+        server.set_handler_timeout("Wait", 1);
+        server.drain(&["default"]).unwrap();
+        // the handler is left running, until it sees it's cancelled
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !STOPPED.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(STOPPED.load(Ordering::SeqCst));
+    }
+
+    fn sleep_long(_: &Job) -> JobHandlerResult {
+        thread::sleep(Duration::from_secs(3));
+        Ok(JobSuccessType::Success)
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn exits_with_the_memory_code_with_jobs_left() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Sleep", "default", vec![]).unwrap();
+        let mut server = SidekiqServer::in_memory(backend.clone(), 1).unwrap();
+        server.attach_handler("Sleep", sleep_long);
+        server.new_queue("default", 1);
+        server.max_rss_kb = Some(1);
+        server.heartbeat_interval = 1;
+        server.force_quite_timeout = 0;
+        assert_eq!(server.start(), MEMORY_EXIT_CODE);
+        // pushed back as it was still running
+        assert_eq!(backend.size("default"), 1);
+    }
+
+    static ABORTED: AtomicBool = AtomicBool::new(false);
+
+    struct Aborted;
+
+    impl Drop for Aborted {
+        fn drop(&mut self) {
+            ABORTED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn hang_until_aborted(_: &Job) -> JobFuture {
+        let aborted = Aborted;
+        Box::pin(::tokio::time::sleep(Duration::from_secs(60)).map(move |_| {
+            let _aborted = &aborted;
+            Ok(JobSuccessType::Success)
+        }))
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn aborts_the_async_jobs_left_at_the_deadline() {
+        let backend = MemoryBackend::new();
+        let mut client = SidekiqClient::in_memory(backend.clone()).unwrap();
+        client.perform_async("Hang", "default", vec![]).unwrap();
+        let mut server = SidekiqServer::in_memory(backend.clone(), 1).unwrap();
+        server.attach_async_handler("Hang", hang_until_aborted).unwrap();
+        server.new_queue("default", 1);
+        server.max_rss_kb = Some(1);
+        server.heartbeat_interval = 1;
+        // past the fetch timeout, so the worker isn't fetching anymore when it's requeued
+        server.force_quite_timeout = 2;
+        assert_eq!(server.start(), MEMORY_EXIT_CODE);
+        assert_eq!(backend.size("default"), 1);
+        // dropped on the runtime
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !ABORTED.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert!(ABORTED.load(Ordering::SeqCst));
     }
 }
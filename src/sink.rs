@@ -0,0 +1,117 @@
+use std::net::UdpSocket;
+
+use errors::*;
+
+// where the workers report the timing and outcome of each job, and the server the queue
+// gauges, every metric is tagged with its job class and queue
+pub trait MetricsSink: Send {
+    fn timing(&mut self, name: &str, ms: u64, tags: &[(&str, &str)]);
+    fn increment(&mut self, name: &str, tags: &[(&str, &str)]);
+    fn gauge(&mut self, name: &str, value: f64, tags: &[(&str, &str)]);
+    fn cloned(&mut self) -> Box<dyn MetricsSink>;
+}
+
+// sends metrics over udp to a statsd daemon, or a dogstatsd one which supports tags
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    tagged: bool,
+}
+
+impl StatsdSink {
+    // tags are folded into the metric name, e.g. `<prefix>.jobs.duration.<class>.<queue>`
+    pub fn new(addr: &str, prefix: &str) -> Result<StatsdSink> {
+        StatsdSink::connect(addr, prefix, false)
+    }
+
+    // tags are sent as datadog tags, `#class:<class>,queue:<queue>`
+    pub fn dogstatsd(addr: &str, prefix: &str) -> Result<StatsdSink> {
+        StatsdSink::connect(addr, prefix, true)
+    }
+
+    fn connect(addr: &str, prefix: &str, tagged: bool) -> Result<StatsdSink> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(addr).map(|_| socket))
+            .map_err(|e| format!("connecting to statsd '{}' failed: '{}'", addr, e))?;
+        Ok(StatsdSink {
+            socket,
+            prefix: prefix.into(),
+            tagged,
+        })
+    }
+
+    fn send(&mut self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let mut metric = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            self.prefix.clone() + "." + name
+        };
+        if !self.tagged {
+            for &(_, v) in tags {
+                metric = metric + "." + &sanitize(v);
+            }
+        }
+        let mut line = format!("{}:{}|{}", metric, value, kind);
+        if self.tagged && !tags.is_empty() {
+            let tags: Vec<_> = tags.iter()
+                .map(|&(k, v)| format!("{}:{}", k, sanitize(v)))
+                .collect();
+            line = line + "|#" + &tags.join(",");
+        }
+        // metrics are best effort, a lost packet is fine
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("sending metric to statsd failed: '{}'", e);
+        }
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn timing(&mut self, name: &str, ms: u64, tags: &[(&str, &str)]) {
+        self.send(name, &ms.to_string(), "ms", tags);
+    }
+
+    fn increment(&mut self, name: &str, tags: &[(&str, &str)]) {
+        self.send(name, "1", "c", tags);
+    }
+
+    fn gauge(&mut self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "g", tags);
+    }
+
+    fn cloned(&mut self) -> Box<dyn MetricsSink> {
+        match self.socket.try_clone() {
+            Ok(socket) => {
+                Box::new(StatsdSink {
+                    socket,
+                    prefix: self.prefix.clone(),
+                    tagged: self.tagged,
+                })
+            }
+            Err(e) => {
+                warn!("cloning statsd socket failed, metrics are dropped: '{}'", e);
+                Box::new(NullSink)
+            }
+        }
+    }
+}
+
+struct NullSink;
+
+impl MetricsSink for NullSink {
+    fn timing(&mut self, _: &str, _: u64, _: &[(&str, &str)]) {}
+    fn increment(&mut self, _: &str, _: &[(&str, &str)]) {}
+    fn gauge(&mut self, _: &str, _: f64, _: &[(&str, &str)]) {}
+    fn cloned(&mut self) -> Box<dyn MetricsSink> {
+        Box::new(NullSink)
+    }
+}
+
+// `:`, `|`, `,`, `#` and `@` are part of the line protocol
+fn sanitize(v: &str) -> String {
+    v.chars()
+        .map(|c| match c {
+            ':' | '|' | ',' | '#' | '@' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,167 @@
+// several servers on one host without a process manager, like the swarm of sidekiq
+// enterprise: the process runs itself again as children, restarts those that exit and
+// forwards them its signals
+//
+//     fn main() {
+//         if let Some(code) = sidekiq::swarm(0) {
+//             ::std::process::exit(code);
+//         }
+//         // only the children get here
+//         let mut server = SidekiqServer::new("redis://127.0.0.1/", 10).unwrap();
+//         ...
+//     }
+use std::env;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::*;
+use platform;
+use server::USAGE_EXIT_CODE;
+
+// set in the children to their index, from 0
+pub const SWARM_INDEX_VAR: &str = "SIDEKIQ_SWARM_INDEX";
+
+// seconds, a child exiting sooner than `MIN_UPTIME` after its start is restarted after
+// `RESTART_DELAY`, so one failing at startup doesn't spin
+const MIN_UPTIME: u64 = 5;
+const RESTART_DELAY: u64 = 5;
+
+struct Member {
+    index: usize,
+    child: Option<Child>,
+    started_at: Instant,
+    restart_at: Option<Instant>,
+}
+
+// the index of this process in its swarm, none outside of one
+pub fn swarm_index() -> Option<usize> {
+    env::var(SWARM_INDEX_VAR).ok().and_then(|index| index.parse().ok())
+}
+
+// none in a child, which goes on to run its server, the exit code of the swarm in the
+// supervisor once its children stopped. `count` children are run, one per core if 0
+pub fn swarm(count: usize) -> Option<i32> {
+    if swarm_index().is_some() {
+        return None;
+    }
+    let count = match count {
+        0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        count => count,
+    };
+    Some(match supervise(count) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("swarm failed: '{}'", e);
+            1
+        }
+    })
+}
+
+fn supervise(count: usize) -> Result<i32> {
+    let signal = platform::listen_signals()?;
+    let mut members = vec![];
+    for index in 0..count {
+        let mut member = Member {
+            index,
+            child: None,
+            started_at: Instant::now(),
+            restart_at: None,
+        };
+        member.start();
+        members.push(member);
+    }
+    info!("swarm of {} started", count);
+
+    let mut stopping = false;
+    let mut exit_code = 0;
+    loop {
+        if let Ok(name) = signal.recv_timeout(Duration::from_secs(1)) {
+            info!("{}: forwarding to the swarm", name);
+            if name == "INT" || name == "TERM" || name == "USR1" {
+                stopping = true;
+            }
+            for member in &members {
+                if let Some(ref child) = member.child {
+                    if let Err(e) = platform::send_signal(child.id(), name) {
+                        warn!("{}", e);
+                    }
+                }
+            }
+        }
+        for member in &mut members {
+            let status = match member.child {
+                // seen as still running, it's looked at again the next time around
+                Some(ref mut child) => {
+                    child.try_wait().unwrap_or_else(|e| {
+                        warn!("waiting on swarm member {} failed: '{}'", member.index, e);
+                        None
+                    })
+                }
+                // waiting for its restart
+                None => None,
+            };
+            if let Some(status) = status {
+                member.child = None;
+                let code = status.code().unwrap_or(1);
+                if stopping {
+                    if code != 0 {
+                        exit_code = code;
+                    }
+                } else if code == USAGE_EXIT_CODE {
+                    // it would fail the same way again
+                    error!("swarm member {} was given invalid options, not restarting it",
+                           member.index);
+                    exit_code = code;
+                } else {
+                    warn!("swarm member {} exited with {}, restarting", member.index, status);
+                    let uptime = member.started_at.elapsed();
+                    let delay = if uptime < Duration::from_secs(MIN_UPTIME) {
+                        RESTART_DELAY
+                    } else {
+                        0
+                    };
+                    member.restart_at = Some(Instant::now() + Duration::from_secs(delay));
+                }
+            }
+            if member.restart_at.is_some_and(|at| !stopping && Instant::now() >= at) {
+                member.start();
+            }
+        }
+        let stopped = |member: &Member| {
+            member.child.is_none() && (stopping || member.restart_at.is_none())
+        };
+        if members.iter().all(stopped) {
+            info!("swarm exited");
+            return Ok(exit_code);
+        }
+    }
+}
+
+impl Member {
+    // tried again after `RESTART_DELAY` if it fails, so the others stay supervised
+    fn start(&mut self) {
+        self.started_at = Instant::now();
+        match spawn(self.index) {
+            Ok(child) => {
+                self.child = Some(child);
+                self.restart_at = None;
+            }
+            Err(e) => {
+                error!("{}, retrying in {} seconds", e, RESTART_DELAY);
+                self.restart_at = Some(Instant::now() + Duration::from_secs(RESTART_DELAY));
+            }
+        }
+    }
+}
+
+// the same program with the same arguments
+fn spawn(index: usize) -> Result<Child> {
+    let program = env::current_exe()
+        .map_err(|e| Error::from(format!("finding the program failed: '{}'", e)))?;
+    Command::new(program)
+        .args(env::args_os().skip(1))
+        .env(SWARM_INDEX_VAR, index.to_string())
+        .spawn()
+        .map_err(|e| format!("starting swarm member {} failed: '{}'", index, e).into())
+}
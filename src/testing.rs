@@ -0,0 +1,269 @@
+// the modes of a client for the tests of the code pushing jobs, like ruby's `Sidekiq::Testing`
+//
+// fake: the jobs are only kept, to look at, `SidekiqClient::in_memory` with no server
+//
+//     let jobs = MemoryBackend::new();
+//     let mut client = SidekiqClient::in_memory(jobs.clone())?;
+//     signup(&mut client, "bob")?;
+//     assert_eq!(jobs.jobs_of("WelcomeMail")?.len(), 1);
+//
+// inline: the handler of each pushed job runs right away, the push returns its error
+//
+//     let mut handlers = InlineBackend::new();
+//     handlers.attach_handler("WelcomeMail", welcome_mail);
+//     let mut client = SidekiqClient::inline(handlers)?;
+//     signup(&mut client, "bob")?;
+//
+// the server middlewares aren't run, the client ones are
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use serde_json::Value as JValue;
+
+use errors::*;
+use job::Job;
+use codec::decode_job;
+use fetcher::{Fetcher, UnitOfWork};
+use job_handler::{JobHandler, Worker, WorkerClass, TypedHandler};
+use memory::MemoryBackend;
+use results::RESULT_TTL;
+use api::Stats;
+use backend::{Backend, BatchState, FetchRequest, Heartbeat, Push};
+use JobSuccessType;
+
+// runs the jobs as they're pushed, scheduled ones too without waiting, the rest is kept in a
+// `MemoryBackend`, so `SidekiqClient::result` has the values the handlers returned
+#[derive(Default)]
+pub struct InlineBackend {
+    handlers: Mutex<BTreeMap<String, Box<dyn JobHandler>>>,
+    store: MemoryBackend,
+}
+
+impl InlineBackend {
+    pub fn new() -> InlineBackend {
+        InlineBackend::default()
+    }
+
+    pub fn attach_handler<T: JobHandler + 'static>(&mut self, name: &str, handle: T) {
+        self.handlers.get_mut().unwrap().insert(name.into(), Box::new(handle));
+    }
+
+    // like `attach_handler`, with the arguments deserialized as `Worker::Args`
+    pub fn attach_worker<W: Worker + 'static>(&mut self, name: &str, worker: W) {
+        self.attach_handler(name, TypedHandler(worker));
+    }
+
+    // same as `attach_worker` with the class of the worker
+    pub fn register<W: WorkerClass + 'static>(&mut self, worker: W) {
+        self.attach_worker(W::class(), worker);
+    }
+
+    // where the results, dead jobs and paused queues are
+    pub fn store(&self) -> &MemoryBackend {
+        &self.store
+    }
+
+    fn perform(&self, job: &Job) -> Result<()> {
+        // not locked while it runs, so the handler can push jobs itself
+        let mut handler = {
+            let mut handlers = self.handlers.lock().unwrap();
+            match handlers.get_mut(job.handler_class()) {
+                Some(handler) => handler.cloned(),
+                None => {
+                    return Err(format!("no handler of job class '{}'", job.handler_class())
+                        .into())
+                }
+            }
+        };
+        match handler.handle(job)? {
+            JobSuccessType::Returned(value) => {
+                self.store.store_result(&job.jid, &value, RESULT_TTL)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Backend for InlineBackend {
+    // stops at the first job failing
+    fn push(&self, jobs: &[Push]) -> Result<()> {
+        for job in jobs {
+            let job = match *job {
+                Push::Enqueue(_, ref payload) => decode_job(payload)?,
+                Push::Schedule(_, ref payload) |
+                Push::Retry(_, ref payload) => decode_job(payload.as_bytes())?,
+            };
+            self.perform(&job)?;
+        }
+        Ok(())
+    }
+
+    fn enqueue_scheduled(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    // there's never a job to fetch
+    fn fetch(&self,
+             fetcher: &mut dyn Fetcher,
+             request: &FetchRequest)
+             -> Result<Option<UnitOfWork>> {
+        self.store.fetch(fetcher, request)
+    }
+
+    fn try_fetch(&self,
+                 fetcher: &mut dyn Fetcher,
+                 request: &FetchRequest)
+                 -> Result<Option<UnitOfWork>> {
+        self.store.try_fetch(fetcher, request)
+    }
+
+    fn acknowledge(&self, _: &mut dyn Fetcher, _: &FetchRequest, _: &UnitOfWork) -> Result<()> {
+        Ok(())
+    }
+
+    fn startup(&self, _: &mut dyn Fetcher, _: &FetchRequest) -> Result<()> {
+        Ok(())
+    }
+
+    fn requeue(&self, _: &mut dyn Fetcher, _: &FetchRequest, _: &[UnitOfWork]) -> Result<()> {
+        Ok(())
+    }
+
+    fn pause_queue(&self, name: &str) -> Result<()> {
+        self.store.pause_queue(name)
+    }
+
+    fn unpause_queue(&self, name: &str) -> Result<()> {
+        self.store.unpause_queue(name)
+    }
+
+    fn paused_queues(&self) -> Result<Vec<String>> {
+        self.store.paused_queues()
+    }
+
+    fn bury(&self, job: &Job) -> Result<()> {
+        self.store.bury(job)
+    }
+
+    fn store_result(&self, jid: &str, value: &JValue, ttl: usize) -> Result<()> {
+        self.store.store_result(jid, value, ttl)
+    }
+
+    fn result(&self, jid: &str) -> Result<Option<JValue>> {
+        self.store.result(jid)
+    }
+
+    fn record_stats(&self, processed: usize, failed: usize, ttl: usize) -> Result<()> {
+        self.store.record_stats(processed, failed, ttl)
+    }
+
+    fn read_stats(&self) -> Result<Stats> {
+        self.store.read_stats()
+    }
+
+    fn prune_stats(&self, ttl: usize) -> Result<usize> {
+        self.store.prune_stats(ttl)
+    }
+
+    fn lock(&self, digest: &str, jid: &str, ttl: Option<u64>) -> Result<bool> {
+        self.store.lock(digest, jid, ttl)
+    }
+
+    fn unlock(&self, digest: &str, jid: &str) -> Result<()> {
+        self.store.unlock(digest, jid)
+    }
+
+    fn open_batch(&self,
+                  bid: &str,
+                  jobs: isize,
+                  callbacks: &str,
+                  description: &str)
+                  -> Result<()> {
+        self.store.open_batch(bid, jobs, callbacks, description)
+    }
+
+    fn drop_from_batch(&self, bid: &str, jobs: isize) -> Result<()> {
+        self.store.drop_from_batch(bid, jobs)
+    }
+
+    fn batch_ran(&self, bid: &str, jid: &str, failed: bool) -> Result<()> {
+        self.store.batch_ran(bid, jid, failed)
+    }
+
+    fn batch_state(&self, bid: &str) -> Result<Option<BatchState>> {
+        self.store.batch_state(bid)
+    }
+
+    fn fire_batch(&self, bid: &str, event: &str) -> Result<bool> {
+        self.store.fire_batch(bid, event)
+    }
+
+    fn heartbeat(&self, _: &Heartbeat) -> Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remote_signal(&self, _: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use client::SidekiqClient;
+
+    fn double(job: &Job) -> Result<JobSuccessType> {
+        Ok(JobSuccessType::Returned(json!(job.args[0].as_i64().unwrap() * 2)))
+    }
+
+    fn fail(_: &Job) -> Result<JobSuccessType> {
+        Err("boom".into())
+    }
+
+    #[test]
+    fn keeps_the_results() {
+        let mut handlers = InlineBackend::new();
+        handlers.attach_handler("Double", double);
+        let mut client = SidekiqClient::inline(handlers).unwrap();
+        let jid = client.perform_async("Double", "default", vec![json!(21)]).unwrap().unwrap();
+        assert_eq!(client.result::<i64>(&jid).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn runs_scheduled_jobs_right_away() {
+        let mut handlers = InlineBackend::new();
+        handlers.attach_handler("Double", double);
+        let mut client = SidekiqClient::inline(handlers).unwrap();
+        let job = Job::new("Double", vec![json!(2)], "default");
+        let jid = client.perform_in(Duration::from_secs(3600), job).unwrap().unwrap();
+        assert_eq!(client.result::<i64>(&jid).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn returns_the_errors() {
+        let mut handlers = InlineBackend::new();
+        handlers.attach_handler("Fail", fail);
+        let mut client = SidekiqClient::inline(handlers).unwrap();
+        let err = client.perform_async("Fail", "default", vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+        let err = client.perform_async("Unknown", "default", vec![]).unwrap_err();
+        assert!(err.to_string().contains("'Unknown'"));
+    }
+
+    #[test]
+    fn queues_nothing() {
+        let store = MemoryBackend::new();
+        let mut handlers = InlineBackend::new();
+        handlers.store = store.clone();
+        handlers.attach_handler("Double", double);
+        let mut client = SidekiqClient::inline(handlers).unwrap();
+        client.perform_async("Double", "default", vec![json!(1)]).unwrap();
+        assert_eq!(store.size("default"), 0);
+    }
+}
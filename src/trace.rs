@@ -0,0 +1,119 @@
+use tracing::{Level, Span, field};
+
+use serde_json::Value as JValue;
+
+use job::Job;
+
+// the span each job runs in, middlewares included, an opentelemetry subscriber such as
+// `tracing-opentelemetry` exports it with the class, queue, jid and retry count as attributes.
+// with the `otel` feature its parent is the span which pushed the job
+pub fn job_span(job: &Job) -> Span {
+    let retry_count = job.retry_info.as_ref().map(|info| info.retry_count).unwrap_or(0);
+    let span = ::tracing::span!(Level::INFO,
+                                "sidekiq.job",
+                                otel.name = field::display(format!("perform {}", job.class)),
+                                otel.kind = "consumer",
+                                messaging.system = "sidekiq",
+                                class = field::display(&job.class),
+                                queue = field::display(&job.queue),
+                                jid = field::display(&job.jid),
+                                retry_count = retry_count as u64,
+                                traceparent = field::Empty,
+                                tracestate = field::Empty,
+                                trace_id = field::Empty,
+                                parent_span_id = field::Empty,
+                                outcome = field::Empty);
+    if let Some((traceparent, tracestate)) = trace_context(job) {
+        // w3c `<version>-<trace id>-<parent id>-<flags>`
+        let parts: Vec<_> = traceparent.split('-').collect();
+        if parts.len() == 4 && parts[1].len() == 32 && parts[2].len() == 16 {
+            span.record("trace_id", parts[1]);
+            span.record("parent_span_id", parts[2]);
+        } else {
+            warn!("job '{}' has a malformed traceparent '{}'", job.jid, traceparent);
+        }
+        span.record("traceparent", traceparent.as_str());
+        if let Some(ref tracestate) = tracestate {
+            span.record("tracestate", tracestate.as_str());
+        }
+        #[cfg(feature = "otel")]
+        set_parent(&span, job, traceparent, tracestate);
+    }
+    span
+}
+
+// the job's trace continues in the span, when the subscriber has a `tracing-opentelemetry`
+// layer
+#[cfg(feature = "otel")]
+fn set_parent(span: &Span, job: &Job, traceparent: String, tracestate: Option<String>) {
+    use std::collections::HashMap;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let mut headers = HashMap::new();
+    headers.insert("traceparent".to_string(), traceparent);
+    if let Some(tracestate) = tracestate {
+        headers.insert("tracestate".to_string(), tracestate);
+    }
+    let context = TraceContextPropagator::new().extract(&headers);
+    if let Err(e) = span.set_parent(context) {
+        debug!("job '{}' isn't traced by opentelemetry: '{}'", job.jid, e);
+    }
+}
+
+// the `trace_context` of the payload, either the traceparent itself or an object with the
+// `traceparent` and `tracestate` headers as written by the opentelemetry ruby sidekiq gem
+fn trace_context(job: &Job) -> Option<(String, Option<String>)> {
+    match job.extra.get("trace_context") {
+        Some(JValue::String(traceparent)) => Some((traceparent.clone(), None)),
+        Some(JValue::Object(headers)) => {
+            headers.get("traceparent").and_then(|v| v.as_str()).map(|traceparent| {
+                let tracestate = headers.get("tracestate").and_then(|v| v.as_str());
+                (traceparent.to_string(), tracestate.map(|v| v.to_string()))
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    fn traced(trace_context: JValue) -> Job {
+        let mut job = Job::new("HardJob", vec![], "default");
+        job.extra.insert("trace_context".into(), trace_context);
+        job
+    }
+
+    fn trace_id(job: &Job) -> String {
+        let tracer = SdkTracerProvider::builder().build().tracer("sidekiq");
+        let subscriber = ::tracing_subscriber::registry()
+            .with(::tracing_opentelemetry::layer().with_tracer(tracer));
+        ::tracing::subscriber::with_default(subscriber, || {
+            job_span(job).context().span().span_context().trace_id().to_string()
+        })
+    }
+
+    #[test]
+    fn continues_the_trace_of_the_job() {
+        let traceparent = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        assert_eq!(trace_id(&traced(json!(traceparent))), TRACE_ID);
+        let headers = json!({"traceparent": traceparent, "tracestate": "congo=t61rcWkgMzE"});
+        assert_eq!(trace_id(&traced(headers)), TRACE_ID);
+    }
+
+    #[test]
+    fn starts_a_trace_without_a_valid_context() {
+        assert_ne!(trace_id(&traced(json!("00-garbage-01"))), TRACE_ID);
+        assert_ne!(trace_id(&Job::new("HardJob", vec![], "default")), TRACE_ID);
+    }
+}
@@ -0,0 +1,147 @@
+use serde_json::{to_string, Value as JValue};
+
+use errors::*;
+use job::Job;
+use backend::current_or_redis;
+use middleware::{ClientMiddleWareResult, ClientNextFunc, MiddleWareResult, NextFunc};
+use JobSuccessType;
+use RedisPool;
+
+// the locks expire after a day unless the job sets `lock_expiration`, so a lost job doesn't
+// hold its lock for good
+pub const DEFAULT_LOCK_EXPIRATION: u64 = 24 * 60 * 60;
+
+// sidekiq-unique-jobs v6's lock.lua without the grabbed and available lists: the `EXISTS`
+// key holds the jid of the job owning the lock, taking it again with the same jid succeeds.
+// it doesn't expire with a ttl of 0
+pub const LOCK_SCRIPT: &str = r#"
+local stored = redis.call('get', KEYS[1])
+if stored and stored ~= ARGV[1] then
+    return 0
+end
+if tonumber(ARGV[2]) > 0 then
+    redis.call('set', KEYS[1], ARGV[1], 'ex', ARGV[2])
+else
+    redis.call('set', KEYS[1], ARGV[1])
+end
+return 1
+"#;
+
+// only delete the lock if it is still held by this job
+pub const UNLOCK_SCRIPT: &str = r#"
+if redis.call('get', KEYS[1]) == ARGV[1] then
+    return redis.call('del', KEYS[1])
+else
+    return 0
+end
+"#;
+
+// jobs opt in with sidekiq-unique-jobs' `lock` (or the older `unique`) option
+fn is_unique(job: &Job) -> bool {
+    match job.extra.get("lock").or_else(|| job.extra.get("unique")) {
+        None | Some(&JValue::Null) | Some(&JValue::Bool(false)) => false,
+        Some(_) => true,
+    }
+}
+
+fn is_set(job: &Job, option: &str) -> bool {
+    job.extra.get(option).and_then(|o| o.as_bool()).unwrap_or(false)
+}
+
+// sidekiq-unique-jobs v6's digest: `uniquejobs:` and the md5 of the class, queue and unique
+// args dumped in that order, without the queue or the class when the job is unique across
+// queues or workers
+pub fn lock_digest(job: &Job) -> Result<String> {
+    let unique_args = job.extra
+        .get("unique_args")
+        .cloned()
+        .unwrap_or_else(|| JValue::Array(job.args.clone()));
+    let mut digestable = vec![];
+    if !is_set(job, "unique_across_workers") {
+        digestable.push(format!(r#""class":{}"#, to_string(&job.class)?));
+    }
+    if !is_set(job, "unique_across_queues") {
+        digestable.push(format!(r#""queue":{}"#, to_string(&job.queue)?));
+    }
+    digestable.push(format!(r#""unique_args":{}"#, to_string(&unique_args)?));
+    let digestable = format!("{{{}}}", digestable.join(","));
+    Ok(format!("uniquejobs:{:x}", ::md5::compute(digestable.as_bytes())))
+}
+
+// take the lock of an unique job before pushing, the job is dropped if it is already locked.
+// the unique args and the digest are kept in the job like the gem does, so a ruby server
+// unlocks it too
+pub fn unique_client_middleware(job: &mut Job,
+                                redis: RedisPool,
+                                next: ClientNextFunc)
+                                -> ClientMiddleWareResult {
+    if !is_unique(job) {
+        return next(job, redis);
+    }
+    if !job.extra.contains_key("unique_args") {
+        job.extra.insert("unique_args".into(), JValue::Array(job.args.clone()));
+    }
+    let digest = lock_digest(job)?;
+    job.extra.insert("unique_digest".into(), JValue::String(digest.clone()));
+    let backend = current_or_redis(&redis, &job.namespace);
+    let ttl = job.extra
+        .get("lock_expiration")
+        .and_then(|ttl| ttl.as_u64())
+        .unwrap_or(DEFAULT_LOCK_EXPIRATION);
+    if !backend.lock(&digest, &job.jid, Some(ttl))? {
+        debug!("job '{}' is locked by another job with digest '{}'", job.jid, digest);
+        return Ok(false);
+    }
+
+    match next(job, redis) {
+        Ok(true) => Ok(true),
+        r => {
+            backend.unlock(&digest, &job.jid)?;
+            r
+        }
+    }
+}
+
+// release the lock of an unique job once it is done, unless it is waiting for a retry or
+// was rescheduled
+pub fn unique_middleware(job: &mut Job, redis: RedisPool, next: NextFunc) -> MiddleWareResult {
+    let digest = match job.extra.get("unique_digest").and_then(|d| d.as_str()) {
+        Some(digest) => digest.to_string(),
+        None => return next(job, redis),
+    };
+    let backend = current_or_redis(&redis, &job.namespace);
+    // like in batch_middleware, retry_middleware after this one schedules a retry or
+    // reschedules a limited job returning `Ignore` for both
+    let failure = |job: &Job| job.retry_info.as_ref().map(|i| (i.retry_count, i.failed_at));
+    let overrated = |job: &Job| job.extra.get("overrated").cloned();
+    let (failed_before, overrated_before) = (failure(job), overrated(job));
+    let r = next(job, redis);
+    let retried = failure(job) != failed_before;
+    let limited = overrated(job) != overrated_before;
+    match r {
+        Ok(JobSuccessType::Ignore) if retried || limited => {}
+        Ok(JobSuccessType::Reschedule(_)) => {}
+        _ => backend.unlock(&digest, &job.jid)?,
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_like_the_gem() {
+        // Digest::MD5.hexdigest(Sidekiq.dump_json("class" => "UniqueJob", "queue" => "default",
+        //                                         "unique_args" => [1, "two"]))
+        let mut job = Job::new("UniqueJob", vec![json!(1), json!("two")], "default");
+        assert_eq!(lock_digest(&job).unwrap(), "uniquejobs:4721cddf4a8a666bc07144211a0efd05");
+        job.queue = "other".into();
+        job.extra.insert("unique_across_queues".into(), json!(true));
+        assert_eq!(lock_digest(&job).unwrap(), "uniquejobs:bfa9bb8d5e8aed1e2a931c95d702d315");
+        // the unique args set by the job win over its args
+        job.args = vec![json!(3)];
+        job.extra.insert("unique_args".into(), json!([1, "two"]));
+        assert_eq!(lock_digest(&job).unwrap(), "uniquejobs:bfa9bb8d5e8aed1e2a931c95d702d315");
+    }
+}
@@ -1,35 +1,279 @@
-#![allow(unused_assignments)]
-use libc::{c_char, size_t, c_int};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
-extern "C" {
-    pub fn gethostname(name: *mut c_char, size: size_t) -> c_int;
+use r2d2::{ManageConnection, Pool};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::cluster::ClusterClientBuilder;
+use redis::{cmd, Client, ClientTlsConfig, ConnectionLike, RedisError, TlsCertificates};
+use tokio::runtime::Handle;
+
+use cluster::RedisConnection;
+use aio::AsyncRedis;
+
+use errors::*;
+use RedisPool;
+
+// `<namespace>:<snippet>`, or the snippet alone without namespace, the keys of ruby's
+// redis-namespace
+pub fn with_namespace(namespace: &str, snippet: &str) -> String {
+    if namespace.is_empty() {
+        snippet.into()
+    } else {
+        namespace.to_string() + ":" + snippet
+    }
 }
 
-pub fn rust_gethostname() -> Result<String, ()> {
-    let len = 255;
-    let mut buf = Vec::<u8>::with_capacity(len);
+// the certificates of a `rediss://` connection, PEM files. the system and webpki roots are
+// trusted unless `ca_file` is set
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub ca_file: Option<String>,
+    // for redis servers requiring clients to authenticate, with `client_key_file`
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+}
 
-    let ptr = buf.as_mut_slice().as_mut_ptr();
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
 
-    let err = unsafe { gethostname(ptr as *mut c_char, len as size_t) } as isize;
+    pub fn ca_file(mut self, path: &str) -> TlsConfig {
+        self.ca_file = Some(path.into());
+        self
+    }
 
-    match err {
-        0 => {
+    pub fn client_cert_files(mut self, cert: &str, key: &str) -> TlsConfig {
+        self.client_cert_file = Some(cert.into());
+        self.client_key_file = Some(key.into());
+        self
+    }
 
-            let mut real_len = len;
-            let mut i = 0;
-            loop {
-                let byte = unsafe { *(((ptr as u64) + (i as u64)) as *const u8) };
-                if byte == 0 {
-                    real_len = i;
-                    break;
-                }
+    fn certificates(&self) -> Result<TlsCertificates> {
+        let client_tls = match (&self.client_cert_file, &self.client_key_file) {
+            (Some(cert), Some(key)) => {
+                Some(ClientTlsConfig {
+                    client_cert: read_pem(cert)?,
+                    client_key: read_pem(key)?,
+                })
+            }
+            (None, None) => None,
+            _ => return Err("a TLS client certificate needs both a cert and a key file".into()),
+        };
+        let root_cert = match self.ca_file {
+            Some(ref ca) => Some(read_pem(ca)?),
+            None => None,
+        };
+        Ok(TlsCertificates {
+            client_tls,
+            root_cert,
+        })
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    let mut pem = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut pem))
+        .map_err(|e| Error::from(format!("reading '{}' failed: '{}'", path, e)))?;
+    Ok(pem)
+}
+
+// the connections of a `RedisPool`, over TLS with a `rediss://` url
+pub struct RedisConnectionManager {
+    client: RedisClient,
+}
 
-                i += 1;
+enum RedisClient {
+    Single(Box<Client>),
+    Cluster(Box<::redis::cluster::ClusterClient>),
+}
+
+impl fmt::Debug for RedisConnectionManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client {
+            RedisClient::Single(ref client) => write!(f, "RedisConnectionManager({:?})", client),
+            RedisClient::Cluster(_) => write!(f, "RedisConnectionManager(cluster)"),
+        }
+    }
+}
+
+impl RedisConnectionManager {
+    // a connection of the async jobs to the same redis, for the tasks of `runtime`. a cluster
+    // is connected to right away, a single node on the first command
+    pub fn async_redis(&self, runtime: &Handle) -> Result<AsyncRedis> {
+        match self.client {
+            RedisClient::Single(ref client) => {
+                let _runtime = runtime.enter();
+                let client = Client::clone(client);
+                let config = ConnectionManagerConfig::new();
+                Ok(AsyncRedis::Single(ConnectionManager::new_lazy_with_config(client, config)?))
+            }
+            RedisClient::Cluster(ref client) => {
+                Ok(AsyncRedis::Cluster(runtime.block_on(client.get_async_connection())?))
+            }
+        }
+    }
+}
+
+impl ManageConnection for RedisConnectionManager {
+    type Connection = RedisConnection;
+    type Error = RedisError;
+
+    fn connect(&self) -> ::std::result::Result<RedisConnection, RedisError> {
+        match self.client {
+            RedisClient::Single(ref client) => client.get_connection().map(RedisConnection::Single),
+            RedisClient::Cluster(ref client) => {
+                client.get_connection().map(|conn| RedisConnection::Cluster(Box::new(conn)))
             }
-            unsafe { buf.set_len(real_len) }
-            Ok(String::from_utf8_lossy(buf.as_slice()).into_owned())
         }
-        _ => Err(()),
     }
-}
\ No newline at end of file
+
+    fn is_valid(&self, conn: &mut RedisConnection) -> ::std::result::Result<(), RedisError> {
+        cmd("PING").query(conn)
+    }
+
+    fn has_broken(&self, conn: &mut RedisConnection) -> bool {
+        !conn.is_open()
+    }
+}
+
+// `tls` is only used with a `rediss://` url
+pub fn connection_manager(redis: &str, tls: Option<&TlsConfig>) -> Result<RedisConnectionManager> {
+    let client = match tls {
+        Some(tls) if redis.starts_with("rediss://") => {
+            Client::build_with_tls(redis, tls.certificates()?)?
+        }
+        _ => Client::open(redis)?,
+    };
+    Ok(RedisConnectionManager { client: RedisClient::Single(Box::new(client)) })
+}
+
+// the connections to a redis cluster, found from any of its `nodes`, see `cluster` for the
+// naming of the keys there
+pub fn cluster_connection_manager(nodes: &[String],
+                                  tls: Option<&TlsConfig>)
+                                  -> Result<RedisConnectionManager> {
+    let mut builder = ClusterClientBuilder::new(nodes.iter().map(|node| node.as_str()));
+    if let Some(tls) = tls {
+        if nodes.iter().any(|node| node.starts_with("rediss://")) {
+            builder = builder.certs(tls.certificates()?);
+        }
+    }
+    Ok(RedisConnectionManager { client: RedisClient::Cluster(Box::new(builder.build()?)) })
+}
+
+// a pool that never connects, given to the middlewares of the in-memory clients and servers,
+// getting a connection from it fails after a second
+pub fn detached_pool(size: u32) -> Result<RedisPool> {
+    Ok(Pool::builder()
+        .max_size(size)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(1))
+        .build_unchecked(connection_manager("redis://127.0.0.1:0/", None)?))
+}
+
+// resident memory of this process in kilobytes, what ruby sidekiq reports as `rss`, only
+// known on linux
+pub fn rust_rss_kb() -> Option<usize> {
+    let mut status = String::new();
+    File::open("/proc/self/status").ok()?.read_to_string(&mut status).ok()?;
+    status.lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+// user and system cpu seconds this process has used, only known on linux
+#[cfg(unix)]
+pub fn process_cpu_secs() -> Option<f64> {
+    let mut stat = String::new();
+    File::open("/proc/self/stat").ok()?.read_to_string(&mut stat).ok()?;
+    // the fields after the command, which may have spaces, start at the state
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    let ticks = unsafe { ::libc::sysconf(::libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return None;
+    }
+    Some((utime + stime) / ticks as f64)
+}
+
+#[cfg(windows)]
+pub fn process_cpu_secs() -> Option<f64> {
+    None
+}
+
+// a counting semaphore shared between worker threads
+#[derive(Clone)]
+pub struct Semaphore {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore { inner: Arc::new((Mutex::new(permits), Condvar::new())) }
+    }
+
+    // block until a permit is available, it is given back when the guard is dropped
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let (ref lock, ref cvar) = *self.inner;
+        let mut permits = lock.lock().unwrap();
+        while *permits == 0 {
+            permits = cvar.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphoreGuard { semaphore: self }
+    }
+
+    // a permit if one is available, without waiting
+    pub fn try_acquire(&self) -> Option<SemaphoreGuard<'_>> {
+        let mut permits = self.inner.0.lock().unwrap();
+        if *permits == 0 {
+            return None;
+        }
+        *permits -= 1;
+        Some(SemaphoreGuard { semaphore: self })
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.semaphore.inner;
+        *lock.lock().unwrap() += 1;
+        cvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_both_client_files() {
+        let tls = TlsConfig { client_cert_file: Some("client.crt".into()), ..TlsConfig::new() };
+        let err = connection_manager("rediss://localhost/", Some(&tls)).unwrap_err();
+        assert!(err.to_string().contains("both a cert and a key"));
+    }
+
+    #[test]
+    fn reports_the_missing_files() {
+        let tls = TlsConfig::new().ca_file("/nonexistent/ca.pem");
+        let err = connection_manager("rediss://localhost/", Some(&tls)).unwrap_err();
+        assert!(err.to_string().contains("'/nonexistent/ca.pem'"));
+    }
+
+    #[test]
+    fn ignores_the_certificates_without_tls() {
+        let tls = TlsConfig::new().ca_file("/nonexistent/ca.pem");
+        assert!(connection_manager("redis://localhost/", Some(&tls)).is_ok());
+    }
+}
@@ -1,16 +1,22 @@
 
+use std::any::Any;
+use std::cmp;
 use std::panic::{catch_unwind, AssertUnwindSafe};
 
-use std::time::Duration;
+use std::thread::{self, sleep};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
 use std::collections::BTreeMap;
 
-use random_choice::random_choice;
+use crossbeam_channel::{Sender, Receiver, tick, unbounded};
+use futures_util::future::FutureExt;
+use tokio::runtime::Handle;
+use tokio::task::AbortHandle;
+use tokio::sync::OwnedSemaphorePermit;
 
-use chan::{Sender, Receiver, tick};
-
-use serde_json::from_str;
 use errors::*;
-use redis::{Commands, PipelineCommands, Pipeline};
 
 
 use rand::Rng;
@@ -19,87 +25,296 @@ use chrono::UTC;
 
 use server::{Signal, Operation};
 use job::Job;
-use job_handler::{JobHandler, JobHandlerResult};
-use middleware::MiddleWare;
-use RedisPool;
+use job_handler::{JobHandler, JobHandlerResult, AsyncJobHandler, JobFuture, DeathHandler,
+                  ErrorHandler, UnknownClass};
+use middleware::{MiddleWare, AsyncMiddleWare, AsyncNextFunc};
+use fetcher::{Fetcher, UnitOfWork};
+use queues::QueueHandle;
+use metrics::ExecutionTracker;
+use sink::MetricsSink;
+use cancel::Cancellations;
+use backoff::Backoff;
+use autoscale::Slots;
+use backend::{self, Backend, FetchRequest, Push};
+use codec::decode_job;
+use redact::filtered;
+use logging::{LogContext, JOB_LOG_TARGET, enter_context, log_context};
+#[cfg(feature = "tracing")]
+use trace;
+use utils::{Semaphore, SemaphoreGuard};
+use {AsyncRedis, RedisPool};
 use JobSuccessType;
 
+// like ruby sidekiq, the server keeps it alive on each heartbeat while jobs run longer
+pub const WORKERS_TTL: usize = 60;
+
+// a job of an unknown class is pushed back to its queue `MAX_REQUEUES` times at most, after
+// `REQUEUE_DELAY` seconds doubling each time up to `MAX_REQUEUE_DELAY`, then it's moved to the
+// dead set. the count is kept in the job under `REQUEUES_KEY`
+const MAX_REQUEUES: u64 = 10;
+const REQUEUE_DELAY: u64 = 5;
+const MAX_REQUEUE_DELAY: u64 = 10 * 60;
+const REQUEUES_KEY: &str = "unknown_class_requeues";
+
+// the job each worker is running, for the server to requeue them if it stops before they
+// are done
+pub type InFlight = Arc<Mutex<BTreeMap<String, UnitOfWork>>>;
+
+// the entry of each busy worker in the `<identity>:workers` hash, the server writes them all
+// on its heartbeat like ruby sidekiq, instead of the worker on every job
+pub type WorkState = Arc<Mutex<BTreeMap<String, String>>>;
+
+// the tasks of the async jobs running in the process by their `in_flight` key, for the server
+// to abort the ones left at `force_quite_timeout`
+pub type AsyncTasks = Arc<Mutex<BTreeMap<String, AbortHandle>>>;
+
+// what the async jobs run with, see `SidekiqServer::attach_async_handler`
+#[derive(Clone)]
+pub struct AsyncLane {
+    pub runtime: Handle,
+    // given to the async middlewares
+    pub redis: AsyncRedis,
+    // a permit of it is held by each async job running in the process, a worker waits for
+    // one before spawning a job
+    pub budget: Arc<::tokio::sync::Semaphore>,
+    pub middlewares: Arc<Vec<Arc<dyn AsyncMiddleWare>>>,
+    pub tasks: AsyncTasks,
+    // how long a terminated worker waits for its async jobs
+    pub shutdown_timeout: Duration,
+}
+
+// the sinks and error handlers of the async jobs of a worker, called on the runtime when a
+// handler is done
+struct Reporters {
+    sinks: Vec<Box<dyn MetricsSink>>,
+    error_handlers: Vec<Box<dyn ErrorHandler>>,
+}
+
+// sent by an async job once its future is done, for the worker which spawned it to finish it
+struct AsyncDone {
+    // of the job in `InFlight` and `WorkState`
+    key: String,
+    work: UnitOfWork,
+    job: Job,
+    r: JobHandlerResult,
+    context: LogContext,
+}
 
 pub struct SidekiqWorker<'a> {
     pub id: String,
     server_id: String,
+    // given to the middlewares and handlers
     pool: RedisPool,
+    backend: Arc<dyn Backend>,
     namespace: String,
-    queues: Vec<String>,
-    weights: Vec<f64>,
+    queues: QueueHandle,
+    queue_limits: BTreeMap<String, Semaphore>,
+    // the queues and weights not paused, that are actually fetched from
+    active_queues: Vec<String>,
+    active_weights: Vec<f64>,
     handlers: BTreeMap<String, Box<JobHandler + 'a>>,
+    fallback: Option<Box<dyn JobHandler + 'a>>,
+    async_handlers: BTreeMap<String, Box<dyn AsyncJobHandler>>,
+    async_lane: Option<AsyncLane>,
+    async_reporters: Arc<Mutex<Reporters>>,
+    // async jobs spawned and not finished yet, they send `AsyncDone` on the channel
+    running: usize,
+    done: (Sender<AsyncDone>, Receiver<AsyncDone>),
+    unknown_class: UnknownClass,
+    handler_limits: BTreeMap<String, Semaphore>,
+    handler_timeouts: BTreeMap<String, usize>,
+    job_timeout: Option<usize>,
+    result_ttl: usize,
+    payload_warn_size: Option<usize>,
     middlewares: Vec<Box<MiddleWare + 'a>>,
+    death_handlers: Vec<Box<dyn DeathHandler + 'a>>,
+    error_handlers: Vec<Box<dyn ErrorHandler + 'a>>,
+    fetcher: Box<dyn Fetcher + 'a>,
+    in_flight: InFlight,
+    work_state: WorkState,
+    cancellations: Cancellations,
+    backoff: Backoff,
+    slots: Slots,
+    quiet: Arc<AtomicBool>,
+    metrics: ExecutionTracker,
+    sinks: Vec<Box<dyn MetricsSink + 'a>>,
     tx: Sender<Signal>,
     rx: Receiver<Operation>,
     processed: usize,
     failed: usize,
+    fetch_timeout: usize,
+}
+
+// what the workers of a server share, cloned for each of them
+#[derive(Clone)]
+pub struct WorkerContext {
+    pub server_id: String,
+    // given to the middlewares and handlers
+    pub pool: RedisPool,
+    pub backend: Arc<dyn Backend>,
+    pub namespace: String,
+    pub queue_limits: BTreeMap<String, Semaphore>,
+    pub unknown_class: UnknownClass,
+    pub handler_limits: BTreeMap<String, Semaphore>,
+    pub handler_timeouts: BTreeMap<String, usize>,
+    pub job_timeout: Option<usize>,
+    pub result_ttl: usize,
+    pub payload_warn_size: Option<usize>,
+    pub in_flight: InFlight,
+    pub work_state: WorkState,
+    pub cancellations: Cancellations,
+    pub backoff: Backoff,
+    pub quiet: Arc<AtomicBool>,
+    pub metrics: ExecutionTracker,
+    pub fetch_timeout: usize,
+    // when an async handler is attached
+    pub async_lane: Option<AsyncLane>,
+}
+
+// the handlers, middlewares and such of a worker, its own copies of the server's
+pub struct WorkerHooks<'a> {
+    pub handlers: BTreeMap<String, Box<dyn JobHandler + 'a>>,
+    pub fallback: Option<Box<dyn JobHandler + 'a>>,
+    pub async_handlers: BTreeMap<String, Box<dyn AsyncJobHandler>>,
+    pub middlewares: Vec<Box<dyn MiddleWare + 'a>>,
+    pub death_handlers: Vec<Box<dyn DeathHandler + 'a>>,
+    pub error_handlers: Vec<Box<dyn ErrorHandler + 'a>>,
+    pub fetcher: Box<dyn Fetcher + 'a>,
+    pub sinks: Vec<Box<dyn MetricsSink + 'a>>,
 }
 
 impl<'a> SidekiqWorker<'a> {
-    pub fn new(server_id: &str,
-               pool: RedisPool,
+    // fetching from `queues` when one of `slots` is free
+    pub fn new(context: WorkerContext,
+               hooks: WorkerHooks<'a>,
+               queues: QueueHandle,
+               slots: Slots,
                tx: Sender<Signal>,
-               rx: Receiver<Operation>,
-               queues: Vec<String>,
-               weights: Vec<f64>,
-               handlers: BTreeMap<String, Box<JobHandler>>,
-               middlewares: Vec<Box<MiddleWare>>,
-               namespace: String)
+               rx: Receiver<Operation>)
                -> SidekiqWorker<'a> {
+        let WorkerContext { server_id,
+                            pool,
+                            backend,
+                            namespace,
+                            queue_limits,
+                            unknown_class,
+                            handler_limits,
+                            handler_timeouts,
+                            job_timeout,
+                            result_ttl,
+                            payload_warn_size,
+                            in_flight,
+                            work_state,
+                            cancellations,
+                            backoff,
+                            quiet,
+                            metrics,
+                            fetch_timeout,
+                            async_lane } = context;
+        let WorkerHooks { handlers,
+                          fallback,
+                          async_handlers,
+                          middlewares,
+                          death_handlers,
+                          mut error_handlers,
+                          fetcher,
+                          mut sinks } = hooks;
+        let async_reporters = Reporters {
+            sinks: match async_lane {
+                Some(_) => sinks.iter_mut().map(|sink| sink.cloned()).collect(),
+                None => vec![],
+            },
+            error_handlers: match async_lane {
+                Some(_) => error_handlers.iter_mut().map(|handler| handler.cloned()).collect(),
+                None => vec![],
+            },
+        };
         SidekiqWorker {
             id: ::rand::thread_rng().gen_ascii_chars().take(9).collect(),
-            server_id: server_id.into(),
-            pool: pool,
-            namespace: namespace,
-            queues: queues,
-            weights: weights,
-            handlers: handlers,
-            middlewares: middlewares,
-            tx: tx,
-            rx: rx,
+            server_id,
+            pool,
+            backend,
+            namespace,
+            active_queues: vec![],
+            active_weights: vec![],
+            queues,
+            queue_limits,
+            handlers,
+            fallback,
+            async_handlers,
+            async_lane,
+            async_reporters: Arc::new(Mutex::new(async_reporters)),
+            running: 0,
+            done: unbounded(),
+            unknown_class,
+            handler_limits,
+            handler_timeouts,
+            job_timeout,
+            result_ttl,
+            payload_warn_size,
+            middlewares,
+            death_handlers,
+            error_handlers,
+            fetcher,
+            in_flight,
+            work_state,
+            cancellations,
+            backoff,
+            slots,
+            quiet,
+            metrics,
+            sinks,
+            tx,
+            rx,
             processed: 0,
             failed: 0,
+            fetch_timeout,
         }
     }
 
     pub fn work(mut self) {
-        let mut choice = random_choice();
         info!("worker '{}' start working", self.with_server_id(&self.id));
         // main loop is here
         let rx = self.rx.clone();
+        let done = self.done.1.clone();
         let clock = tick(Duration::from_secs(1));
+        if let Err(e) = self.refresh_queues() {
+            warn!("{}: refreshing queues failed: '{}'", self.id, e);
+        }
         loop {
-            chan_select! {
+            select! {
                 default => {
-                    let queue_name = {
-                        let v = choice.random_choice_f64(&self.queues, &self.weights, 1);
-                        v[0].clone()
-                    };
-                    debug!("{} run queue once", self.id);
-                    match self.run_queue_once(&queue_name) {
-                        Ok(true) => self.processed += 1,
-                        Ok(false) => {}
-                        Err(e) => {
-                            self.failed += 1;
-                            warn!("uncaught error '{}'", e);
-                        }
-                    };
+                    if let Some(wait) = self.backoff.wait() {
+                        // redis is down, the clock and terminate are still looked at every
+                        // second
+                        sleep(cmp::min(wait, Duration::from_secs(1)));
+                    } else {
+                        debug!("{} run queue once", self.id);
+                        let r = self.run_queue_once();
+                        self.tally(r);
+                    }
+                },
+                recv(done) -> done => {
+                    if let Ok(done) = done {
+                        let r = self.finish_async(done);
+                        self.tally(r);
+                    }
                 },
-                clock.recv() => {
+                recv(clock) -> _ => {
                     // synchronize state
                     debug!("{} syncing state", self.id);
                     self.sync_state();
+                    if let Err(e) = self.refresh_queues() {
+                        warn!("{}: refreshing queues failed: '{}'", self.id, e);
+                    }
                     debug!("{} syncing state done", self.id);
                 },
-                rx.recv() -> op => {
-                    if let Some(Operation::Terminate) = op {
+                recv(rx) -> op => {
+                    if let Ok(Operation::Terminate) = op {
                         info!("{}: Terminate signal received, exiting...", self.id);
-                        self.tx.send(Signal::Terminated(self.id.clone()));
+                        self.wait_async();
+                        self.sync_state();
+                        let _ = self.tx.send(Signal::Terminated(self.id.clone()));
                         debug!("{}: Terminate signal sent", self.id);
                         return;
                     } else {
@@ -111,85 +326,512 @@ impl<'a> SidekiqWorker<'a> {
     }
 
 
-    fn run_queue_once(&mut self, name: &str) -> Result<bool> {
-        let queue_name = self.queue_name(name);
-        debug!("{}: queue name '{}'", self.id, queue_name);
-
-        let result: Option<Vec<String>> = self.pool.get()?.brpop(&queue_name, 2)?;
+    // count the outcome of a job
+    fn tally(&mut self, r: Result<bool>) {
+        match r {
+            Ok(true) => {
+                self.processed += 1;
+                self.backoff.succeeded();
+            }
+            Ok(false) => self.backoff.succeeded(),
+            Err(ref e) if self.backoff.failed(e) => {}
+            Err(e) => {
+                self.failed += 1;
+                warn!("uncaught error '{}'", e);
+            }
+        };
+    }
 
-        if let Some(result) = result {
-            let mut job: Job = from_str(&result[1])?;
-            self.tx.send(Signal::Acquire(self.id.clone()));
-            if let Some(ref mut retry_info) = job.retry_info {
-                retry_info.retried_at = Some(UTC::now());
+    fn run_queue_once(&mut self) -> Result<bool> {
+        if self.quiet.load(Ordering::SeqCst) {
+            sleep(Duration::from_secs(self.fetch_timeout as u64));
+            return Ok(false);
+        }
+        if self.active_queues.is_empty() {
+            // every queue is paused or removed
+            sleep(Duration::from_secs(self.fetch_timeout as u64));
+            return Ok(false);
+        }
+        // held until the job is acknowledged
+        let slots = self.slots.clone();
+        let _slot = match slots.try_acquire() {
+            Some(slot) => slot,
+            None => {
+                // the autoscaler keeps this worker idle for now
+                sleep(Duration::from_millis(100));
+                return Ok(false);
             }
+        };
+        // a permit of each limited queue is taken before fetching, so no more workers than
+        // its limit ever wait on it with a job fetched
+        let limits: Vec<(String, Semaphore)> = self.active_queues
+            .iter()
+            .filter_map(|name| self.queue_limits.get(name).map(|l| (name.clone(), l.clone())))
+            .collect();
+        let mut permits: Vec<(&str, SemaphoreGuard)> = limits.iter()
+            .filter_map(|(name, limit)| limit.try_acquire().map(|permit| (&name[..], permit)))
+            .collect();
+        let (queues, weights): (Vec<String>, Vec<f64>) = self.active_queues
+            .iter()
+            .zip(&self.active_weights)
+            .filter(|&(name, _)| {
+                !self.queue_limits.contains_key(name) ||
+                permits.iter().any(|&(permitted, _)| permitted == name)
+            })
+            .map(|(name, weight)| (name.clone(), *weight))
+            .unzip();
+        if queues.is_empty() {
+            // every limited queue is busy
+            sleep(Duration::from_millis(100));
+            return Ok(false);
+        }
+        let backend = self.backend.clone();
+        let server_id = self.server_id.clone();
+        let request = FetchRequest {
+            identity: &server_id,
+            queues: &queues,
+            weights: &weights,
+            timeout: self.fetch_timeout,
+        };
+        match backend.fetch(&mut *self.fetcher, &request)? {
+            Some(work) => {
+                // only the permit of its queue is kept while the job runs
+                permits.retain(|&(name, _)| name == work.queue);
+                self.run_work(&*backend, &request, &work)
+            }
+            None => Ok(false),
+        }
+    }
 
-            job.namespace = self.namespace.clone();
-            self.report_working(&job)?;
-            let r = self.perform(job)?;
-            self.report_done()?;
+    // run the jobs of the queues one after the other until they're empty, jobs pushed by them
+    // included, for `SidekiqServer::drain`. returns how many were run, and the processed and
+    // failed counts of the stats, the ignored and rescheduled ones aren't processed
+    pub fn drain(&mut self) -> Result<(usize, usize, usize)> {
+        self.refresh_queues()?;
+        if self.active_queues.is_empty() {
+            return Ok((0, 0, 0));
+        }
+        let backend = self.backend.clone();
+        let server_id = self.server_id.clone();
+        let (queues, weights) = (self.active_queues.clone(), self.active_weights.clone());
+        let request = FetchRequest {
+            identity: &server_id,
+            queues: &queues,
+            weights: &weights,
+            timeout: 0,
+        };
+        let (mut run, mut processed, mut failed) = (0, 0, 0);
+        while let Some(work) = backend.try_fetch(&mut *self.fetcher, &request)? {
+            run += 1;
+            let r = match self.run_work(&*backend, &request, &work) {
+                // an async job is only spawned, it's waited for so the jobs it pushes are
+                // drained too
+                Ok(_) if self.running > 0 => self.next_async_done(),
+                r => r,
+            };
             match r {
-                JobSuccessType::Ignore => Ok(false),
-                JobSuccessType::Success => Ok(true),
+                Ok(true) => processed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    failed += 1;
+                    warn!("{}: draining '{}' failed: '{}'", self.id, work.queue, e);
+                }
+            }
+        }
+        Ok((run, processed, failed))
+    }
+
+    fn run_work(&mut self,
+                backend: &dyn Backend,
+                request: &FetchRequest,
+                work: &UnitOfWork)
+                -> Result<bool> {
+        debug!("{}: fetched from queue '{}'", self.id, work.queue);
+        if let Some(limit) = self.payload_warn_size {
+            if work.payload.len() > limit {
+                warn!("{}: fetched a job of {} bytes from queue '{}', over {} bytes",
+                      self.id,
+                      work.payload.len(),
+                      work.queue,
+                      limit);
+            }
+        }
+        let job = match decode_job(&work.payload) {
+            Ok(job) => job,
+            Err(e) => {
+                // it can't be run, whatever the handler
+                backend.acknowledge(&mut *self.fetcher, request, work)?;
+                return Err(e);
+            }
+        };
+        if let Some(lane) = self.async_lane.clone() {
+            if self.async_handlers.contains_key(job.handler_class()) {
+                return self.start_async(&lane, job, work);
             }
-        } else {
-            Ok(false)
         }
+        self.in_flight.lock().unwrap().insert(self.id.clone(), work.clone());
+        let r = self.run_job(job);
+        let _ = self.tx.send(Signal::Release(self.id.clone()));
+        // the job has been dealt with whatever the result is
+        let acknowledged = backend.acknowledge(&mut *self.fetcher, request, work);
+        self.in_flight.lock().unwrap().remove(&self.id);
+        acknowledged?;
+        r
+    }
+
+    // pick up the queues added or removed at runtime, skipping the ones in the `paused` set
+    // like sidekiq pro
+    fn refresh_queues(&mut self) -> Result<()> {
+        let (names, weights) = self.queues.snapshot();
+        let paused = self.backend.paused_queues()?;
+        let (queues, weights) = names.iter()
+            .zip(&weights)
+            .filter(|&(name, _)| !paused.contains(name))
+            .map(|(name, weight)| (name.clone(), *weight))
+            .unzip();
+        self.active_queues = queues;
+        self.active_weights = weights;
+        Ok(())
     }
 
+    fn run_job(&mut self, mut job: Job) -> Result<bool> {
+        let _ = self.tx.send(Signal::Acquire(self.id.clone()));
+        self.prepare(&mut job);
+        self.report_working(&self.id, &job);
+        let r = self.perform(job);
+        // whatever the result is, so failed jobs don't linger in the busy tab
+        self.report_done(&self.id);
+        Ok(processed(r?))
+    }
 
-    fn perform(&mut self, job: Job) -> Result<JobSuccessType> {
+    // the job as its handler sees it
+    fn prepare(&self, job: &mut Job) {
+        if let Some(ref mut retry_info) = job.retry_info {
+            retry_info.retried_at = Some(UTC::now());
+        }
+        job.namespace = self.namespace.clone();
+    }
+
+    // spawn the future of an async handler and its middlewares on the runtime, the job is
+    // acknowledged by `finish_async` once it's done, the worker fetches the next one meanwhile.
+    // the sync middlewares, handler limits, cancellations and queue limits don't apply to it
+    fn start_async(&mut self, lane: &AsyncLane, mut job: Job, work: &UnitOfWork) -> Result<bool> {
+        let permit = self.async_permit(&lane.budget);
+        self.prepare(&mut job);
+        let key = format!("{}:{}", self.id, job.jid);
+        self.in_flight.lock().unwrap().insert(key.clone(), work.clone());
+        self.report_working(&key, &job);
+        let context = LogContext::new(&self.id, &job);
+        let _context = enter_context(context.clone());
         debug!("{}: job is {:?}", self.id, job);
+        info!(target: JOB_LOG_TARGET, "start");
+
+        let class = job.handler_class().to_string();
+        // the caller checked it's there
+        let mut handler = self.async_handlers.get_mut(&class).unwrap().cloned();
+        let timeout = self.handler_timeouts.get(&class).cloned().or(self.job_timeout);
+        let metrics = self.metrics.clone();
+        let reporters = self.async_reporters.clone();
+        let handle: AsyncNextFunc = Box::new(move |job, _| {
+            let start = Instant::now();
+            let handled = AssertUnwindSafe(handler.handle(&job)).catch_unwind();
+            let handled = match timeout {
+                Some(timeout) => {
+                    let expired = move |_| Ok(Err(ErrorKind::Timeout(timeout).into()));
+                    ::tokio::time::timeout(Duration::from_secs(timeout as u64), handled)
+                        .map(move |r| r.unwrap_or_else(expired))
+                        .boxed()
+                }
+                None => handled.boxed(),
+            };
+            handled.map(move |r| {
+                    let r = r.unwrap_or_else(|payload| Err(panicked(&job, payload)));
+                    let mut reporters = reporters.lock().unwrap();
+                    let Reporters { ref mut sinks, ref mut error_handlers } = *reporters;
+                    report_handled(&metrics,
+                                   sinks,
+                                   error_handlers,
+                                   &class,
+                                   &job,
+                                   start.elapsed(),
+                                   &r);
+                    r
+                })
+                .boxed()
+        });
+        // the timeouts are made on the runtime
+        let _runtime = lane.runtime.enter();
+        let middlewares = lane.middlewares.clone();
+        let (redis, cloned) = (lane.redis.clone(), job.clone());
+        let chain = backend::using(self.backend.clone(),
+                                   || call_async_middleware(middlewares, 0, cloned, redis, handle));
+        let done = self.done.0.clone();
+        let work = work.clone();
+        let key_of_task = key.clone();
+        // removed by `finish_async`, which runs on this thread after this
+        let mut tasks = lane.tasks.lock().unwrap();
+        let task = lane.runtime.spawn(AssertUnwindSafe(chain).catch_unwind().map(move |r| {
+            drop(permit);
+            // only a middleware panicking gets here, the job isn't retried
+            let r = r.unwrap_or_else(|_| Err("Worker crashed".into()));
+            let _ = done.send(AsyncDone {
+                key,
+                work,
+                job,
+                r,
+                context,
+            });
+        }));
+        tasks.insert(key_of_task, task.abort_handle());
+        self.running += 1;
+        Ok(false)
+    }
 
-        let mut handler = if let Some(handler) = self.handlers.get_mut(&job.class) {
-            handler.cloned()
+    // a permit of the async budget, finishing the async jobs done while waiting for one
+    fn async_permit(&mut self, budget: &Arc<::tokio::sync::Semaphore>) -> OwnedSemaphorePermit {
+        loop {
+            if let Ok(permit) = budget.clone().try_acquire_owned() {
+                return permit;
+            }
+            if let Ok(done) = self.done.1.recv_timeout(Duration::from_millis(100)) {
+                let r = self.finish_async(done);
+                self.tally(r);
+            }
+        }
+    }
+
+    // acknowledge an async job once its future is done, what's left of it is done like for
+    // the other jobs
+    fn finish_async(&mut self, done: AsyncDone) -> Result<bool> {
+        let AsyncDone { key, work, job, r, context } = done;
+        self.running -= 1;
+        if let Some(ref lane) = self.async_lane {
+            lane.tasks.lock().unwrap().remove(&key);
+        }
+        let _context = enter_context(context.clone());
+        let r = self.complete(&job, r);
+        if r.is_ok() {
+            info!(target: JOB_LOG_TARGET, "done: {:.3} sec", context.elapsed());
         } else {
-            warn!("unknown job class '{}'", job.class);
-            return Err("unknown job class".into());
+            info!(target: JOB_LOG_TARGET, "fail: {:.3} sec", context.elapsed());
+        }
+        self.report_done(&key);
+        let backend = self.backend.clone();
+        let request = FetchRequest {
+            identity: &self.server_id,
+            queues: &self.active_queues,
+            weights: &self.active_weights,
+            timeout: self.fetch_timeout,
+        };
+        let acknowledged = backend.acknowledge(&mut *self.fetcher, &request, &work);
+        self.in_flight.lock().unwrap().remove(&key);
+        acknowledged?;
+        Ok(processed(r?))
+    }
+
+    fn next_async_done(&mut self) -> Result<bool> {
+        // never disconnected, the worker holds a sender
+        let done = self.done.1.recv().map_err(|_| Error::from("an async job was dropped"))?;
+        self.finish_async(done)
+    }
+
+    // until the async jobs spawned by this worker are finished, or the shutdown timeout. the
+    // server aborts the ones left and pushes them back to their queues
+    fn wait_async(&mut self) {
+        let timeout = match self.async_lane {
+            Some(ref lane) => lane.shutdown_timeout,
+            None => return,
         };
+        let deadline = Instant::now() + timeout;
+        while self.running > 0 {
+            info!("{}: waiting for {} async jobs", self.id, self.running);
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.done.1.recv_timeout(left) {
+                Ok(done) => {
+                    let r = self.finish_async(done);
+                    self.tally(r);
+                }
+                Err(_) => {
+                    warn!("{}: leaving {} async jobs running", self.id, self.running);
+                    return;
+                }
+            }
+        }
+    }
+
+
+    fn perform(&mut self, mut job: Job) -> Result<JobSuccessType> {
+        let context = LogContext::new(&self.id, &job);
+        let _context = enter_context(context.clone());
+        debug!("{}: job is {:?}", self.id, job);
+        info!(target: JOB_LOG_TARGET, "start");
+
+        // the handler of an active job is attached with the name of the rails job class
+        let class = job.handler_class().to_string();
+        if !self.handlers.contains_key(&class) && self.fallback.is_none() {
+            let r = self.handle_unknown(&job);
+            // for the death handlers of the buried ones
+            let r = self.complete(&job, r);
+            if r.is_ok() {
+                info!(target: JOB_LOG_TARGET, "done: {:.3} sec", context.elapsed());
+            } else {
+                info!(target: JOB_LOG_TARGET, "fail: {:.3} sec", context.elapsed());
+            }
+            return r;
+        }
+        // removed again below, a handler panicking is caught before that
+        self.cancellations.register(&job.jid);
+
+        #[cfg(feature = "tracing")]
+        let span = trace::job_span(&job);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
+        let limit = self.handler_limits.get(&class).cloned();
+        let _permit = limit.as_ref().map(|limit| {
+            debug!("{}: waiting for a free slot of '{}'", self.id, class);
+            limit.acquire()
+        });
 
-        match catch_unwind(AssertUnwindSafe(|| { self.call_middleware(job, |job| handler.handle(job)) })) {
+        let timeout = self.handler_timeouts.get(&class).cloned().or(self.job_timeout);
+        // the chain only borrows the fields it needs, so the only box of the job is the copy
+        // of its handler, dropped with it so a handler keeps no state from a job to the next,
+        // even one which panicked
+        let mut handler = match self.handlers.get_mut(&class) {
+            Some(handler) => handler.cloned(),
+            None => self.fallback.as_mut().unwrap().cloned(),
+        };
+        let handler = &mut handler;
+        let id = &self.id;
+        let cancellations = &self.cancellations;
+        let metrics = &self.metrics;
+        let sinks = &mut self.sinks;
+        let error_handlers = &mut self.error_handlers;
+        let middlewares = &mut self.middlewares;
+        let pool = self.pool.clone();
+        let backend = self.backend.clone();
+        let r = catch_unwind(AssertUnwindSafe(|| {
+            backend::using(backend, || call_middleware(middlewares, pool, &mut job, |job| {
+                let start = Instant::now();
+                let r = match timeout {
+                    Some(timeout) => {
+                        handle_with_timeout(id, cancellations, handler, job, timeout)
+                    }
+                    None => handle_catching_panic(handler, job),
+                };
+                report_handled(metrics,
+                               &mut sinks[..],
+                               &mut error_handlers[..],
+                               &class,
+                               job,
+                               start.elapsed(),
+                               &r);
+                r
+            }))
+        }));
+        let r = match r {
+            // only a middleware panicking gets here, the job isn't retried
             Err(_) => {
                 error!("Worker '{}' panicked, recovering", self.id);
                 Err("Worker crashed".into())
             }
-            Ok(r) => Ok(try!(r)),
+            Ok(r) => r,
+        };
+        let r = self.complete(&job, r);
+        self.cancellations.remove(&job.jid);
+        #[cfg(feature = "tracing")]
+        span.record("outcome", if r.is_ok() { "success" } else { "failure" });
+        if r.is_ok() {
+            info!(target: JOB_LOG_TARGET, "done: {:.3} sec", context.elapsed());
+        } else {
+            info!(target: JOB_LOG_TARGET, "fail: {:.3} sec", context.elapsed());
         }
+        r
     }
 
-    fn call_middleware<F>(&mut self, mut job: Job, mut job_handle: F) -> Result<JobSuccessType>
-        where F: FnMut(&Job) -> JobHandlerResult
-    {
-        fn imp<'a, F: FnMut(&Job) -> JobHandlerResult>(job: &mut Job,
-                                                       redis: RedisPool,
-                                                       chain: &mut [Box<MiddleWare + 'a>],
-                                                       job_handle: &mut F)
-                                                       -> Result<JobSuccessType> {
-            chain.split_first_mut()
-                .map(|(head, tail)| {
-                    head.handle(job,
-                                redis,
-                                &mut |job, redis| imp(job, redis, tail, job_handle))
-                })
-                .or_else(|| Some(job_handle(&job)))
-                .unwrap()
+    // what's left once the middlewares are done, the death handlers of a dead job, or the
+    // rescheduling or result of a job done
+    fn complete(&mut self, job: &Job, r: Result<JobSuccessType>) -> Result<JobSuccessType> {
+        if let Err(ref e) = r {
+            if let ErrorKind::JobDead(ref cause) = *e.kind() {
+                for handler in &mut self.death_handlers {
+                    handler.handle(job, cause);
+                }
+            }
+        }
+        match r {
+            Ok(JobSuccessType::Reschedule(delay)) => {
+                self.reschedule(job, delay).map(|_| JobSuccessType::Reschedule(delay))
+            }
+            Ok(JobSuccessType::Returned(value)) => {
+                self.store_result(job, &value).map(|_| JobSuccessType::Returned(value))
+            }
+            r => r,
         }
+    }
+
+    // to the schedule set, like `perform_in`
+    fn reschedule(&self, job: &Job, delay: Duration) -> Result<()> {
+        info!("{}: rescheduling '{}' in {:?}", self.id, job.jid, delay);
+        let now = UTC::now();
+        let at = now.timestamp() as f64 + now.timestamp_subsec_micros() as f64 / 1000000f64 +
+                 delay.as_secs() as f64 + delay.subsec_nanos() as f64 / 1000000000f64;
+        self.backend.push(&[Push::Schedule(at, to_string(job)?)])
+    }
 
-        imp(&mut job,
-            self.pool.clone(),
-            &mut self.middlewares,
-            &mut job_handle)
+    fn store_result(&self, job: &Job, value: &JValue) -> Result<()> {
+        self.backend.store_result(&job.jid, value, self.result_ttl)
+    }
+
+    fn handle_unknown(&self, job: &Job) -> Result<JobSuccessType> {
+        match self.unknown_class {
+            UnknownClass::Fail => {
+                warn!("unknown job class '{}'", job.handler_class());
+                Err("unknown job class".into())
+            }
+            UnknownClass::Ignore => {
+                warn!("unknown job class '{}', ignoring '{}'", job.handler_class(), job.jid);
+                Ok(JobSuccessType::Ignore)
+            }
+            UnknownClass::Dead => {
+                warn!("unknown job class '{}', moving '{}' to dead set", job.handler_class(), job.jid);
+                self.backend.bury(job)?;
+                Err(ErrorKind::JobDead(Box::new("unknown job class".into())).into())
+            }
+            UnknownClass::Requeue => {
+                // through the schedule set, or the process would fetch it again right away
+                let requeues = job.extra.get(REQUEUES_KEY).and_then(|n| n.as_u64()).unwrap_or(0);
+                if requeues >= MAX_REQUEUES {
+                    warn!("unknown job class '{}', moving '{}' to dead set after {} requeues",
+                          job.handler_class(),
+                          job.jid,
+                          requeues);
+                    self.backend.bury(job)?;
+                    return Err(ErrorKind::JobDead(Box::new("unknown job class".into())).into());
+                }
+                let delay = cmp::min(REQUEUE_DELAY << requeues, MAX_REQUEUE_DELAY);
+                warn!("unknown job class '{}', pushing '{}' back to queue '{}' in {} seconds",
+                      job.handler_class(),
+                      job.jid,
+                      job.queue,
+                      delay);
+                let mut job = job.clone();
+                job.extra.insert(REQUEUES_KEY.into(), JValue::from(requeues + 1));
+                self.reschedule(&job, Duration::from_secs(delay))?;
+                Ok(JobSuccessType::Ignore)
+            }
+        }
     }
 
     fn sync_state(&mut self) {
         if self.processed != 0 {
             debug!("{} sending complete signal", self.id);
-            self.tx.send(Signal::Complete(self.id.clone(), self.processed));
+            let _ = self.tx.send(Signal::Complete(self.id.clone(), self.processed));
             self.processed = 0;
         }
         if self.failed != 0 {
             debug!("{} sending fail signal", self.id);
-            self.tx.send(Signal::Fail(self.id.clone(), self.failed));
+            let _ = self.tx.send(Signal::Fail(self.id.clone(), self.failed));
             self.failed = 0;
         }
     }
@@ -197,38 +839,18 @@ impl<'a> SidekiqWorker<'a> {
     // Sidekiq dashboard reporting functions
 
 
-    fn report_working(&self, job: &Job) -> Result<()> {
-        let conn = try!(self.pool.get());
+    fn report_working(&self, key: &str, job: &Job) {
         let payload: JValue = json!({
             "queue": job.queue.clone(),
-            "payload": job,
+            "payload": filtered(job),
             "run_at": UTC::now().timestamp()
         });
-        let _: () = Pipeline::new().hset(&self.with_namespace(&self.with_server_id("workers")),
-                  &self.id,
-                  to_string(&payload).unwrap())
-            .expire(&self.with_namespace(&self.with_server_id("workers")), 5)
-            .query(&*conn)?;
-
-        Ok(())
-    }
-
-
-    fn report_done(&self) -> Result<()> {
-        let _: () = self.pool
-            .get()?
-            .hdel(&self.with_namespace(&self.with_server_id("workers")),
-                  &self.id)?;
-        Ok(())
+        self.work_state.lock().unwrap().insert(key.into(), to_string(&payload).unwrap());
     }
 
 
-    fn with_namespace(&self, snippet: &str) -> String {
-        if self.namespace == "" {
-            snippet.into()
-        } else {
-            self.namespace.clone() + ":" + snippet
-        }
+    fn report_done(&self, key: &str) {
+        self.work_state.lock().unwrap().remove(key);
     }
 
 
@@ -236,10 +858,6 @@ impl<'a> SidekiqWorker<'a> {
         self.server_id.clone() + ":" + snippet
     }
 
-
-    fn queue_name(&self, name: &str) -> String {
-        self.with_namespace(&("queue:".to_string() + name))
-    }
     // fn json_to_ruby_obj(v: &JValue) -> RObject {
     //     match v {x
     //         &JValue::Null => RNil::new().to_any_object(),
@@ -265,3 +883,136 @@ impl<'a> SidekiqWorker<'a> {
     //     }
     // }
 }
+
+// each middleware calls the rest of the chain through a closure on the stack, the last one
+// calls the handler
+fn call_middleware<'a, F>(chain: &mut [Box<dyn MiddleWare + 'a>],
+                          redis: RedisPool,
+                          job: &mut Job,
+                          mut job_handle: F)
+                          -> Result<JobSuccessType>
+    where F: FnMut(&Job) -> JobHandlerResult
+{
+    fn imp<'a, F: FnMut(&Job) -> JobHandlerResult>(job: &mut Job,
+                                                   redis: RedisPool,
+                                                   chain: &mut [Box<dyn MiddleWare + 'a>],
+                                                   job_handle: &mut F)
+                                                   -> Result<JobSuccessType> {
+        chain.split_first_mut()
+            .map(|(head, tail)| {
+                head.handle(job,
+                            redis,
+                            &mut |job, redis| imp(job, redis, tail, job_handle))
+            })
+            .unwrap_or_else(|| job_handle(job))
+    }
+
+    imp(job, redis, chain, &mut job_handle)
+}
+
+// each async middleware is given the rest of the chain in a box, the last one calls the
+// handler
+fn call_async_middleware(chain: Arc<Vec<Arc<dyn AsyncMiddleWare>>>,
+                         index: usize,
+                         job: Job,
+                         redis: AsyncRedis,
+                         handle: AsyncNextFunc)
+                         -> JobFuture {
+    let middleware = match chain.get(index) {
+        Some(middleware) => middleware.clone(),
+        None => return handle(job, redis),
+    };
+    middleware.handle(job,
+                      redis,
+                      Box::new(move |job, redis| {
+                          call_async_middleware(chain, index + 1, job, redis, handle)
+                      }))
+}
+
+// whether the job counts as processed in the stats
+fn processed(r: JobSuccessType) -> bool {
+    match r {
+        JobSuccessType::Ignore |
+        JobSuccessType::Reschedule(_) => false,
+        JobSuccessType::Success |
+        JobSuccessType::Returned(_) => true,
+    }
+}
+
+// the timing and outcome of a handler for the metrics and sinks, and its error for the error
+// handlers
+fn report_handled<'a>(metrics: &ExecutionTracker,
+                      sinks: &mut [Box<dyn MetricsSink + 'a>],
+                      error_handlers: &mut [Box<dyn ErrorHandler + 'a>],
+                      class: &str,
+                      job: &Job,
+                      elapsed: Duration,
+                      r: &JobHandlerResult) {
+    metrics.record(class, elapsed, r.is_ok());
+    let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+    let tags = [("class", class), ("queue", &*job.queue)];
+    for sink in sinks.iter_mut() {
+        sink.timing("jobs.duration", ms, &tags);
+        sink.increment(if r.is_ok() { "jobs.success" } else { "jobs.failure" }, &tags);
+    }
+    if let Err(ref e) = *r {
+        for handler in error_handlers.iter_mut() {
+            handler.handle(job, e);
+        }
+    }
+}
+
+// a panicking handler fails the job like an error would, so it goes through the retries
+fn handle_catching_panic<'a>(handler: &mut Box<dyn JobHandler + 'a>,
+                             job: &Job)
+                             -> JobHandlerResult {
+    catch_unwind(AssertUnwindSafe(|| handler.handle(job)))
+        .unwrap_or_else(|payload| Err(panicked(job, payload)))
+}
+
+fn panicked(job: &Job, payload: Box<dyn Any + Send>) -> Error {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown".to_string()
+    };
+    warn!("job '{}' of '{}' panicked at '{}'", job.jid, job.class, message);
+    ErrorKind::Panicked(message).into()
+}
+
+// run the handler on its own thread to give up on it after `timeout` seconds. the thread
+// can't be stopped and keeps running, its job is cancelled so a handler polling its
+// `CancellationToken` stops
+fn handle_with_timeout<'a>(worker_id: &str,
+                           cancellations: &Cancellations,
+                           handler: &mut Box<dyn JobHandler + 'a>,
+                           job: &Job,
+                           timeout: usize)
+                           -> JobHandlerResult {
+    let (tx, rx) = channel();
+    let mut handler = handler.cloned();
+    let cloned_job = job.clone();
+    let context = log_context();
+    thread::Builder::new()
+        .name("job".into())
+        .spawn(move || {
+            let _context = context.map(enter_context);
+            let _ = tx.send(handle_catching_panic(&mut handler, &cloned_job));
+        })
+        .map_err(|e| Error::from(format!("spawning job thread failed: '{}'", e)))?;
+    match rx.recv_timeout(Duration::from_secs(timeout as u64)) {
+        Ok(r) => r,
+        Err(RecvTimeoutError::Timeout) => {
+            error!("{}: job '{}' of '{}' is stuck for {} seconds, cancelling it",
+                   worker_id,
+                   job.jid,
+                   job.class,
+                   timeout);
+            cancellations.cancel(&job.jid);
+            Err(ErrorKind::Timeout(timeout).into())
+        }
+        Err(RecvTimeoutError::Disconnected) => Err("job thread exited without a result".into()),
+    }
+}